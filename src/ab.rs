@@ -0,0 +1,78 @@
+// Running two commands against the same files, to check that a rewrite behaves like the original (see the ab mode)
+use std::path::PathBuf;
+
+use crate::error::Result;
+use crate::runner::TestOutcome;
+use crate::suite::TestSuite;
+use crate::{compare, diff};
+
+/// Print where the outcomes of an old and a new command on the same files differ, then how their timings compare
+/// Outputs are compared as they would be saved (with the comparator, if any); returns true if any differ
+pub fn print(
+    suite: &TestSuite,
+    files: &[PathBuf],
+    old: &[TestOutcome],
+    new: &[TestOutcome],
+) -> Result<bool> {
+    let options = &suite.db.options;
+    let mut same = 0;
+    let mut different = 0;
+    let mut timings = Vec::new();
+
+    for ((file, old), new) in files.iter().zip(old).zip(new) {
+        let key = suite.key(file);
+
+        match (old, new) {
+            (
+                TestOutcome::Success(old_output, old_error, old_ms, ..),
+                TestOutcome::Success(new_output, new_error, new_ms, ..),
+            ) => {
+                timings.push((key.clone(), *old_ms, *new_ms));
+
+                let old_saved = options.saved(old_output, old_error);
+                let new_saved = options.saved(new_output, new_error);
+                if suite.matches(&old_saved, &new_saved)? {
+                    same += 1;
+                    continue;
+                }
+
+                println!("Different output: {}", key);
+                for line in diff::unified(&old_saved, &new_saved).lines() {
+                    println!("\t{}", line);
+                }
+            }
+            _ if old.name() == new.name() => {
+                same += 1;
+                continue;
+            }
+            _ => println!(
+                "Different status: {} ({} -> {})",
+                key,
+                old.name(),
+                new.name()
+            ),
+        }
+        different += 1;
+    }
+
+    if different > 0 {
+        println!();
+    }
+    println!(
+        "{} of {} files differ ({} the same)",
+        different,
+        files.len(),
+        same
+    );
+
+    println!();
+    compare::print_timing_changes(
+        "Timing changes (old -> new)",
+        timings
+            .iter()
+            .map(|(key, old_ms, new_ms)| (key.as_str(), *old_ms, *new_ms))
+            .collect(),
+    );
+
+    Ok(different > 0)
+}
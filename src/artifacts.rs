@@ -0,0 +1,133 @@
+// Full outputs saved for later inspection, one directory per run
+// Old runs are cleaned up when a new one starts, based on age and total size
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::error::{self, Error};
+
+const RUN_PREFIX: &str = "run-";
+
+/// The artifact directory for the current run
+pub struct Artifacts {
+    directory: PathBuf,
+}
+
+impl Artifacts {
+    /// Create a new run directory (named by start time) under the given root
+    pub fn start(root: &str) -> error::Result<Artifacts> {
+        let timestamp = humantime::format_rfc3339_seconds(SystemTime::now())
+            .to_string()
+            .replace(':', "-");
+
+        let directory = Path::new(root).join(format!("{}{}", RUN_PREFIX, timestamp));
+        std::fs::create_dir_all(&directory)
+            .map_err(|err| Error::io("create artifacts directory", directory.display(), err))?;
+
+        Ok(Artifacts { directory })
+    }
+
+    /// Save output for a file, returning where it was written
+    pub fn write(&self, file: &Path, output: &str) -> error::Result<PathBuf> {
+        let artifact = self.directory.join(format!("{}.out", file.display()));
+        std::fs::create_dir_all(artifact.parent().unwrap())
+            .and_then(|_| std::fs::write(&artifact, output))
+            .map_err(|err| Error::io("write artifact", artifact.display(), err))?;
+        Ok(artifact)
+    }
+}
+
+/// Remove run directories older than max_age, then the oldest runs until under max_size bytes
+pub fn cleanup(root: &str, max_age: Option<Duration>, max_size: Option<u64>) {
+    let Ok(entries) = std::fs::read_dir(root) else {
+        return;
+    };
+
+    // Only touch directories we created; names sort oldest first
+    let mut runs = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_dir())
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with(RUN_PREFIX))
+        .map(|entry| entry.path())
+        .collect::<Vec<_>>();
+    runs.sort();
+
+    if let Some(max_age) = max_age {
+        runs.retain(|run| {
+            let age = std::fs::metadata(run)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(|modified| modified.elapsed().ok())
+                .unwrap_or_default();
+
+            if age > max_age {
+                remove_run(run);
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    if let Some(max_size) = max_size {
+        let sizes = runs
+            .iter()
+            .map(|run| directory_size(run))
+            .collect::<Vec<_>>();
+        let mut total = sizes.iter().sum::<u64>();
+
+        for (run, size) in runs.iter().zip(sizes) {
+            if total <= max_size {
+                break;
+            }
+            remove_run(run);
+            total -= size;
+        }
+    }
+}
+
+fn remove_run(run: &Path) {
+    log::info!("Removing old artifacts: {}", run.display());
+    if let Err(err) = std::fs::remove_dir_all(run) {
+        log::warn!("Unable to remove {}: {}", run.display(), err);
+    }
+}
+
+fn directory_size(path: &Path) -> u64 {
+    let Ok(entries) = std::fs::read_dir(path) else {
+        return 0;
+    };
+
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| {
+            let path = entry.path();
+            if path.is_dir() {
+                directory_size(&path)
+            } else {
+                entry.metadata().map(|m| m.len()).unwrap_or(0)
+            }
+        })
+        .sum()
+}
+
+/// Parse a size like 500M or 2G (plain numbers are bytes)
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (number, multiplier) = match value.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&value[..i], 1 << 10),
+        Some((i, 'm' | 'M')) => (&value[..i], 1 << 20),
+        Some((i, 'g' | 'G')) => (&value[..i], 1 << 30),
+        _ => (value, 1),
+    };
+
+    number
+        .trim()
+        .parse::<u64>()
+        .map(|n| n * multiplier)
+        .map_err(|_| {
+            format!(
+                "invalid size: {} (expected a number with optional K, M, or G)",
+                value
+            )
+        })
+}
@@ -0,0 +1,115 @@
+// Coloring terminal output: statuses, and what changed in an output compared to the accepted one
+use std::io::IsTerminal;
+
+use crate::diff::{self, Line};
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum ColorMode {
+    /// Color if stdout is a terminal (and $NO_COLOR isn't set)
+    Auto,
+
+    /// Always color
+    Always,
+
+    /// Never color
+    Never,
+}
+
+impl std::fmt::Display for ColorMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ColorMode::Auto => write!(f, "auto"),
+            ColorMode::Always => write!(f, "always"),
+            ColorMode::Never => write!(f, "never"),
+        }
+    }
+}
+
+impl ColorMode {
+    /// If output should be colored
+    pub fn enabled(&self) -> bool {
+        match self {
+            ColorMode::Auto => {
+                std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none()
+            }
+            ColorMode::Always => true,
+            ColorMode::Never => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Color {
+    Red,
+    Green,
+    Yellow,
+
+    /// Bold and reversed green, for the changed part of a line
+    Highlight,
+}
+
+impl Color {
+    /// Wrap text in the ANSI escape codes for this color
+    pub fn paint(&self, text: &str) -> String {
+        let code = match self {
+            Color::Red => "31",
+            Color::Green => "32",
+            Color::Yellow => "33",
+            Color::Highlight => "1;7;32",
+        };
+        format!("\x1b[{}m{}\x1b[0m", code, text)
+    }
+}
+
+/// Color the lines of a new output that aren't in the old one
+/// Added lines are green; for lines that replaced an old line, only the part that changed is highlighted
+pub fn highlight_changes(old: &str, new: &str) -> String {
+    let mut lines = Vec::new();
+
+    // Lines removed since the last unchanged line, to pair with the lines that replaced them
+    let mut removed = std::collections::VecDeque::new();
+    for line in diff::lines(old, new) {
+        match line {
+            Line::Same(line) => {
+                removed.clear();
+                lines.push(line.to_string());
+            }
+            Line::Removed(line) => removed.push_back(line),
+            Line::Added(line) => match removed.pop_front() {
+                Some(old_line) => lines.push(highlight_line(old_line, line)),
+                None => lines.push(Color::Green.paint(line)),
+            },
+        }
+    }
+
+    let mut highlighted = lines.join("\n");
+    if new.ends_with('\n') {
+        highlighted.push('\n');
+    }
+    highlighted
+}
+
+// Highlight the part of a line between the prefix and suffix it shares with the line it replaced
+fn highlight_line(old: &str, new: &str) -> String {
+    let prefix = old
+        .chars()
+        .zip(new.chars())
+        .take_while(|(a, b)| a == b)
+        .map(|(c, _)| c.len_utf8())
+        .sum::<usize>();
+    let suffix = old[prefix..]
+        .chars()
+        .rev()
+        .zip(new[prefix..].chars().rev())
+        .take_while(|(a, b)| a == b)
+        .map(|(c, _)| c.len_utf8())
+        .sum::<usize>();
+
+    let end = new.len() - suffix;
+    format!(
+        "{}{}{}",
+        &new[..prefix],
+        Color::Highlight.paint(&new[prefix..end]),
+        &new[end..]
+    )
+}
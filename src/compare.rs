@@ -0,0 +1,175 @@
+// Comparing a db to the same db as it was at some git revision, to review what a change did to it
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use crate::error::{Error, Result};
+use crate::{db_dir, diff, git, snapshots, Db};
+
+// How many of the largest timing changes to list
+const TIMING_CHANGES: usize = 10;
+
+/// Load a db as it was at a git revision (like HEAD~1), along with its snapshots (or db dir) if it uses them
+pub fn load_revision(path: &str, revision: &str) -> Result<Db> {
+    let directory = match Path::new(path).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let Some(name) = Path::new(path).file_name() else {
+        return Err(Error::Config(format!("{} isn't a db file", path)));
+    };
+    let name = &*name.to_string_lossy();
+
+    let unreadable = |file: &str, err: String| {
+        Error::Other(format!("Unable to read {} at {}: {}", file, revision, err))
+    };
+    let bytes = git::show(directory, revision, name).map_err(|err| unreadable(path, err))?;
+    let mut db = Db::from_bytes(&bytes, &format!("{} at {}", path, revision))?;
+
+    if let Some(snapshot_dir) = db.options.snapshots.clone() {
+        let snapshot_dir = snapshot_dir.trim_end_matches('/');
+        let files = git::list(directory, revision, snapshot_dir).unwrap_or_else(|err| {
+            log::warn!("No snapshots for {} at {}: {}", path, revision, err);
+            Vec::new()
+        });
+
+        let files = files
+            .into_iter()
+            .map(|file| {
+                let snapshot = format!("{}/{}", snapshot_dir, file);
+                let output = git::show(directory, revision, &snapshot)
+                    .map_err(|err| unreadable(&format!("snapshot {}", snapshot), err))?;
+                Ok((file, String::from_utf8_lossy(&output).into_owned()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        db.results = snapshots::from_files(files);
+    }
+
+    if let Some(tree) = db.options.db_dir.clone() {
+        let tree = tree.trim_end_matches('/');
+        let files = git::list(directory, revision, tree).unwrap_or_else(|err| {
+            log::warn!("No db dir for {} at {}: {}", path, revision, err);
+            Vec::new()
+        });
+
+        let files = files
+            .into_iter()
+            .map(|file| {
+                let test = format!("{}/{}", tree, file);
+                let contents =
+                    git::show(directory, revision, &test).map_err(|err| unreadable(&test, err))?;
+                Ok((file, String::from_utf8_lossy(&contents).into_owned()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        db_dir::from_files(files, &mut db)?;
+    }
+
+    Ok(db)
+}
+
+/// Print the accepted outputs and timings that differ between an old and a new version of a db
+/// Returns true if any accepted outputs differ
+pub fn print(old: &Db, new: &Db) -> bool {
+    let files = old
+        .results
+        .keys()
+        .chain(new.results.keys())
+        .collect::<BTreeSet<_>>();
+
+    let mut differ = false;
+    for file in files {
+        match (old.results.get(file), new.results.get(file)) {
+            (None, Some(outputs)) => {
+                println!("Added: {} ({} accepted output(s))", file, outputs.len());
+            }
+            (Some(outputs), None) => {
+                println!("Removed: {} ({} accepted output(s))", file, outputs.len());
+            }
+            (Some(old_outputs), Some(new_outputs)) if old_outputs != new_outputs => {
+                let removed = old_outputs
+                    .iter()
+                    .filter(|output| !new_outputs.contains(output))
+                    .collect::<Vec<_>>();
+                let added = new_outputs
+                    .iter()
+                    .filter(|output| !old_outputs.contains(output))
+                    .collect::<Vec<_>>();
+
+                println!("Changed: {}", file);
+                if let ([removed], [added]) = (removed.as_slice(), added.as_slice()) {
+                    for line in diff::unified(removed, added).lines() {
+                        println!("\t{}", line);
+                    }
+                } else {
+                    println!(
+                        "\t{} output(s) no longer accepted, {} newly accepted",
+                        removed.len(),
+                        added.len()
+                    );
+                }
+            }
+            _ => continue,
+        }
+        differ = true;
+    }
+    if !differ {
+        println!("Accepted outputs are unchanged");
+    }
+
+    // Timing, for tests timed in both versions
+    let changes = new
+        .timing
+        .iter()
+        .filter_map(|(file, timing)| {
+            let before = old.timing.get(file)?.most_recent;
+            Some((file.as_str(), before, timing.most_recent))
+        })
+        .collect::<Vec<_>>();
+
+    println!();
+    print_timing_changes("Timing changes (most recent)", changes);
+    differ
+}
+
+/// Print the total and largest changes in timing (in ms) of tests, from before to after
+pub fn print_timing_changes(title: &str, changes: Vec<(&str, u128, u128)>) {
+    let mut changes = changes
+        .into_iter()
+        .filter(|(_, before, after)| before != after)
+        .collect::<Vec<_>>();
+    if changes.is_empty() {
+        println!("Timings are unchanged");
+        return;
+    }
+
+    let before = changes.iter().map(|(_, before, _)| before).sum::<u128>();
+    let after = changes.iter().map(|(_, _, after)| after).sum::<u128>();
+    println!(
+        "{}: {} test(s), {}ms -> {}ms ({:+.0}%)",
+        title,
+        changes.len(),
+        before,
+        after,
+        percent(before, after)
+    );
+
+    changes.sort_by(|(a_file, a_before, a_after), (b_file, b_before, b_after)| {
+        b_after
+            .abs_diff(*b_before)
+            .cmp(&a_after.abs_diff(*a_before))
+            .then_with(|| a_file.cmp(b_file))
+    });
+    for (file, before, after) in changes.iter().take(TIMING_CHANGES) {
+        println!(
+            "\t{:>8}ms -> {:>8}ms ({:+.0}%)  {}",
+            before,
+            after,
+            percent(*before, *after),
+            file
+        );
+    }
+}
+
+// The change from before to after, as a percentage of before
+fn percent(before: u128, after: u128) -> f64 {
+    (after as f64 - before as f64) / (before.max(1) as f64) * 100.0
+}
@@ -0,0 +1,67 @@
+// Transparent compression for db files
+// Reading detects the format from magic bytes, writing picks it from --compress or the file extension
+use std::io::{Read, Write};
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Compression {
+    /// Plain JSON
+    None,
+
+    /// gzip (.gz)
+    Gzip,
+
+    /// zstd (.zst)
+    Zstd,
+}
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+impl Compression {
+    /// Detect compression from the start of a file's contents
+    pub fn detect(bytes: &[u8]) -> Compression {
+        if bytes.starts_with(GZIP_MAGIC) {
+            Compression::Gzip
+        } else if bytes.starts_with(ZSTD_MAGIC) {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+
+    /// Guess compression from a file name, if it has a known extension
+    pub fn from_extension(path: &str) -> Option<Compression> {
+        if path.ends_with(".gz") {
+            Some(Compression::Gzip)
+        } else if path.ends_with(".zst") || path.ends_with(".zstd") {
+            Some(Compression::Zstd)
+        } else {
+            None
+        }
+    }
+
+    pub fn decompress(self, bytes: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(bytes.to_vec()),
+            Compression::Gzip => {
+                let mut output = Vec::new();
+                flate2::read::GzDecoder::new(bytes).read_to_end(&mut output)?;
+                Ok(output)
+            }
+            Compression::Zstd => zstd::decode_all(bytes),
+        }
+    }
+
+    pub fn compress(self, bytes: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(bytes.to_vec()),
+            Compression::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(bytes)?;
+                encoder.finish()
+            }
+            Compression::Zstd => zstd::encode_all(bytes, 0),
+        }
+    }
+}
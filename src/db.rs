@@ -0,0 +1,651 @@
+// The database of accepted outputs (and everything else remembered between runs) for a suite
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::compression::Compression;
+use crate::db_dir;
+use crate::error::{Error, Result};
+use crate::format::Format;
+use crate::hash;
+use crate::migrate;
+use crate::options::{Metadata, Options};
+use crate::snapshots;
+
+/// The outcome of the most recent run of a test, as saved in the db
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Success,
+    Failure,
+    Timeout,
+    Skipped,
+
+    /// A success with output other than the outputs accepted before
+    New,
+
+    /// Couldn't be run at all (see TestOutcome::Error)
+    Error,
+}
+
+impl std::fmt::Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Status::Success => write!(f, "success"),
+            Status::Failure => write!(f, "failure"),
+            Status::Timeout => write!(f, "timeout"),
+            Status::Skipped => write!(f, "skipped"),
+            Status::New => write!(f, "new"),
+            Status::Error => write!(f, "error"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TimingData {
+    pub fastest: u128,
+    pub most_recent: u128,
+}
+
+/// A single run of a test, as kept in its history
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    /// When the run finished, in seconds since the unix epoch
+    pub at: u64,
+
+    pub status: Status,
+
+    /// How long it took, for successes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub elapsed_ms: Option<u128>,
+}
+
+/// Resources used by a test's command (and anything it waited for)
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Usage {
+    /// Peak resident memory in KiB
+    pub max_rss_kb: u64,
+
+    /// CPU time in user and kernel mode in ms
+    pub user_ms: u64,
+    pub system_ms: u64,
+
+    /// Page faults that had to read from disk
+    pub major_faults: u64,
+}
+
+/// A run that saved the db, so when (and where) its baseline was last refreshed can be answered
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RunRecord {
+    /// When the run finished, in seconds since the epoch
+    pub at: u64,
+
+    /// Where it ran: the hostname, OS and architecture (like linux-x86_64), and version of testit
+    pub host: String,
+    pub os: String,
+    pub version: String,
+
+    pub duration_ms: u128,
+
+    /// How many tests had each outcome
+    pub successes: usize,
+    pub new_successes: usize,
+    pub failures: usize,
+    pub timeouts: usize,
+    pub skipped: usize,
+
+    /// Tests that couldn't be run at all
+    #[serde(default)]
+    pub errors: usize,
+}
+
+impl RunRecord {
+    /// A run finishing now on this machine after some time, with no counts yet
+    pub fn now(duration_ms: u128) -> RunRecord {
+        RunRecord {
+            at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            host: hostname(),
+            os: format!("{}-{}", std::env::consts::OS, std::env::consts::ARCH),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            duration_ms,
+            successes: 0,
+            new_successes: 0,
+            failures: 0,
+            timeouts: 0,
+            skipped: 0,
+            errors: 0,
+        }
+    }
+}
+
+/// Changes to a tree of files stored along with a db (see --snapshots and --db-dir), made together with saving the db
+#[derive(Debug, Default)]
+pub struct TreeChanges {
+    /// Files to write, with their new contents; unchanged files are left out, so their modification times stay the same
+    pub writes: Vec<(PathBuf, String)>,
+
+    /// Files that are no longer needed
+    pub removals: Vec<PathBuf>,
+}
+
+impl TreeChanges {
+    // Write each changed file next to where it goes, so only renaming it into place is left to do
+    fn stage(&self) -> Result<()> {
+        for (path, contents) in self.writes.iter() {
+            let staged = staged_path(path);
+            std::fs::create_dir_all(path.parent().unwrap_or(Path::new("")))
+                .and_then(|_| std::fs::write(&staged, contents))
+                .map_err(|err| Error::io("write", staged.display(), err))?;
+        }
+        Ok(())
+    }
+
+    // Remove the staged files of a save that didn't go through
+    fn discard(&self) {
+        for (path, _) in self.writes.iter() {
+            let _ = std::fs::remove_file(staged_path(path));
+        }
+    }
+
+    // Rename each staged file into place, then remove the files that are no longer needed
+    fn commit(&self) -> Result<()> {
+        for (path, _) in self.writes.iter() {
+            std::fs::rename(staged_path(path), path)
+                .map_err(|err| Error::io("write", path.display(), err))?;
+        }
+        for path in self.removals.iter() {
+            log::info!("Removing {}", path.display());
+            let _ = std::fs::remove_file(path);
+        }
+        Ok(())
+    }
+}
+
+// Where a file in a tree is written before it's renamed into place (see TreeChanges)
+fn staged_path(path: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.tmp", path.display()))
+}
+
+// How many runs to keep in a db's runs
+const RUNS_LENGTH: usize = 50;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Db {
+    /// The schema version this db was written with (see migrate)
+    #[serde(default)]
+    pub version: u32,
+
+    pub results: BTreeMap<String, Vec<String>>,
+
+    pub metadata: Metadata,
+
+    pub options: Options,
+
+    /// Named sets of options that can be used for a run instead (see --profile)
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, Options>,
+
+    #[serde(default)]
+    pub timing: BTreeMap<String, TimingData>,
+
+    /// The most recent runs of each test, oldest first (see --history-length)
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub history: BTreeMap<String, Vec<HistoryEntry>>,
+
+    /// Resource usage of each test as of its most recent success
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub usage: BTreeMap<String, Usage>,
+
+    /// Hash of each input file as of its most recent success
+    #[serde(default)]
+    pub hashes: BTreeMap<String, String>,
+
+    /// Hashes of the dependencies of each input file as of its most recent success
+    #[serde(default)]
+    pub dependency_hashes: BTreeMap<String, BTreeMap<String, String>>,
+
+    /// The status of each test from the most recent run
+    #[serde(default)]
+    pub status: BTreeMap<String, Status>,
+
+    /// The most recent successful output of each test, so it can be blessed later
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub latest: BTreeMap<String, String>,
+
+    /// The output of the version command (see --version-command) when the baseline was recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_version: Option<String>,
+
+    /// The most recent runs that saved this db, oldest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub runs: Vec<RunRecord>,
+
+    /// Other db files (relative to this one) to run along with this one
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+}
+
+impl Db {
+    /// Create an empty db at the current version
+    pub fn new(metadata: Metadata, options: Options) -> Db {
+        Db {
+            version: migrate::VERSION,
+            results: BTreeMap::new(),
+            metadata,
+            options,
+            profiles: BTreeMap::new(),
+            timing: BTreeMap::new(),
+            history: BTreeMap::new(),
+            usage: BTreeMap::new(),
+            hashes: BTreeMap::new(),
+            dependency_hashes: BTreeMap::new(),
+            status: BTreeMap::new(),
+            latest: BTreeMap::new(),
+            tool_version: None,
+            runs: Vec::new(),
+            include: Vec::new(),
+        }
+    }
+
+    /// Read a db file, along with its snapshots or db dir (if any)
+    pub fn read(path: &str) -> Result<Db> {
+        Db::read_hashed(path).map(|(db, _)| db)
+    }
+
+    /// Read a db file like read, along with the hash of the file's contents as they were parsed
+    pub fn read_hashed(path: &str) -> Result<(Db, String)> {
+        if !Path::new(path).exists() {
+            return Err(Error::Db {
+                path: path.to_string(),
+                message: "does not exist".to_string(),
+            });
+        }
+
+        let bytes =
+            std::fs::read(path).map_err(|err| Error::io("read database file", path, err))?;
+        let mut db = Db::from_bytes(&bytes, path)?;
+        if let Some(snapshots) = &db.options.snapshots {
+            db.results = snapshots::load(&snapshots::directory(path, snapshots))?;
+        }
+        if let Some(directory) = db.options.db_dir.clone() {
+            db_dir::load(&db_dir::directory(path, &directory), &mut db)?;
+        }
+        Ok((db, hash::hash_bytes(&bytes)))
+    }
+
+    /// Read a db from the (possibly compressed) contents of a db file, without loading any snapshots (or db dir)
+    /// The name is only used for errors
+    pub fn from_bytes(bytes: &[u8], name: &str) -> Result<Db> {
+        Db::parse(bytes).map_err(|message| Error::Db {
+            path: name.to_string(),
+            message,
+        })
+    }
+
+    /// The same, but returning what's wrong with the contents if they aren't a db we can read
+    pub fn parse(bytes: &[u8]) -> std::result::Result<Db, String> {
+        let bytes = Compression::detect(bytes)
+            .decompress(bytes)
+            .map_err(|err| format!("could not be decompressed: {}", err))?;
+        let mut value = Format::detect(&bytes)
+            .parse(&bytes)
+            .map_err(|err| format!("could not be parsed: {}", err))?;
+        if !value.is_object() {
+            return Err("is not an object".to_string());
+        }
+
+        // Older dbs are upgraded in memory (and saved as the new version), newer ones we can't trust
+        if migrate::version(&value) > migrate::VERSION {
+            return Err(format!(
+                "is version {}, but this version of testit only supports up to {}",
+                migrate::version(&value),
+                migrate::VERSION
+            ));
+        }
+        migrate::migrate(&mut value);
+
+        // Outputs may have been saved as arrays of lines (see --results-as-lines)
+        for_each_output(&mut value, join_lines);
+
+        serde_json::from_value(value)
+            .map_err(|err| format!("does not match the db schema: {}", err))
+    }
+
+    /// Save a db file, compressing it and in the format requested
+    /// Otherwise compression and format are based on the extension, then whatever the file already used (or plain JSON)
+    /// With snapshots, accepted outputs are written to the snapshot directory instead
+    /// With a db dir, everything about each test is written to its own file there instead
+    /// Changed files in either tree are written before the db is replaced, and renamed into place right after it
+    /// The previous version (if any) is kept as `<db>.bak`
+    pub fn save(
+        &self,
+        path: &str,
+        compression: Option<Compression>,
+        format: Option<Format>,
+    ) -> Result<()> {
+        let existing = std::fs::read(path).ok();
+        let compression = compression
+            .or_else(|| Compression::from_extension(path))
+            .or_else(|| existing.as_deref().map(Compression::detect))
+            .unwrap_or(Compression::None);
+        let format = format
+            .or_else(|| Format::from_extension(path))
+            .or_else(|| {
+                let existing = existing.as_deref()?;
+                let bytes = Compression::detect(existing).decompress(existing).ok()?;
+                Some(Format::detect(&bytes))
+            })
+            .unwrap_or(Format::Json);
+
+        // Going through a JSON value sorts every key, so the same db is always saved the same way
+        let mut value = serde_json::to_value(self).unwrap();
+
+        // With snapshots (or a db dir), results (and the rest) are written as files and left out of the db
+        let tree = match (&self.options.snapshots, &self.options.db_dir) {
+            (Some(snapshots), _) => {
+                value["results"] = serde_json::json!({});
+                Some(snapshots::changes(
+                    &snapshots::directory(path, snapshots),
+                    &self.results,
+                )?)
+            }
+            (None, Some(directory)) => {
+                let object = value.as_object_mut().unwrap();
+                for section in db_dir::SECTIONS {
+                    object.remove(section);
+                }
+                object.insert("results".to_string(), serde_json::json!({}));
+                Some(db_dir::changes(&db_dir::directory(path, directory), self)?)
+            }
+            (None, None) => None,
+        };
+        if self.options.results_as_lines == Some(true) {
+            for_each_output(&mut value, split_lines);
+        }
+        let bytes = format.serialize(&value);
+        let bytes = compression
+            .compress(&bytes)
+            .map_err(|err| Error::io("compress", path, err))?;
+
+        // Write the db and any changed files in its tree next to where they go, and only rename them into place once
+        // all of them were written, so a failed or interrupted save can't leave a truncated db (or one that doesn't
+        // match its tree)
+        let temporary = format!("{}.tmp", path);
+        let staged = File::create(&temporary)
+            .and_then(|mut file| {
+                file.write_all(&bytes)?;
+                file.sync_all()
+            })
+            .map_err(|err| Error::io("write to db file", &temporary, err))
+            .and_then(|_| tree.as_ref().map_or(Ok(()), TreeChanges::stage))
+            .and_then(|_| {
+                if !Path::new(path).exists() {
+                    return Ok(());
+                }
+                let backup = format!("{}.bak", path);
+                std::fs::copy(path, &backup)
+                    .map(|_| ())
+                    .map_err(|err| Error::io("back up db file to", backup, err))
+            })
+            .and_then(|_| {
+                std::fs::rename(&temporary, path)
+                    .map_err(|err| Error::io("write to db file", path, err))
+            });
+        if let Err(err) = staged {
+            if let Some(tree) = &tree {
+                tree.discard();
+            }
+            let _ = std::fs::remove_file(&temporary);
+            return Err(err);
+        }
+
+        match tree {
+            Some(tree) => tree.commit(),
+            None => Ok(()),
+        }
+    }
+
+    /// Replace the accepted outputs for a test with its most recent output
+    /// Returns false (changing nothing) if the test hasn't had a successful run yet
+    pub fn bless(&mut self, file: &str) -> bool {
+        match self.latest.get(file) {
+            Some(latest) => {
+                self.results.insert(file.to_string(), vec![latest.clone()]);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Check if another copy of this db accepts the same outputs with the same metadata and options
+    /// Timing, hashes, statuses, and latest outputs change every run, so they aren't compared
+    pub fn same_expectations(&self, other: &Db) -> bool {
+        self.results == other.results
+            && self.metadata == other.metadata
+            && self.options == other.options
+            && self.include == other.include
+    }
+
+    /// Merge results from another copy of this db into this one
+    /// Accepted outputs are combined, everything else from this copy takes precedence
+    pub fn merge(&mut self, other: Db) {
+        for (file, outputs) in other.results {
+            let ours = self.results.entry(file).or_default();
+            for output in outputs {
+                if !ours.contains(&output) {
+                    ours.push(output);
+                }
+            }
+        }
+
+        for (file, timing) in other.timing {
+            self.timing.entry(file).or_insert(timing);
+        }
+        for (file, history) in other.history {
+            self.history.entry(file).or_insert(history);
+        }
+        for (file, usage) in other.usage {
+            self.usage.entry(file).or_insert(usage);
+        }
+        for (file, hash) in other.hashes {
+            self.hashes.entry(file).or_insert(hash);
+        }
+        for (file, hashes) in other.dependency_hashes {
+            self.dependency_hashes.entry(file).or_insert(hashes);
+        }
+        for (file, status) in other.status {
+            self.status.entry(file).or_insert(status);
+        }
+        for (file, latest) in other.latest {
+            self.latest.entry(file).or_insert(latest);
+        }
+        for run in other.runs {
+            self.add_run(run);
+        }
+    }
+
+    /// Remember a run (unless it's already here), keeping only the most recent ones
+    pub fn add_run(&mut self, run: RunRecord) {
+        if self.runs.contains(&run) {
+            return;
+        }
+        self.runs.push(run);
+        self.runs.sort_by_key(|run| run.at);
+        if self.runs.len() > RUNS_LENGTH {
+            self.runs.drain(..self.runs.len() - RUNS_LENGTH);
+        }
+    }
+
+    /// Merge the results of a shard (see --shard), a copy of base after running some of its tests, into this db
+    /// Anything the shard changed from base replaces what's here, so merging each shard into base combines them
+    pub fn merge_shard(&mut self, base: &Db, shard: Db) {
+        fn changed<V: PartialEq>(
+            ours: &mut BTreeMap<String, V>,
+            base: &BTreeMap<String, V>,
+            theirs: BTreeMap<String, V>,
+        ) {
+            for (file, value) in theirs {
+                if base.get(&file) != Some(&value) {
+                    ours.insert(file, value);
+                }
+            }
+        }
+
+        changed(&mut self.results, &base.results, shard.results);
+        changed(&mut self.timing, &base.timing, shard.timing);
+        changed(&mut self.history, &base.history, shard.history);
+        changed(&mut self.usage, &base.usage, shard.usage);
+        changed(&mut self.hashes, &base.hashes, shard.hashes);
+        changed(
+            &mut self.dependency_hashes,
+            &base.dependency_hashes,
+            shard.dependency_hashes,
+        );
+        changed(&mut self.status, &base.status, shard.status);
+        changed(&mut self.latest, &base.latest, shard.latest);
+        for run in shard.runs {
+            if !base.runs.contains(&run) {
+                self.add_run(run);
+            }
+        }
+    }
+}
+
+/// Find all of the db files to run for a db (or a glob pattern of dbs), following includes
+/// A file with only includes (no metadata) groups other suites without running anything itself
+pub fn suite_paths(pattern: &str) -> Result<Vec<String>> {
+    fn visit(path: &str, seen: &mut Vec<String>, paths: &mut Vec<String>) -> Result<()> {
+        let canonical = std::fs::canonicalize(path)
+            .map(|p| p.display().to_string())
+            .unwrap_or_else(|_| path.to_string());
+        if seen.contains(&canonical) {
+            log::warn!("Skipping {}, already included", path);
+            return Ok(());
+        }
+        seen.push(canonical);
+
+        let invalid = |message: String| Error::Db {
+            path: path.to_string(),
+            message,
+        };
+        if !Path::new(path).exists() {
+            return Err(invalid("does not exist".to_string()));
+        }
+
+        let bytes =
+            std::fs::read(path).map_err(|err| Error::io("read database file", path, err))?;
+        let bytes = Compression::detect(&bytes)
+            .decompress(&bytes)
+            .map_err(|err| invalid(format!("could not be decompressed: {}", err)))?;
+        let value = Format::detect(&bytes)
+            .parse(&bytes)
+            .map_err(|err| invalid(format!("could not be parsed: {}", err)))?;
+        if !value.is_object() {
+            return Err(invalid("is not an object".to_string()));
+        }
+
+        if value.get("metadata").is_some() || value.get("%metadata%").is_some() {
+            paths.push(path.to_string());
+        }
+
+        let directory = Path::new(path).parent().unwrap_or(Path::new(""));
+        for include in value
+            .get("include")
+            .and_then(|include| include.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|include| include.as_str())
+        {
+            visit(&directory.join(include).to_string_lossy(), seen, paths)?;
+        }
+        Ok(())
+    }
+
+    // Only treat the path as a pattern if it isn't an existing file
+    let roots = if Path::new(pattern).exists() {
+        vec![pattern.to_string()]
+    } else {
+        glob::glob(pattern)
+            .map(|paths| {
+                paths
+                    .filter_map(|path| path.ok())
+                    .map(|path| path.display().to_string())
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+
+    if roots.is_empty() {
+        return Err(Error::Db {
+            path: pattern.to_string(),
+            message: "does not exist".to_string(),
+        });
+    }
+
+    let mut seen = Vec::new();
+    let mut paths = Vec::new();
+    for root in roots {
+        visit(&root, &mut seen, &mut paths)?;
+    }
+    Ok(paths)
+}
+
+// Apply a function to each output in a db (as a JSON value): every accepted output and each latest output
+fn for_each_output(value: &mut serde_json::Value, f: fn(&mut serde_json::Value)) {
+    if let Some(results) = value
+        .get_mut("results")
+        .and_then(|results| results.as_object_mut())
+    {
+        results
+            .values_mut()
+            .filter_map(|outputs| outputs.as_array_mut())
+            .flatten()
+            .for_each(f);
+    }
+    if let Some(latest) = value
+        .get_mut("latest")
+        .and_then(|latest| latest.as_object_mut())
+    {
+        latest.values_mut().for_each(f);
+    }
+}
+
+/// Replace an output (a JSON string) with an array of its lines, each keeping its newline, so it diffs line by line
+pub fn split_lines(output: &mut serde_json::Value) {
+    if let Some(text) = output.as_str() {
+        *output = text.split_inclusive('\n').collect();
+    }
+}
+
+/// Replace an output saved as an array of lines with the string they make up; other outputs are left alone
+pub fn join_lines(output: &mut serde_json::Value) {
+    if let Some(lines) = output.as_array() {
+        *output = lines
+            .iter()
+            .filter_map(|line| line.as_str())
+            .collect::<String>()
+            .into();
+    }
+}
+
+// The name of this machine, or "unknown" if it can't be found
+fn hostname() -> String {
+    let mut buffer = [0u8; 256];
+    // SAFETY: gethostname writes at most the given length into our own buffer
+    // The last byte is held back (and stays zero), since a truncated name may not be NUL terminated
+    let result = unsafe { libc::gethostname(buffer.as_mut_ptr().cast(), buffer.len() - 1) };
+    if result != 0 {
+        return "unknown".to_string();
+    }
+    match std::ffi::CStr::from_bytes_until_nul(&buffer) {
+        Ok(name) => name.to_string_lossy().into_owned(),
+        Err(_) => "unknown".to_string(),
+    }
+}
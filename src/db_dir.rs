@@ -0,0 +1,201 @@
+// Everything a db remembers about each test stored as one small file per test in a tree next to the db (see --db-dir)
+// Concurrent baseline updates then only touch the files of the tests they changed, so git can merge them
+// The file for an input (or matrix variant) is `<db_dir>/<key>.json`
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::db::{self, Db, HistoryEntry, Status, TimingData, TreeChanges, Usage};
+use crate::error::{Error, Result};
+use crate::fixtures;
+
+/// The sections of a db that are stored in the tree instead of the db file when using a db dir
+pub const SECTIONS: [&str; 8] = [
+    "results",
+    "timing",
+    "history",
+    "usage",
+    "hashes",
+    "dependency_hashes",
+    "status",
+    "latest",
+];
+
+// Everything remembered about a single test, each field being its entry in the db section of the same name
+#[derive(Debug, Serialize, Deserialize)]
+struct Record {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    results: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timing: Option<TimingData>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    history: Vec<HistoryEntry>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    usage: Option<Usage>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hash: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dependency_hashes: Option<BTreeMap<String, String>>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    status: Option<Status>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    latest: Option<String>,
+}
+
+/// The db dir for a db, relative to the db file
+pub fn directory(db_path: &str, db_dir: &str) -> PathBuf {
+    Path::new(db_path)
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join(db_dir)
+}
+
+/// Read every test's file under a directory into a db
+pub fn load(directory: &Path, db: &mut Db) -> Result<()> {
+    let mut files = Vec::new();
+    for (path, relative) in list(directory)? {
+        let contents =
+            std::fs::read_to_string(&path).map_err(|err| Error::io("read", path.display(), err))?;
+        files.push((relative, contents));
+    }
+    from_files(files, db)
+}
+
+/// Read the contents of test files (by their paths relative to the db dir) into a db
+pub fn from_files(files: impl IntoIterator<Item = (String, String)>, db: &mut Db) -> Result<()> {
+    for (relative, contents) in files {
+        let Some(key) = relative.strip_suffix(".json") else {
+            log::warn!("Ignoring unexpected file in db dir {}", relative);
+            continue;
+        };
+        let invalid = |message: String| Error::Db {
+            path: format!("{} (in the db dir)", relative),
+            message,
+        };
+        let mut value: serde_json::Value = serde_json::from_str(&contents)
+            .map_err(|err| invalid(format!("could not be parsed: {}", err)))?;
+        for_each_output(&mut value, db::join_lines);
+        let record: Record = serde_json::from_value(value)
+            .map_err(|err| invalid(format!("does not match the db schema: {}", err)))?;
+        let key = key.to_string();
+
+        if !record.results.is_empty() {
+            db.results.insert(key.clone(), record.results);
+        }
+        if let Some(timing) = record.timing {
+            db.timing.insert(key.clone(), timing);
+        }
+        if !record.history.is_empty() {
+            db.history.insert(key.clone(), record.history);
+        }
+        if let Some(usage) = record.usage {
+            db.usage.insert(key.clone(), usage);
+        }
+        if let Some(hash) = record.hash {
+            db.hashes.insert(key.clone(), hash);
+        }
+        if let Some(dependency_hashes) = record.dependency_hashes {
+            db.dependency_hashes.insert(key.clone(), dependency_hashes);
+        }
+        if let Some(status) = record.status {
+            db.status.insert(key.clone(), status);
+        }
+        if let Some(latest) = record.latest {
+            db.latest.insert(key, latest);
+        }
+    }
+    Ok(())
+}
+
+/// The changes that write a file for each test in a db, removing those for tests it no longer has (see Db::save)
+pub fn changes(directory: &Path, db: &Db) -> Result<TreeChanges> {
+    let mut changes = TreeChanges::default();
+    let keys = db
+        .results
+        .keys()
+        .chain(db.timing.keys())
+        .chain(db.history.keys())
+        .chain(db.usage.keys())
+        .chain(db.hashes.keys())
+        .chain(db.dependency_hashes.keys())
+        .chain(db.status.keys())
+        .chain(db.latest.keys())
+        .collect::<BTreeSet<_>>();
+
+    let mut stale = list(directory)?
+        .into_iter()
+        .map(|(path, _)| path)
+        .collect::<Vec<_>>();
+    for key in keys {
+        let record = Record {
+            results: db.results.get(key).cloned().unwrap_or_default(),
+            timing: db.timing.get(key).cloned(),
+            history: db.history.get(key).cloned().unwrap_or_default(),
+            usage: db.usage.get(key).cloned(),
+            hash: db.hashes.get(key).cloned(),
+            dependency_hashes: db.dependency_hashes.get(key).cloned(),
+            status: db.status.get(key).copied(),
+            latest: db.latest.get(key).cloned(),
+        };
+        let mut value = serde_json::to_value(&record).unwrap();
+        if db.options.results_as_lines == Some(true) {
+            for_each_output(&mut value, db::split_lines);
+        }
+        let contents = serde_json::to_string_pretty(&value).unwrap() + "\n";
+
+        let path = directory.join(format!("{}.json", key));
+        stale.retain(|existing| *existing != path);
+
+        // Skip unchanged files, so only the tests that changed show up in a diff
+        if std::fs::read_to_string(&path).is_ok_and(|existing| existing == contents) {
+            continue;
+        }
+        changes.writes.push((path, contents));
+    }
+
+    changes.removals = stale;
+    Ok(changes)
+}
+
+// Apply a function to each output in a record (as a JSON value): every accepted output and the latest output
+fn for_each_output(value: &mut serde_json::Value, f: fn(&mut serde_json::Value)) {
+    if let Some(results) = value
+        .get_mut("results")
+        .and_then(|results| results.as_array_mut())
+    {
+        results.iter_mut().for_each(f);
+    }
+    if let Some(latest) = value.get_mut("latest") {
+        f(latest);
+    }
+}
+
+// All test files under a directory (which may not exist yet), with their paths relative to it
+fn list(directory: &Path) -> Result<Vec<(PathBuf, String)>> {
+    fixtures::list_files(directory)
+        .into_iter()
+        .filter(|relative| {
+            relative
+                .extension()
+                .is_some_and(|extension| extension == "json")
+        })
+        .map(|relative| {
+            let path = directory.join(&relative);
+            match relative.to_str() {
+                Some(relative) => Ok((path.clone(), relative.to_string())),
+                None => Err(Error::Config(format!(
+                    "Test file names must be valid UTF-8: {}",
+                    path.display()
+                ))),
+            }
+        })
+        .collect()
+}
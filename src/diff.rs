@@ -0,0 +1,59 @@
+// Line diffs between two versions of an output
+use std::fmt::Write;
+
+/// A line in a diff
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Line<'a> {
+    Same(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+/// A minimal line diff (based on the longest common subsequence), with removals before additions
+pub fn lines<'a>(old: &'a str, new: &'a str) -> Vec<Line<'a>> {
+    let old = old.lines().collect::<Vec<_>>();
+    let new = new.lines().collect::<Vec<_>>();
+
+    // lengths[i][j] is the length of the LCS of old[i..] and new[j..]
+    let mut lengths = vec![vec![0; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lengths[i][j] = if old[i] == new[j] {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+
+    let mut diff = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            diff.push(Line::Same(old[i]));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lengths[i + 1][j] >= lengths[i][j + 1]) {
+            diff.push(Line::Removed(old[i]));
+            i += 1;
+        } else {
+            diff.push(Line::Added(new[j]));
+            j += 1;
+        }
+    }
+
+    diff
+}
+
+/// The changed lines between two strings, with - for removed and + for added lines
+pub fn unified(old: &str, new: &str) -> String {
+    let mut diff = String::new();
+    for line in lines(old, new) {
+        match line {
+            Line::Same(_) => {}
+            Line::Removed(line) => writeln!(diff, "-{}", line).unwrap(),
+            Line::Added(line) => writeln!(diff, "+{}", line).unwrap(),
+        }
+    }
+    diff
+}
@@ -0,0 +1,157 @@
+// Checking a db for problems that would otherwise only show up later as confusing results (see the doctor mode)
+// Only repairs that drop redundant data (or upgrade the schema) are made by --fix, anything else is left to fix by hand
+use std::collections::HashSet;
+use std::path::Path;
+use std::process::Command;
+
+use crate::compression::Compression;
+use crate::format::Format;
+use crate::options::TestCommand;
+use crate::{migrate, Db, TestSuite};
+
+/// Something wrong with a db, and if --fix can repair it (see repair)
+#[derive(Debug)]
+pub struct Problem {
+    pub description: String,
+    pub fixable: bool,
+}
+
+/// Check a db file, returning its problems and the suite (unless the db couldn't be read at all)
+pub fn check(path: &str) -> (Vec<Problem>, Option<TestSuite>) {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(err) => return (vec![by_hand(format!("Unable to read: {}", err))], None),
+    };
+    if let Err(err) = Db::parse(&bytes) {
+        return (vec![by_hand(format!("Database file {}", err))], None);
+    }
+
+    let suite = match TestSuite::load(path) {
+        Ok(suite) => suite,
+        Err(err) => return (vec![by_hand(err.to_string())], None),
+    };
+    let mut problems = Vec::new();
+
+    // Parsing succeeded, so neither of these can fail
+    let decompressed = Compression::detect(&bytes).decompress(&bytes).unwrap();
+    let version = migrate::version(&Format::detect(&decompressed).parse(&decompressed).unwrap());
+    if version < migrate::VERSION {
+        problems.push(fixable(format!(
+            "Schema version {} is older than the current version {}",
+            version,
+            migrate::VERSION
+        )));
+    }
+
+    let db = &suite.db;
+    for (key, outputs) in db.results.iter() {
+        if outputs.is_empty() {
+            problems.push(fixable(format!("{}: no accepted outputs", key)));
+        }
+
+        let mut seen = HashSet::new();
+        let duplicates = outputs
+            .iter()
+            .filter(|output| !seen.insert(output.as_str()))
+            .count();
+        if duplicates > 0 {
+            problems.push(fixable(format!(
+                "{}: {} duplicate accepted output(s)",
+                key, duplicates
+            )));
+        }
+    }
+    for key in db.timing.keys() {
+        if !db.results.contains_key(key) {
+            problems.push(fixable(format!("{}: timing without accepted outputs", key)));
+        }
+    }
+
+    match &db.metadata.directory {
+        Some(directory) if !Path::new(directory).is_dir() => {
+            problems.push(by_hand(format!("Directory {} does not exist", directory)));
+        }
+        _ => {
+            match suite.files() {
+                Ok(files) => {
+                    for file in files {
+                        if let Err(err) = std::fs::File::open(&file) {
+                            problems.push(by_hand(format!(
+                                "{}: unable to read input: {}",
+                                suite.relative_path(&file).display(),
+                                err
+                            )));
+                        }
+                    }
+                }
+                Err(err) => problems.push(by_hand(err.to_string())),
+            }
+            problems.extend(check_command(&suite));
+        }
+    }
+
+    (problems, Some(suite))
+}
+
+/// Make the safe repairs: remove duplicate accepted outputs, then tests without any, then timing for tests without any
+/// Saving the db afterwards also upgrades its schema
+pub fn repair(db: &mut Db) {
+    for outputs in db.results.values_mut() {
+        let mut seen = HashSet::new();
+        outputs.retain(|output| seen.insert(output.clone()));
+    }
+    db.results.retain(|_, outputs| !outputs.is_empty());
+    db.timing.retain(|key, _| db.results.contains_key(key));
+}
+
+// Check that the program the command starts with can be found (from the directory, as bash would find it)
+// Commands that start with anything bash would expand first (quotes, variables, placeholders) aren't checked
+fn check_command(suite: &TestSuite) -> Option<Problem> {
+    let command = &suite.db.metadata.command;
+    let program = match command {
+        TestCommand::Shell(command) => command
+            .split_whitespace()
+            .find(|word| !is_assignment(word))?,
+        TestCommand::Argv(argv) => argv.first()?,
+    };
+    if program.contains(['\'', '"', '$', '`', '{', '(', '\\']) {
+        return None;
+    }
+
+    let found = Command::new("bash")
+        .args(["-c", "command -v -- \"$1\"", "testit", program])
+        .current_dir(suite.db.metadata.directory.as_deref().unwrap_or("."))
+        .output()
+        .is_ok_and(|output| output.status.success());
+    if found {
+        None
+    } else {
+        Some(by_hand(format!(
+            "Command not found: {} (running {})",
+            program, command
+        )))
+    }
+}
+
+// If a word in a command sets an environment variable for it (like `LANG=C`)
+fn is_assignment(word: &str) -> bool {
+    word.split_once('=').is_some_and(|(name, _)| {
+        !name.is_empty()
+            && !name.starts_with(|c: char| c.is_ascii_digit())
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    })
+}
+
+fn fixable(description: String) -> Problem {
+    Problem {
+        description,
+        fixable: true,
+    }
+}
+
+fn by_hand(description: String) -> Problem {
+    Problem {
+        description,
+        fixable: false,
+    }
+}
@@ -0,0 +1,68 @@
+// Errors that stop testit before it can finish (as opposed to tests that fail), each naming the file or option at fault
+// They're printed as a single message, then testit exits with EXIT_ERROR so scripts can tell them apart from failures
+use std::fmt;
+
+/// The exit status for an error, as opposed to 1 for failing tests (clap also uses it for invalid arguments)
+pub const EXIT_ERROR: i32 = 2;
+
+#[derive(Debug)]
+pub enum Error {
+    /// An invalid option or argument (such as a bad glob or env pair), the message names which
+    Config(String),
+
+    /// A file that couldn't be read or written: what was being done with it, its path, and why
+    Io {
+        action: &'static str,
+        path: String,
+        source: std::io::Error,
+    },
+
+    /// A db file (or a file in a db dir) that couldn't be parsed
+    Db { path: String, message: String },
+
+    /// Anything else that stops a run, such as a failed setup hook or a db that's in use
+    Other(String),
+}
+
+/// The result of anything that can fail with an Error
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// An Io error for a path
+    pub fn io(action: &'static str, path: impl fmt::Display, source: std::io::Error) -> Error {
+        Error::Io {
+            action,
+            path: path.to_string(),
+            source,
+        }
+    }
+
+    /// Print the error and exit with EXIT_ERROR
+    pub fn exit(&self) -> ! {
+        eprintln!("{}", self);
+        std::process::exit(EXIT_ERROR);
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Config(message) | Error::Other(message) => write!(f, "{}", message),
+            Error::Io {
+                action,
+                path,
+                source,
+            } => write!(f, "Unable to {} {}: {}", action, path, source),
+            Error::Db { path, message } => write!(f, "Database file {} {}", path, message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,89 @@
+// Machine readable events, written as newline delimited JSON so other tools can follow a run live
+use std::io::Write;
+use std::sync::Mutex;
+
+use serde::Serialize;
+
+use crate::error::{Error, Result};
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    /// The run is starting with this many tests
+    RunStarted { total: usize },
+
+    /// A test is about to run
+    TestStarted { file: &'a str },
+
+    /// A test's command finished (or was skipped) but hasn't been compared yet
+    TestFinished {
+        file: &'a str,
+        status: &'a str,
+        elapsed_ms: Option<u128>,
+
+        /// For timeouts, which limit was hit (wall_clock or cpu)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        limit: Option<&'a str>,
+
+        /// For errors, why the test couldn't be run
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reason: Option<&'a str>,
+    },
+
+    /// A test's output was compared against the db
+    TestResult {
+        file: &'a str,
+        status: &'a str,
+        new: bool,
+    },
+
+    /// Something went wrong running a test itself, such as output held open by a background process
+    Incident { file: &'a str, description: &'a str },
+
+    /// The run is done
+    Summary {
+        successes: usize,
+        new_successes: usize,
+        failures: usize,
+        timeouts: usize,
+        errors: usize,
+        skipped: usize,
+        unchanged: usize,
+        unsaved: usize,
+        incidents: usize,
+        not_run: usize,
+    },
+}
+
+/// Where to send events; events are dropped if no stream was requested
+pub struct EventStream {
+    writer: Option<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl EventStream {
+    /// Open the event stream at the given path, - for stdout
+    pub fn open(path: Option<&str>) -> Result<EventStream> {
+        let writer: Option<Box<dyn Write + Send>> = match path {
+            None => None,
+            Some("-") => Some(Box::new(std::io::stdout())),
+            Some(path) => Some(Box::new(
+                std::fs::File::create(path)
+                    .map_err(|err| Error::io("create events file", path, err))?,
+            )),
+        };
+
+        Ok(EventStream {
+            writer: writer.map(Mutex::new),
+        })
+    }
+
+    /// Write a single event as one line, flushing so readers see it immediately
+    pub fn emit(&self, event: Event) {
+        if let Some(writer) = &self.writer {
+            let mut writer = writer.lock().unwrap();
+            serde_json::to_writer(&mut *writer, &event).unwrap();
+            writeln!(writer).unwrap();
+            writer.flush().unwrap();
+        }
+    }
+}
@@ -0,0 +1,83 @@
+// Export db contents for use in other tools (spreadsheets, dashboards, etc)
+use std::collections::BTreeSet;
+use std::io::Write;
+
+use crate::error::{Error, Result};
+use crate::Db;
+
+#[derive(Debug, Clone, clap::ValueEnum)]
+pub enum ExportFormat {
+    /// Comma separated values with a header row
+    Csv,
+}
+
+impl std::fmt::Display for ExportFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExportFormat::Csv => write!(f, "csv"),
+        }
+    }
+}
+
+/// Export one row per test in the db to the given file (or stdout)
+pub fn export(db: &Db, format: &ExportFormat, output: Option<&str>) -> Result<()> {
+    let mut writer: Box<dyn Write> = match output {
+        Some(path) => Box::new(
+            std::fs::File::create(path)
+                .map_err(|err| Error::io("create export file", path, err))?,
+        ),
+        None => Box::new(std::io::stdout()),
+    };
+
+    match format {
+        ExportFormat::Csv => write_csv(db, &mut writer),
+    }
+    .map_err(|err| Error::io("write export to", output.unwrap_or("stdout"), err))
+}
+
+fn write_csv(db: &Db, writer: &mut dyn Write) -> std::io::Result<()> {
+    writeln!(
+        writer,
+        "file,status,fastest_ms,most_recent_ms,variants,output_bytes"
+    )?;
+
+    // Any test we know anything about gets a row
+    let files = db
+        .results
+        .keys()
+        .chain(db.timing.keys())
+        .chain(db.status.keys())
+        .collect::<BTreeSet<_>>();
+
+    for file in files {
+        let status = db.status.get(file).map(|s| s.to_string());
+        let timing = db.timing.get(file);
+        let outputs = db.results.get(file);
+
+        writeln!(
+            writer,
+            "{},{},{},{},{},{}",
+            csv_escape(file),
+            status.unwrap_or_default(),
+            timing.map(|t| t.fastest.to_string()).unwrap_or_default(),
+            timing
+                .map(|t| t.most_recent.to_string())
+                .unwrap_or_default(),
+            outputs.map(|o| o.len()).unwrap_or(0),
+            outputs
+                .map(|o| o.iter().map(|s| s.len()).sum::<usize>())
+                .unwrap_or(0),
+        )?;
+    }
+
+    Ok(())
+}
+
+// Quote a field if it contains anything that would break the row
+fn csv_escape(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
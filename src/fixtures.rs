@@ -0,0 +1,119 @@
+// Comparing a directory of outputs from a test against a committed fixture tree
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use crate::diff;
+use crate::error::{Error, Result};
+
+/// A difference between a fixture tree and the actual output
+#[derive(Debug)]
+pub enum Change {
+    /// A file in the output that isn't in the fixture
+    Added(PathBuf),
+
+    /// A file in the fixture that wasn't output
+    Removed(PathBuf),
+
+    /// A file in both with different contents, along with a line diff (if both are text)
+    Changed(PathBuf, Option<String>),
+}
+
+impl std::fmt::Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Change::Added(path) => write!(f, "added: {}", path.display()),
+            Change::Removed(path) => write!(f, "removed: {}", path.display()),
+            Change::Changed(path, None) => write!(f, "changed: {} (binary)", path.display()),
+            Change::Changed(path, Some(diff)) => {
+                write!(f, "changed: {}\n{}", path.display(), diff)
+            }
+        }
+    }
+}
+
+/// Recursively compare the actual output directory against the expected fixture directory
+/// A missing fixture directory is treated as empty
+pub fn compare(expected: &Path, actual: &Path) -> Result<Vec<Change>> {
+    let expected_files = list_files(expected);
+    let actual_files = list_files(actual);
+
+    let mut changes = Vec::new();
+    for file in expected_files.union(&actual_files) {
+        match (expected_files.contains(file), actual_files.contains(file)) {
+            (true, false) => changes.push(Change::Removed(file.clone())),
+            (false, true) => changes.push(Change::Added(file.clone())),
+            _ => {
+                let read = |path: PathBuf| {
+                    std::fs::read(&path).map_err(|err| Error::io("read", path.display(), err))
+                };
+                let old = read(expected.join(file))?;
+                let new = read(actual.join(file))?;
+                if old != new {
+                    let diff = match (String::from_utf8(old), String::from_utf8(new)) {
+                        (Ok(old), Ok(new)) => Some(diff::unified(&old, &new)),
+                        _ => None,
+                    };
+                    changes.push(Change::Changed(file.clone(), diff));
+                }
+            }
+        }
+    }
+
+    Ok(changes)
+}
+
+/// Replace the fixture directory with the actual output
+pub fn update(expected: &Path, actual: &Path) -> Result<()> {
+    if expected.exists() {
+        std::fs::remove_dir_all(expected)
+            .map_err(|err| Error::io("remove old fixture", expected.display(), err))?;
+    }
+
+    for file in list_files(actual) {
+        let target = expected.join(&file);
+        std::fs::create_dir_all(target.parent().unwrap())
+            .and_then(|_| std::fs::copy(actual.join(&file), &target))
+            .map_err(|err| Error::io("update fixture", target.display(), err))?;
+    }
+    std::fs::create_dir_all(expected)
+        .map_err(|err| Error::io("update fixture", expected.display(), err))
+}
+
+/// Copy every file under a directory (recursively) into another, creating directories as needed
+pub fn copy(from: &Path, to: &Path) -> std::io::Result<()> {
+    if !from.is_dir() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "not a directory",
+        ));
+    }
+
+    for file in list_files(from) {
+        let target = to.join(&file);
+        std::fs::create_dir_all(target.parent().unwrap())?;
+        std::fs::copy(from.join(&file), &target)?;
+    }
+    Ok(())
+}
+
+/// All files under a directory (recursively), relative to it; a missing directory has none
+pub fn list_files(root: &Path) -> BTreeSet<PathBuf> {
+    fn visit(root: &Path, directory: &Path, files: &mut BTreeSet<PathBuf>) {
+        let Ok(entries) = std::fs::read_dir(directory) else {
+            return;
+        };
+
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let path = entry.path();
+            if path.is_dir() {
+                visit(root, &path, files);
+            } else {
+                files.insert(path.strip_prefix(root).unwrap().to_path_buf());
+            }
+        }
+    }
+
+    let mut files = BTreeSet::new();
+    visit(root, root, &mut files);
+    files
+}
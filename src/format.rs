@@ -0,0 +1,75 @@
+// Db file formats: JSON by default, YAML so multi-line outputs are block scalars that read well in a diff,
+// or CBOR (a compact binary encoding of the same data) for huge suites where parsing JSON slows down startup
+// Reading detects the format from the (decompressed) contents, writing picks it from --db-format or the file extension
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Format {
+    /// JSON (.json)
+    Json,
+
+    /// YAML (.yaml or .yml)
+    Yaml,
+
+    /// CBOR (.cbor)
+    Cbor,
+}
+
+impl Format {
+    /// Detect the format of a (decompressed) db file's contents; dbs are always an object (a map in CBOR)
+    pub fn detect(bytes: &[u8]) -> Format {
+        if bytes
+            .first()
+            .is_some_and(|byte| (0xa0..=0xbf).contains(byte))
+        {
+            return Format::Cbor;
+        }
+        match bytes.iter().find(|byte| !byte.is_ascii_whitespace()) {
+            Some(b'{') | None => Format::Json,
+            Some(_) => Format::Yaml,
+        }
+    }
+
+    /// Guess the format from a file name (ignoring a compression extension), if it has a known extension
+    pub fn from_extension(path: &str) -> Option<Format> {
+        let path = [".gz", ".zst", ".zstd"]
+            .iter()
+            .find_map(|extension| path.strip_suffix(extension))
+            .unwrap_or(path);
+
+        if path.ends_with(".json") {
+            Some(Format::Json)
+        } else if path.ends_with(".yaml") || path.ends_with(".yml") {
+            Some(Format::Yaml)
+        } else if path.ends_with(".cbor") {
+            Some(Format::Cbor)
+        } else {
+            None
+        }
+    }
+
+    /// Parse the contents of a db file, whatever its format, as JSON (for migrations)
+    pub fn parse(self, bytes: &[u8]) -> Result<serde_json::Value, String> {
+        match self {
+            Format::Json => serde_json::from_slice(bytes).map_err(|err| err.to_string()),
+            Format::Yaml => serde_yaml::from_slice(bytes).map_err(|err| err.to_string()),
+            Format::Cbor => ciborium::from_reader(bytes).map_err(|err| err.to_string()),
+        }
+    }
+
+    pub fn serialize<T: Serialize>(self, value: &T) -> Vec<u8> {
+        match self {
+            Format::Json => {
+                let mut bytes = serde_json::to_vec_pretty(value).unwrap();
+                bytes.push(b'\n');
+                bytes
+            }
+            Format::Yaml => serde_yaml::to_string(value).unwrap().into_bytes(),
+            Format::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::into_writer(value, &mut bytes).unwrap();
+                bytes
+            }
+        }
+    }
+}
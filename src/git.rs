@@ -0,0 +1,59 @@
+// Running git, for comparing against older versions of a db and committing new ones
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The contents of a file (relative to a directory) at a revision
+pub fn show(directory: &Path, revision: &str, file: &str) -> Result<Vec<u8>, String> {
+    run(directory, &["show", &format!("{}:./{}", revision, file)])
+}
+
+/// Every file under a directory (relative to another directory) at a revision, relative to it
+pub fn list(directory: &Path, revision: &str, subdirectory: &str) -> Result<Vec<String>, String> {
+    let tree = format!("{}:./{}", revision, subdirectory);
+    let files = run(
+        directory,
+        &["ls-tree", "-r", "--full-tree", "--name-only", "-z", &tree],
+    )?;
+
+    Ok(String::from_utf8_lossy(&files)
+        .split('\0')
+        .filter(|file| !file.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+/// Stage and commit just these paths (including removed files under them), leaving anything else staged alone
+/// Returns false if there was nothing to commit
+pub fn commit(paths: &[PathBuf], message: &str) -> Result<bool, String> {
+    let here = Path::new(".");
+    let on_paths = |args: &[&str]| {
+        let mut all = Vec::new();
+        all.extend_from_slice(args);
+        all.push("--");
+        all.extend(paths.iter().map(|path| path.to_str().unwrap()));
+        run(here, &all)
+    };
+
+    on_paths(&["add", "--all"])?;
+    if on_paths(&["diff", "--cached", "--name-only"])?.is_empty() {
+        return Ok(false);
+    }
+    on_paths(&["commit", "--quiet", "-m", message])?;
+    Ok(true)
+}
+
+// Run git from a directory, returning its stdout or (on failure) its stderr
+fn run(directory: &Path, args: &[&str]) -> Result<Vec<u8>, String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(directory)
+        .args(args)
+        .output()
+        .map_err(|err| format!("unable to run git: {}", err))?;
+
+    if output.status.success() {
+        Ok(output.stdout)
+    } else {
+        Err(String::from_utf8_lossy(&output.stderr).trim().to_string())
+    }
+}
@@ -0,0 +1,68 @@
+// Hashing input files and their dependencies, to tell when they've changed
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+
+use crate::error::{Error, Result};
+
+/// Hash the contents of a file as a hex encoded sha256
+pub fn hash_file(file: &Path) -> Result<String> {
+    Ok(hash_bytes(&read(file)?))
+}
+
+/// Hash bytes as a hex encoded sha256
+pub fn hash_bytes(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+/// Find any extra files an input depends on, relative to the input's directory
+/// These can be listed one per line in a `<file>.deps` sidecar or with `testit:depends <path>` in the file
+pub fn dependencies(file: &Path) -> Result<Vec<String>> {
+    let mut dependencies = Vec::new();
+
+    let mut sidecar = file.as_os_str().to_owned();
+    sidecar.push(".deps");
+    if let Ok(contents) = std::fs::read_to_string(sidecar) {
+        dependencies.extend(
+            contents
+                .lines()
+                .map(|line| line.trim())
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(|line| line.to_string()),
+        );
+    }
+
+    let contents = read(file)?;
+    for line in String::from_utf8_lossy(&contents).lines() {
+        if let Some((_, dependency)) = line.split_once("testit:depends ") {
+            dependencies.push(dependency.trim().to_string());
+        }
+    }
+
+    Ok(dependencies)
+}
+
+/// Hash each dependency of an input file; missing dependencies are recorded as such
+pub fn hash_dependencies(file: &Path) -> Result<BTreeMap<String, String>> {
+    let directory = file.parent().unwrap_or(Path::new("."));
+
+    dependencies(file)?
+        .into_iter()
+        .map(|dependency| {
+            let path = directory.join(&dependency);
+            let hash = if path.exists() {
+                hash_file(&path)?
+            } else {
+                log::warn!("{}: Missing dependency {}", file.display(), dependency);
+                "missing".to_string()
+            };
+            Ok((dependency, hash))
+        })
+        .collect()
+}
+
+// Read a file to hash (or look for dependencies in), with an error naming it if it can't be read
+fn read(file: &Path) -> Result<Vec<u8>> {
+    std::fs::read(file).map_err(|err| Error::io("read", file.display(), err))
+}
@@ -0,0 +1,90 @@
+// Showing how a test has done over its recent runs, to spot tests that are slowly getting worse
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::db::{HistoryEntry, Status};
+use crate::error::{Error, Result};
+use crate::Db;
+
+// Characters for a sparkline of durations, from shortest to longest
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// Print each run in a test's history (oldest first), then the trend of its status and duration
+pub fn print(db: &Db, file: &str) -> Result<()> {
+    let Some(history) = db.history.get(file).filter(|history| !history.is_empty()) else {
+        return Err(Error::Config(format!("No history for {}", file)));
+    };
+
+    println!("History of {} (last {} runs):", file, history.len());
+    for entry in history {
+        let at = humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(entry.at));
+        match entry.elapsed_ms {
+            Some(elapsed_ms) => println!("\t{}  {:<8} {}ms", at, entry.status, elapsed_ms),
+            None => println!("\t{}  {}", at, entry.status),
+        }
+    }
+
+    println!();
+    println!("Status: {}", status_counts(history));
+
+    let durations = history
+        .iter()
+        .filter_map(|entry| entry.elapsed_ms)
+        .collect::<Vec<_>>();
+    if let Some(trend) = duration_trend(&durations) {
+        println!("Duration: {}", trend);
+    }
+    Ok(())
+}
+
+// Count each status, like: 18 success, 2 failure
+fn status_counts(history: &[HistoryEntry]) -> String {
+    [
+        Status::Success,
+        Status::New,
+        Status::Failure,
+        Status::Timeout,
+        Status::Error,
+        Status::Skipped,
+    ]
+    .iter()
+    .map(|status| {
+        let count = history
+            .iter()
+            .filter(|entry| entry.status == *status)
+            .count();
+        (status, count)
+    })
+    .filter(|(_, count)| *count > 0)
+    .map(|(status, count)| format!("{} {}", count, status))
+    .collect::<Vec<_>>()
+    .join(", ")
+}
+
+// Compare the average of the older half of the durations to the newer half, with a sparkline of all of them
+// Like: 105ms -> 210ms (+100%) ▁▁▂▄█
+fn duration_trend(durations: &[u128]) -> Option<String> {
+    if durations.len() < 2 {
+        return None;
+    }
+
+    let half = durations.len() / 2;
+    let average = |durations: &[u128]| durations.iter().sum::<u128>() / durations.len() as u128;
+    let older = average(&durations[..half]);
+    let newer = average(&durations[durations.len() - half..]);
+    let change = (newer as f64 - older as f64) / (older.max(1) as f64) * 100.0;
+
+    let min = *durations.iter().min().unwrap();
+    let max = *durations.iter().max().unwrap();
+    let sparkline = durations
+        .iter()
+        .map(|duration| {
+            let level = (duration - min) * (SPARKS.len() as u128 - 1) / (max - min).max(1);
+            SPARKS[level as usize]
+        })
+        .collect::<String>();
+
+    Some(format!(
+        "{}ms -> {}ms ({:+.0}%) {}",
+        older, newer, change, sparkline
+    ))
+}
@@ -0,0 +1,119 @@
+// Seeding a db from an existing corpus of golden (expected output) files, without running anything
+use std::path::{Component, Path, PathBuf};
+
+use crate::error::{Error, Result};
+use crate::suite::TestSuite;
+
+/// Accept golden files matching a glob pattern (relative to the directory) as the outputs of inputs
+/// Each golden file's input comes from a template, in which these are replaced (based on the golden file):
+/// - {path}: its path, relative to the directory
+/// - {dir}: its directory, relative to the start of the pattern before any wildcards
+/// - {name}: its file name
+/// - {stem}: its file name without the extension
+///
+/// Returns each input and the golden file imported for it; golden files for inputs that aren't in the suite are skipped
+pub fn import(
+    suite: &mut TestSuite,
+    expected_glob: &str,
+    map: &str,
+) -> Result<Vec<(String, PathBuf)>> {
+    let directory = suite
+        .db
+        .metadata
+        .directory
+        .as_deref()
+        .map(PathBuf::from)
+        .unwrap_or_default();
+    let base = directory.join(pattern_base(expected_glob));
+
+    let files = suite.files()?;
+    let inputs = files
+        .iter()
+        .map(|file| suite.relative_path(file).to_path_buf())
+        .collect::<Vec<_>>();
+
+    let pattern = directory.join(expected_glob);
+    let golden_files = glob::glob(&pattern.to_string_lossy())
+        .map_err(|err| Error::Config(format!("Invalid expected glob {}: {}", expected_glob, err)))?
+        .filter_map(|path| path.ok())
+        .filter(|path| path.is_file());
+
+    let mut imported = Vec::new();
+    for golden in golden_files {
+        // Every part of the path is put into the input's name, so it has to be text
+        if golden.to_str().is_none() {
+            return Err(Error::Config(format!(
+                "Golden file names must be valid UTF-8: {}",
+                golden.display()
+            )));
+        }
+
+        let relative = golden.strip_prefix(&directory).unwrap_or(&golden);
+        let dir = golden
+            .parent()
+            .and_then(|parent| parent.strip_prefix(&base).ok())
+            .unwrap_or(Path::new(""));
+        let input = map
+            .replace("{path}", &relative.to_string_lossy())
+            .replace("{dir}", &dir.to_string_lossy())
+            .replace(
+                "{name}",
+                &golden.file_name().unwrap_or_default().to_string_lossy(),
+            )
+            .replace(
+                "{stem}",
+                &golden.file_stem().unwrap_or_default().to_string_lossy(),
+            );
+
+        // Templates like {dir}/{stem}.txt leave extra separators when {dir} is empty
+        let input = Path::new(&input)
+            .components()
+            .filter(|component| *component != Component::CurDir)
+            .collect::<PathBuf>();
+
+        if !inputs.contains(&input) {
+            log::warn!(
+                "Skipping {}: {} isn't one of the suite's files",
+                golden.display(),
+                input.display()
+            );
+            continue;
+        }
+
+        let output = std::fs::read_to_string(&golden)
+            .map_err(|err| Error::io("read golden file", golden.display(), err))?;
+
+        let key = input.to_string_lossy().to_string();
+        let results = suite.db.results.entry(key.clone()).or_default();
+        if !results.contains(&output) {
+            results.push(output);
+        }
+        imported.push((key, golden));
+    }
+
+    Ok(imported)
+}
+
+// The leading directories of a glob pattern, before any wildcards (or the parent, if there aren't any)
+fn pattern_base(pattern: &str) -> PathBuf {
+    let components = Path::new(pattern).components().collect::<Vec<_>>();
+    let literal = components
+        .iter()
+        .take_while(|component| {
+            !component
+                .as_os_str()
+                .to_str()
+                .unwrap()
+                .contains(['*', '?', '['])
+        })
+        .count();
+
+    if literal == components.len() {
+        Path::new(pattern)
+            .parent()
+            .unwrap_or(Path::new(""))
+            .to_path_buf()
+    } else {
+        components[..literal].iter().collect()
+    }
+}
@@ -0,0 +1,44 @@
+//! Test a series of input files against a command to check that its output hasn't changed
+//!
+//! The `testit` binary is a thin command line wrapper around this library. To run a suite from
+//! other code, load (or create) a [`TestSuite`], run its files with a [`Runner`], then
+//! [`TestSuite::record`] each [`TestOutcome`] to compare it against (and update) the db.
+pub mod ab;
+pub mod artifacts;
+pub mod color;
+pub mod compare;
+pub mod compression;
+pub mod db;
+pub mod db_dir;
+pub mod diff;
+pub mod doctor;
+pub mod error;
+pub mod events;
+pub mod export;
+pub mod fixtures;
+pub mod format;
+pub mod git;
+pub mod hash;
+pub mod history;
+pub mod import;
+pub mod lock;
+pub mod log_file;
+pub mod manifest;
+pub mod migrate;
+pub mod normalize;
+pub mod notify;
+pub mod options;
+pub mod remote;
+pub mod report;
+pub mod resources;
+pub mod runner;
+pub mod shard;
+pub mod snapshots;
+pub mod stats;
+pub mod suite;
+
+pub use db::{Db, HistoryEntry, Status, TimingData, Usage};
+pub use error::Error;
+pub use options::{Metadata, Options, StreamMode, TestCommand};
+pub use runner::{Runner, TestOutcome};
+pub use suite::{Comparison, TestSuite};
@@ -0,0 +1,60 @@
+// Advisory locks on db files, so concurrent runs against the same db don't clobber each other
+use std::fs::{File, OpenOptions, TryLockError};
+
+use crate::error::{Error, Result};
+
+/// An exclusive lock on a db, held until this is dropped (or the process exits)
+/// The lock is taken on a `<db>.lock` file next to the db, since saving can replace the db file itself
+pub struct DbLock {
+    _file: File,
+}
+
+impl DbLock {
+    /// Lock a db (which doesn't need to exist yet), either waiting for another run to finish or failing
+    pub fn acquire(db_path: &str, wait: bool) -> Result<DbLock> {
+        let path = format!("{}.lock", db_path);
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .map_err(|err| Error::io("open lock file", &path, err))?;
+
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) if wait => {
+                eprintln!("Waiting for another run to finish with {}", db_path);
+                file.lock().map_err(|err| Error::io("lock", &path, err))?;
+            }
+            Err(TryLockError::WouldBlock) => {
+                return Err(Error::Other(format!(
+                    "Database file is in use by another run: {}",
+                    db_path
+                )))
+            }
+            Err(TryLockError::Error(err)) => return Err(Error::io("lock", path, err)),
+        }
+
+        Ok(DbLock { _file: file })
+    }
+}
+
+/// Lock several dbs, in a consistent order so two runs with overlapping dbs can't deadlock
+pub fn acquire_all(db_paths: &[String], wait: bool) -> Result<Vec<DbLock>> {
+    let mut sorted = db_paths
+        .iter()
+        .map(|path| {
+            let canonical = std::fs::canonicalize(path)
+                .map(|p| p.display().to_string())
+                .unwrap_or_else(|_| path.clone());
+            (canonical, path)
+        })
+        .collect::<Vec<_>>();
+    sorted.sort();
+    sorted.dedup_by(|a, b| a.0 == b.0);
+
+    sorted
+        .into_iter()
+        .map(|(_, path)| DbLock::acquire(path, wait))
+        .collect()
+}
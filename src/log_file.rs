@@ -0,0 +1,147 @@
+// Logging to the console at the chosen verbosity and, optionally, to a file at trace level (see --log-file)
+// The file gets everything, including each test's command, timing, and (truncated) output, so quiet runs can be debugged later
+// Either can be text for people or one JSON object per line for log aggregation (see --log-format)
+use std::fs::File;
+use std::io::Write;
+use std::time::SystemTime;
+
+use log::kv::{self, Key, Value, VisitSource};
+use log::{LevelFilter, Log, Metadata, Record};
+
+use crate::error::{self, Error};
+
+/// How much of each test's stdout and stderr is written to the log file
+pub const OUTPUT_LENGTH: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum LogFormat {
+    /// A line of text for each record
+    Text,
+
+    /// A JSON object for each record, with its time, level, target, and message plus any fields
+    /// (such as event, file, status, duration_ms, and bytes for each test)
+    Json,
+}
+
+impl std::fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LogFormat::Text => write!(f, "text"),
+            LogFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+// Sends each record to the console and the file loggers that want it
+struct Logger {
+    console: env_logger::Logger,
+    file: Option<env_logger::Logger>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.console.enabled(metadata)
+            || self
+                .file
+                .as_ref()
+                .is_some_and(|file| file.enabled(metadata))
+    }
+
+    fn log(&self, record: &Record) {
+        if self.console.enabled(record.metadata()) {
+            self.console.log(record);
+        }
+        if let Some(file) = &self.file {
+            if file.enabled(record.metadata()) {
+                file.log(record);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        self.console.flush();
+        if let Some(file) = &self.file {
+            file.flush();
+        }
+    }
+}
+
+/// Log to the console at a level, and to a file (created or truncated) at trace level if given
+pub fn init(level: LevelFilter, path: Option<&str>, format: LogFormat) -> error::Result<()> {
+    let console = builder(format).filter_level(level).build();
+
+    let file = match path {
+        Some(path) => {
+            let file = File::create(path).map_err(|err| Error::io("create log file", path, err))?;
+            Some(
+                builder(format)
+                    .filter_level(LevelFilter::Trace)
+                    .write_style(env_logger::WriteStyle::Never)
+                    .target(env_logger::Target::Pipe(Box::new(file)))
+                    .build(),
+            )
+        }
+        None => None,
+    };
+
+    let max_level = if file.is_some() {
+        LevelFilter::Trace
+    } else {
+        console.filter()
+    };
+    log::set_boxed_logger(Box::new(Logger { console, file }))
+        .map_err(|_| Error::Other("A logger has already been set".to_string()))?;
+    log::set_max_level(max_level);
+    Ok(())
+}
+
+// A logger builder writing records in a format
+fn builder(format: LogFormat) -> env_logger::Builder {
+    let mut builder = env_logger::Builder::new();
+    if format == LogFormat::Json {
+        builder.format(|buf, record| {
+            let mut object = serde_json::Map::new();
+            object.insert(
+                "time".to_string(),
+                humantime::format_rfc3339_millis(SystemTime::now())
+                    .to_string()
+                    .into(),
+            );
+            object.insert("level".to_string(), record.level().as_str().into());
+            object.insert("target".to_string(), record.target().into());
+            object.insert("message".to_string(), record.args().to_string().into());
+            let _ = record.key_values().visit(&mut Fields(&mut object));
+
+            serde_json::to_writer(&mut *buf, &object)?;
+            writeln!(buf)
+        });
+    }
+    builder
+}
+
+// Collects a record's fields into a JSON object, as numbers and booleans where they are
+struct Fields<'a>(&'a mut serde_json::Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let value = if let Some(value) = value.to_u64() {
+            value.into()
+        } else if let Some(value) = value.to_i64() {
+            value.into()
+        } else if let Some(value) = value.to_bool() {
+            value.into()
+        } else {
+            value.to_string().into()
+        };
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+/// At most OUTPUT_LENGTH characters of an output for the log file, noting how much more there was
+pub fn excerpt(output: &str) -> String {
+    match output.char_indices().nth(OUTPUT_LENGTH) {
+        Some((end, _)) => format!("{}... ({} more bytes)", &output[..end], output.len() - end),
+        None => output.to_string(),
+    }
+}
@@ -1,17 +1,27 @@
 use std::collections::BTreeMap;
-use std::io::Read;
-use std::path;
-use std::process::Command;
-use std::time::Duration;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
 
 use clap::Parser;
 use clap_verbosity_flag::Verbosity;
-use env_logger;
-use log;
-use rayon::iter::ParallelIterator;
-use rayon_progress::ProgressAdaptor;
-use serde::{Deserialize, Serialize};
-use wait_timeout::ChildExt;
+
+use testit::artifacts::{self, Artifacts};
+use testit::color::{self, Color, ColorMode};
+use testit::compression::Compression;
+use testit::events::{Event, EventStream};
+use testit::format::Format;
+use testit::lock::{self, DbLock};
+use testit::log_file::{self, LogFormat};
+use testit::manifest::Manifest;
+use testit::report::SortBy;
+use testit::runner::Limit;
+use testit::shard::{self, Shard};
+use testit::snapshots;
+use testit::{
+    ab, compare, db, db_dir, doctor, error, export, git, hash, history, import, migrate, notify,
+    remote, report, stats,
+};
+use testit::{Db, Error, Metadata, Options, Runner, Status, TestCommand, TestOutcome, TestSuite};
 
 /// Test a series of input files to check that output hasn't changed
 #[derive(Parser, Debug)]
@@ -25,47 +35,185 @@ struct Args {
     #[command(flatten)]
     verbose: Verbosity,
 
+    /// Also write the full trace level log (each test's command, timing, and output) to this file, whatever the verbosity
+    #[arg(long, global = true)]
+    log_file: Option<String>,
+
+    /// How to write log records, on the console and in --log-file
+    #[arg(long, global = true, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
     /// If this flag is set, don't automatically save to the database (if set)
     #[arg(short = 'n', long, action, global = true)]
     dry_run: bool,
-}
 
-// Options that are saved with record and cannot be overridden
-#[derive(Parser, Debug, Clone, Serialize, Deserialize)]
-struct Metadata {
-    /// The command to run; should read from stdin and write to stdout and/or stderr
-    command: String,
+    /// Check input files against the hashes they were recorded with before running (default: warn, or error if given without a value)
+    #[arg(long, global = true, num_args = 0..=1, default_missing_value = "error")]
+    verify_inputs: Option<VerifyMode>,
 
-    /// The working directory to run the command from (default: cwd)
-    #[arg(short, long)]
-    directory: Option<String>,
+    /// Refuse to run if any input changed since its results were recorded (the same as --verify-inputs error)
+    #[arg(long, action, global = true, conflicts_with = "verify_inputs")]
+    strict_inputs: bool,
 
-    /// A glob style pattern defining the files to test
-    files: String,
-}
+    /// Refuse to run if the tool's version (see --version-command) differs from the one that recorded the baseline
+    #[arg(long, action, global = true)]
+    strict_version: bool,
+
+    /// Only run files that have changed (including their dependencies) since their last success, or were never run
+    #[arg(long, visible_alias = "changed-only", action, global = true)]
+    incremental: bool,
+
+    /// Only run the tests that failed, timed out, couldn't run, or had new output in their last recorded run
+    #[arg(long, action, global = true)]
+    failed_only: bool,
+
+    /// Only run files that aren't in the db yet, to record new inputs without rerunning the rest
+    #[arg(long, action, global = true, conflicts_with = "failed_only")]
+    new_only: bool,
 
-// Global options
-#[derive(Parser, Debug, Clone, Serialize, Deserialize)]
-struct Options {
-    /// How to direct stdout (default: both)
-    #[arg(long)]
-    stdout_mode: Option<StreamMode>,
+    /// Run the tests that failed, timed out, or couldn't run in their last recorded run first, for the quickest signal while fixing them
+    #[arg(long, action, global = true)]
+    failed_first: bool,
 
-    /// How to direct stderr (default: print)
-    #[arg(long)]
-    stderr_mode: Option<StreamMode>,
+    /// Replace fixture trees that don't match (see --fixtures) with the actual output instead of failing
+    #[arg(long, action, global = true)]
+    update_fixtures: bool,
 
-    /// Specify environment variables as key=value pairs; multiple can be specified (default: [])
-    #[arg(short, long)]
-    env: Vec<String>,
+    /// Keep the temporary directory ($TESTIT_TMPDIR) of each test that fails instead of deleting it
+    #[arg(long, action, global = true)]
+    keep_failed_tmp: bool,
 
-    /// Preserve the environment of the parent process (default: false)
-    #[arg(short = 'E', long)]
-    preserve_env: Option<bool>,
+    /// Run hermetically and one test at a time with a pinned locale and timezone, writing a manifest of everything the run depended on
+    #[arg(long, action, global = true)]
+    reproducible: bool,
 
-    /// The time to allow for each test in seconds (default: 10)
-    #[arg(short, long)]
-    timeout: Option<u64>,
+    /// Where to write the --reproducible manifest (default: <db>.manifest.json, or testit.manifest.json for run)
+    #[arg(long, global = true)]
+    manifest: Option<String>,
+
+    /// Run the files listed in this file (one per line, relative to the directory) instead of the files patterns, - for stdin
+    #[arg(long, global = true)]
+    files_from: Option<String>,
+
+    /// Only run shard K of N (such as 2/4), splitting the files evenly by their recorded timings
+    #[arg(long, global = true, value_parser = shard::parse)]
+    shard: Option<Shard>,
+
+    /// Only run tests owned by this owner (see --owners), or me for $USER
+    #[arg(long, global = true)]
+    owner: Option<String>,
+
+    /// Print the resolved configuration of each suite as JSON and exit without running anything
+    #[arg(long, action, global = true)]
+    print_config: bool,
+
+    /// Print which files would run (in order) and exactly how, then exit without running anything
+    #[arg(long, action, global = true)]
+    plan: bool,
+
+    /// Wait for other runs using the same database file to finish (default)
+    #[arg(long, action, global = true, overrides_with = "no_wait")]
+    wait: bool,
+
+    /// Exit immediately if another run is using the same database file
+    #[arg(long, action, global = true, overrides_with = "wait")]
+    no_wait: bool,
+
+    /// Exit with an error if any tests had new output, even if it was saved
+    #[arg(long, action, global = true)]
+    fail_on_new: bool,
+
+    /// Stop starting new tests after the first failure or timeout (letting running tests finish), skipping the rest
+    #[arg(long, action, global = true)]
+    fail_fast: bool,
+
+    /// Don't exit with an error because of timeouts
+    #[arg(long, action, global = true)]
+    allow_timeouts: bool,
+
+    /// Only exit with an error if there are more than this many failures (default: 0)
+    #[arg(long, global = true)]
+    max_failures: Option<usize>,
+
+    /// Stop starting new tests once the whole run has taken this long (such as 30m), leaving the rest not run
+    #[arg(long, global = true)]
+    max_total_time: Option<humantime::Duration>,
+
+    /// What to do if the database file was changed by someone else during the run
+    #[arg(long, global = true, default_value_t = OnConflict::Abort)]
+    on_conflict: OnConflict,
+
+    /// Don't print each test's output, only the summary and a list of failures and timeouts
+    #[arg(long, action, global = true)]
+    summary_only: bool,
+
+    /// How to order the list of failing tests at the end of a run
+    #[arg(long, global = true, default_value_t = SortBy::Name)]
+    sort: SortBy,
+
+    /// Also write the final summary and failing tests to this file as JSON
+    #[arg(long, global = true)]
+    summary_json: Option<String>,
+
+    /// Print this many of the slowest tests in the run, compared to their recorded fastest times
+    #[arg(long, global = true)]
+    show_slowest: Option<usize>,
+
+    /// Echo each test's output (that would be printed) as it runs, prefixed by the file name
+    #[arg(long, action, global = true)]
+    stream: bool,
+
+    /// When to color statuses and highlight what changed in printed output
+    #[arg(long, global = true, default_value_t = ColorMode::Auto)]
+    color: ColorMode,
+
+    /// Only print this many lines of each output, marking what was removed (default: no limit)
+    #[arg(long, global = true)]
+    truncate: Option<usize>,
+
+    /// The marker for truncated output; {lines}, {bytes}, and {path} are replaced
+    #[arg(
+        long,
+        global = true,
+        default_value = "[testit:truncated lines={lines} bytes={bytes} full={path}]"
+    )]
+    truncate_marker: String,
+
+    /// A directory to write the full output of each reported test to, in a new subdirectory per run
+    #[arg(long, global = true)]
+    artifacts: Option<String>,
+
+    /// Remove artifacts from runs older than this (such as 14d or 12h) when starting a new run
+    #[arg(long, global = true)]
+    artifact_retention: Option<humantime::Duration>,
+
+    /// Remove the oldest runs' artifacts until the total is under this size (such as 500M or 2G)
+    #[arg(long, global = true, value_parser = artifacts::parse_size)]
+    artifact_max_size: Option<u64>,
+
+    /// Write newline delimited JSON events to this file as the run progresses, - for stdout (which requires -q)
+    #[arg(long, global = true)]
+    events: Option<String>,
+
+    /// Reports to generate once the run is done; multiple can be specified (github, markdown=<path>)
+    #[arg(long, global = true)]
+    report: Vec<report::Report>,
+
+    /// POST a JSON summary of the run to this url when it's done (such as a Slack, Discord, or Teams webhook)
+    #[arg(long, global = true)]
+    notify_webhook: Option<String>,
+
+    /// After a record or update without failures, stage and commit the saved db files (and snapshots) with this message
+    #[arg(long, global = true)]
+    git_commit: Option<String>,
+
+    /// Compress the database file when saving (default: by extension, else as it was)
+    #[arg(long, global = true)]
+    compress: Option<Compression>,
+
+    /// Save the database file in this format (default: by extension, else as it was, else JSON)
+    #[arg(long, global = true)]
+    db_format: Option<Format>,
 }
 
 // Subcommands
@@ -80,6 +228,32 @@ enum Mode {
         options: Options,
     },
 
+    /// Run two commands against the same files and report where their outputs and timings differ, without a db
+    Ab {
+        /// The original command
+        #[arg(long)]
+        old: String,
+
+        /// The command to compare to it
+        #[arg(long)]
+        new: String,
+
+        /// The working directory to run the commands from (default: cwd)
+        #[arg(short, long)]
+        directory: Option<String>,
+
+        /// Glob style patterns defining the files to test; multiple can be specified
+        #[arg(long, required = true)]
+        files: Vec<String>,
+
+        /// Glob patterns (relative to the directory) of files to leave out entirely; multiple can be specified (default: [])
+        #[arg(long)]
+        exclude: Vec<String>,
+
+        #[clap(flatten)]
+        options: Options,
+    },
+
     /// Record new input with the given options.
     Record {
         #[clap(flatten)]
@@ -94,428 +268,1883 @@ enum Mode {
 
     /// Reun/update the given db file; new options will also be saved.
     Update {
-        /// The database file to run
+        /// The database file to run, or a glob pattern of database files
+        db: String,
+
+        #[clap(flatten)]
+        options: Options,
+
+        /// Use the given options for this run only, keeping the saved options as they were
+        #[arg(long)]
+        no_save_options: bool,
+
+        /// Use the options saved as this profile (see --save-profile) for this run, under any given here
+        #[arg(long)]
+        profile: Option<String>,
+
+        /// Save the options given here as this profile (replacing it) instead of over the saved options, and use it
+        #[arg(long, conflicts_with_all = ["profile", "no_save_options"])]
+        save_profile: Option<String>,
+
+        /// Accept the output of each successful test as its only accepted output, removing older ones
+        #[arg(long)]
+        accept_all: bool,
+    },
+
+    /// Create a db from existing golden files as accepted outputs, without running anything
+    Import {
+        #[clap(flatten)]
+        metadata: Metadata,
+
+        /// The database file to create
         db: String,
 
+        /// Glob pattern (relative to the directory) of the golden files to import
+        #[arg(long)]
+        expected_glob: String,
+
+        /// The input file (relative to the directory) for each golden file; {path}, {dir}, {name}, and {stem} are replaced
+        #[arg(long)]
+        map: String,
+
         #[clap(flatten)]
         options: Options,
     },
+
+    /// Replace the accepted outputs of tests with their most recent output
+    Bless {
+        /// The database file to update
+        db: String,
+
+        /// The tests to bless, relative to the directory (default: all)
+        files: Vec<String>,
+    },
+
+    /// Combine the db files saved by sharded runs (see --shard) into the db they were copied from
+    Merge {
+        /// The database file each shard started from, to save the combined results to
+        db: String,
+
+        /// The database files saved by each shard
+        #[arg(required = true)]
+        shards: Vec<String>,
+    },
+
+    /// Export the results and timings from a db file
+    Export {
+        /// The database file to export
+        db: String,
+
+        /// The format to export as
+        #[arg(long, default_value_t = export::ExportFormat::Csv)]
+        format: export::ExportFormat,
+
+        /// The file to write to (default: stdout)
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+
+    /// Summarize the tests, timings, and size of a db file
+    Stats {
+        /// The database file to summarize
+        db: String,
+    },
+
+    /// Show a test's recent runs and how its duration is trending
+    History {
+        /// The database file to read
+        db: String,
+
+        /// The test to show, relative to the directory
+        file: String,
+    },
+
+    /// Compare a db file's accepted outputs and timings to the same file at a git revision
+    Compare {
+        /// The database file to compare
+        db: String,
+
+        /// The git revision to compare against (like HEAD~1)
+        #[arg(long)]
+        git: String,
+    },
+
+    /// Convert a db file to another format, picked by --db-format and --compress or the output's extension
+    Convert {
+        /// The database file to convert
+        db: String,
+
+        /// Where to write the converted db (default: in place, keeping a backup of the original)
+        output: Option<String>,
+    },
+
+    /// Upgrade a db file to the current schema version, keeping a backup of the original
+    Migrate {
+        /// The database file to upgrade
+        db: String,
+    },
+
+    /// Check a db file for problems: an invalid schema, duplicate or missing accepted outputs, timing without accepted
+    /// outputs, unreadable input files, and a command that can't be found
+    Doctor {
+        /// The database file to check
+        db: String,
+
+        /// Repair the problems that can be safely repaired (keeping a backup of the original)
+        #[arg(long)]
+        fix: bool,
+    },
+
+    /// Download the db file (and its snapshots or db dir) from shared storage
+    Pull {
+        /// The database file to save to
+        db: String,
+
+        /// The remote to download from, such as s3://bucket/suite
+        #[arg(long)]
+        remote: String,
+
+        /// Overwrite local changes that haven't been pushed
+        #[arg(short, long)]
+        force: bool,
+    },
+
+    /// Upload the db file (and its snapshots or db dir) to shared storage
+    Push {
+        /// The database file to upload
+        db: String,
+
+        /// The remote to upload to, such as s3://bucket/suite
+        #[arg(long)]
+        remote: String,
+
+        /// Overwrite remote changes that haven't been pulled
+        #[arg(short, long)]
+        force: bool,
+    },
 }
 
-#[derive(Debug, Clone, clap::ValueEnum, Serialize, Deserialize)]
-enum StreamMode {
-    /// Don't save or print
-    None,
+#[derive(Debug, Clone, clap::ValueEnum)]
+enum VerifyMode {
+    /// Print changed inputs but run anyway
+    Warn,
+
+    /// Print changed inputs and refuse to run
+    Error,
+}
 
-    /// Save to database, don't print
-    Save,
+#[derive(Debug, Clone, clap::ValueEnum)]
+enum OnConflict {
+    /// Don't save that database (other suites are still run and saved), then exit with an error
+    Abort,
 
-    /// Print as normal, but don't save
-    Print,
+    /// Merge this run's results into the other changes
+    Merge,
 
-    /// Save to database and print
-    Both,
+    /// Save this run's results over the other changes
+    Overwrite,
 }
 
-impl std::fmt::Display for StreamMode {
+impl std::fmt::Display for OnConflict {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            StreamMode::None => write!(f, "none"),
-            StreamMode::Save => write!(f, "save"),
-            StreamMode::Print => write!(f, "print"),
-            StreamMode::Both => write!(f, "both"),
+            OnConflict::Abort => write!(f, "abort"),
+            OnConflict::Merge => write!(f, "merge"),
+            OnConflict::Overwrite => write!(f, "overwrite"),
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-enum TestResult {
-    Success(String, String, u128),
-    Failure(String, String),
-    Timeout,
+// Prepare output for printing
+// The full output is saved as an artifact (if enabled), then truncated with a marker saying what was removed
+fn truncate_output(
+    args: &Args,
+    artifacts: Option<&Artifacts>,
+    file: &Path,
+    output: &str,
+) -> String {
+    let artifact = artifacts.and_then(|artifacts| match artifacts.write(file, output) {
+        Ok(path) => Some(path),
+        Err(err) => {
+            log::warn!("{}", err);
+            None
+        }
+    });
+
+    let Some(max_lines) = args.truncate else {
+        return output.to_string();
+    };
+
+    let lines = output.split_inclusive('\n').collect::<Vec<_>>();
+    if lines.len() <= max_lines {
+        return output.to_string();
+    }
+
+    let kept = lines[..max_lines].concat();
+    let marker = args
+        .truncate_marker
+        .replace("{lines}", &(lines.len() - max_lines).to_string())
+        .replace("{bytes}", &(output.len() - kept.len()).to_string())
+        .replace(
+            "{path}",
+            &artifact.map_or("-".to_string(), |path| path.display().to_string()),
+        );
+
+    format!("{}{}", kept, marker)
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct TimingData {
-    fastest: u128,
-    most_recent: u128,
+// If each test's result should be printed as it's compared (see --summary-only)
+fn prints_tests(args: &Args) -> bool {
+    !args.verbose.is_silent() && !args.summary_only
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Db {
-    results: BTreeMap<String, Vec<String>>,
+// Color text if color is enabled
+fn paint(args: &Args, color: Color, text: &str) -> String {
+    if args.color.enabled() {
+        color.paint(text)
+    } else {
+        text.to_string()
+    }
+}
 
-    #[serde(alias = "%metadata%")]
-    metadata: Metadata,
+// Highlight what changed in an output compared to the previously accepted one (if any and color is enabled)
+fn highlight(args: &Args, previous: Option<&str>, output: &str) -> String {
+    match previous {
+        Some(previous) if args.color.enabled() => color::highlight_changes(previous, output),
+        _ => output.to_string(),
+    }
+}
 
-    #[serde(alias = "%options%")]
-    options: Options,
+// The exit status for a dry run that would have changed a db (with no failures or timeouts)
+const EXIT_UNSAVED_CHANGES: i32 = 3;
 
-    #[serde(alias = "%timing%", default)]
-    timing: BTreeMap<String, TimingData>,
+// The exit status for a run that ran out of time (see --max-total-time) before running every test (with no failures or timeouts)
+const EXIT_OUT_OF_TIME: i32 = 4;
+
+// The db file that results will be saved to, if any
+fn db_path(mode: &Mode) -> Option<&str> {
+    match mode {
+        Mode::Record { db, .. } | Mode::Update { db, .. } => Some(db),
+        _ => None,
+    }
 }
 
 fn main() {
     let args = Args::parse();
-    env_logger::Builder::new()
-        .filter_level(args.verbose.log_level_filter())
-        .init();
+    let start = std::time::Instant::now();
+    log_file::init(
+        args.verbose.log_level_filter(),
+        args.log_file.as_deref(),
+        args.log_format,
+    )
+    .unwrap_or_else(|err| err.exit());
 
     log::warn!("Logs are only available at -v and -vv");
 
-    // Load options
-    macro_rules! override_option {
-        ($db:expr, $args:expr, $field:ident) => {
-            if let Some(value) = &$args.$field {
-                $db.options.$field = Some(value.clone());
+    // Exporting and syncing don't run any tests
+    match &args.mode {
+        Mode::Export { db, format, output } => {
+            let db = Db::read(db).unwrap_or_else(|err| err.exit());
+            export::export(&db, format, output.as_deref()).unwrap_or_else(|err| err.exit());
+            return;
+        }
+        Mode::Stats { db } => {
+            stats::print(&Db::read(db).unwrap_or_else(|err| err.exit()), db);
+            return;
+        }
+        Mode::History { db, file } => {
+            let db = Db::read(db).unwrap_or_else(|err| err.exit());
+            history::print(&db, file).unwrap_or_else(|err| err.exit());
+            return;
+        }
+        Mode::Compare { db, git } => {
+            let old = compare::load_revision(db, git).unwrap_or_else(|err| err.exit());
+            println!("Comparing {} at {} to the working copy", db, git);
+            println!();
+            if compare::print(&old, &Db::read(db).unwrap_or_else(|err| err.exit())) {
+                std::process::exit(1);
+            }
+            return;
+        }
+        Mode::Convert {
+            db: db_path,
+            output,
+        } => {
+            let output = output.as_deref().unwrap_or(db_path);
+            if output == db_path && args.db_format.is_none() && args.compress.is_none() {
+                Error::Config(format!(
+                    "Converting {} in place needs --db-format or --compress",
+                    db_path
+                ))
+                .exit();
             }
-        };
-    }
 
-    // 1) Set values from the mode + defaults
-    let mut db = match &args.mode {
-        Mode::Run { metadata, options }
-        | Mode::Record {
-            metadata, options, ..
-        } => Db {
-            results: BTreeMap::new(),
-            metadata: metadata.clone(),
-            options: options.clone(),
-            timing: BTreeMap::new(),
-        },
-        Mode::Update { db, options } => {
-            // File doesn't exist
-            if !std::path::Path::new(db).exists() {
-                eprintln!("Database file does not exist: {}", db);
-                std::process::exit(1);
+            let _lock = DbLock::acquire(db_path, !args.no_wait).unwrap_or_else(|err| err.exit());
+            let db = Db::read(db_path).unwrap_or_else(|err| err.exit());
+            if args.dry_run {
+                println!("Would convert {} to {}", db_path, output);
+                return;
             }
 
-            let f = std::fs::File::open(db).unwrap();
-            let mut db: Db = serde_json::from_reader(f).unwrap();
+            let before = std::fs::metadata(db_path).unwrap().len();
+            db.save(output, args.compress, args.db_format)
+                .unwrap_or_else(|err| err.exit());
+            let after = std::fs::metadata(output).unwrap().len();
+            println!(
+                "Converted {} ({} bytes) to {} ({} bytes)",
+                db_path, before, output, after
+            );
+            return;
+        }
+        Mode::Migrate { db: db_path } => {
+            let _lock = DbLock::acquire(db_path, !args.no_wait).unwrap_or_else(|err| err.exit());
+            let original = std::fs::read(db_path).unwrap();
+            let db = Db::read(db_path).unwrap_or_else(|err| err.exit());
+
+            let bytes = Compression::detect(&original)
+                .decompress(&original)
+                .unwrap();
+            let version = migrate::version(&Format::detect(&bytes).parse(&bytes).unwrap());
+            if version == migrate::VERSION {
+                println!("{} is already version {}", db_path, version);
+                return;
+            }
+
+            if args.dry_run {
+                println!(
+                    "Would migrate {} from version {} to {}",
+                    db_path,
+                    version,
+                    migrate::VERSION
+                );
+                return;
+            }
 
-            // 2) Override db values with values from the command line
-            override_option!(db, options, stdout_mode);
-            override_option!(db, options, stderr_mode);
-            override_option!(db, options, preserve_env);
-            override_option!(db, options, timeout);
+            // Saving keeps the original as a backup
+            db.save(db_path, None, None)
+                .unwrap_or_else(|err| err.exit());
+            println!(
+                "Migrated {} from version {} to {} (original saved as {}.bak)",
+                db_path,
+                version,
+                migrate::VERSION,
+                db_path
+            );
+            return;
+        }
+        Mode::Doctor { db: db_path, fix } => {
+            let _lock = fix
+                .then(|| DbLock::acquire(db_path, !args.no_wait).unwrap_or_else(|err| err.exit()));
+            let (problems, suite) = doctor::check(db_path);
+            if problems.is_empty() {
+                println!("No problems found in {}", db_path);
+                return;
+            }
 
-            // Env is a vec, so set it only if it's not empty
-            if !options.env.is_empty() {
-                db.options.env = options.env.clone();
+            let fixable = problems.iter().filter(|problem| problem.fixable).count();
+            println!(
+                "{} problem(s) found in {}, {} fixable with --fix:",
+                problems.len(),
+                db_path,
+                fixable
+            );
+            for problem in problems.iter() {
+                let marker = if problem.fixable { " (fixable)" } else { "" };
+                println!("\t{}{}", problem.description, marker);
             }
 
-            db
+            if let (true, Some(mut suite)) = (*fix, suite) {
+                if fixable > 0 && !args.dry_run {
+                    doctor::repair(&mut suite.db);
+                    suite
+                        .db
+                        .save(db_path, None, None)
+                        .unwrap_or_else(|err| err.exit());
+                }
+                println!();
+                println!(
+                    "{} {} problem(s), {} left to fix by hand",
+                    if args.dry_run { "Would fix" } else { "Fixed" },
+                    fixable,
+                    problems.len() - fixable
+                );
+                if problems.len() == fixable {
+                    return;
+                }
+            }
+            std::process::exit(1);
         }
-    };
+        Mode::Import {
+            metadata,
+            db: db_path,
+            expected_glob,
+            map,
+            options,
+        } => {
+            let _lock = DbLock::acquire(db_path, !args.no_wait).unwrap_or_else(|err| err.exit());
+            if Path::new(db_path).exists() {
+                Error::Db {
+                    path: db_path.clone(),
+                    message: "already exists".to_string(),
+                }
+                .exit();
+            }
+
+            let mut suite = TestSuite::new(metadata.clone(), options.clone());
+            suite.db.options.apply_defaults();
+            let errors = suite.validate();
+            if !errors.is_empty() {
+                invalid_configuration(errors).exit();
+            }
+
+            let imported =
+                import::import(&mut suite, expected_glob, map).unwrap_or_else(|err| err.exit());
+            let total = suite.files().unwrap_or_else(|err| err.exit()).len();
+            for (input, golden) in imported.iter() {
+                log::info!("Imported {} for {}", golden.display(), input);
+            }
+
+            if !args.verbose.is_silent() {
+                let verb = if args.dry_run {
+                    "Would import"
+                } else {
+                    "Imported"
+                };
+                println!(
+                    "{} outputs for {} of {} inputs to {}",
+                    verb,
+                    suite.db.results.len(),
+                    total,
+                    db_path
+                );
+                if suite.db.results.len() < total {
+                    println!("Inputs without a golden file will be recorded by the next update");
+                }
+            }
+
+            if !args.dry_run {
+                suite
+                    .db
+                    .save(db_path, args.compress, args.db_format)
+                    .unwrap_or_else(|err| err.exit());
+            }
+            return;
+        }
+        Mode::Bless { db: db_path, files } => {
+            let _lock = DbLock::acquire(db_path, !args.no_wait).unwrap_or_else(|err| err.exit());
+            let mut db = Db::read(db_path).unwrap_or_else(|err| err.exit());
+
+            let files = if files.is_empty() {
+                db.latest.keys().cloned().collect()
+            } else {
+                files.clone()
+            };
+
+            let mut blessed = 0;
+            let mut missing = 0;
+            for file in files.iter() {
+                if !db.latest.contains_key(file) {
+                    eprintln!("{}: No successful output to bless", file);
+                    missing += 1;
+                } else if db.results.get(file) == Some(&vec![db.latest[file].clone()]) {
+                    log::info!("{}: Already accepted", file);
+                } else {
+                    db.bless(file);
+                    blessed += 1;
+                    if !args.verbose.is_silent() {
+                        let verb = if args.dry_run {
+                            "Would bless"
+                        } else {
+                            "Blessed"
+                        };
+                        println!("{} {}", verb, file);
+                    }
+                }
+            }
+
+            if blessed > 0 && !args.dry_run {
+                db.save(db_path, args.compress, args.db_format)
+                    .unwrap_or_else(|err| err.exit());
+            }
+            if missing > 0 {
+                std::process::exit(error::EXIT_ERROR);
+            }
+            return;
+        }
+        Mode::Merge {
+            db: db_path,
+            shards,
+        } => {
+            let _lock = DbLock::acquire(db_path, !args.no_wait).unwrap_or_else(|err| err.exit());
+            let base = Db::read(db_path).unwrap_or_else(|err| err.exit());
+            let mut db = Db::read(db_path).unwrap_or_else(|err| err.exit());
+
+            for shard_path in shards.iter() {
+                let shard = Db::read(shard_path).unwrap_or_else(|err| err.exit());
+                if shard.metadata != base.metadata {
+                    Error::Other(format!(
+                        "{} isn't a shard of {}: its metadata is different",
+                        shard_path, db_path
+                    ))
+                    .exit();
+                }
+                db.merge_shard(&base, shard);
+            }
 
-    // 3) Replace any unset values with their defaults
-    if db.options.stdout_mode.is_none() {
-        db.options.stdout_mode = Some(StreamMode::Both);
+            if !args.verbose.is_silent() {
+                let verb = if args.dry_run {
+                    "Would merge"
+                } else {
+                    "Merged"
+                };
+                println!("{} {} shard(s) into {}", verb, shards.len(), db_path);
+            }
+
+            if !args.dry_run {
+                db.save(db_path, args.compress, args.db_format)
+                    .unwrap_or_else(|err| err.exit());
+            }
+            return;
+        }
+        Mode::Ab {
+            old,
+            new,
+            directory,
+            files,
+            exclude,
+            options,
+        } => {
+            let metadata = Metadata {
+                command: TestCommand::Shell(String::new()),
+                directory: directory.clone(),
+                files: files.clone(),
+                exclude: exclude.clone(),
+            };
+            run_ab(&args, old, new, metadata, options);
+        }
+        Mode::Pull { db, remote, force } => {
+            let _lock = DbLock::acquire(db, !args.no_wait).unwrap_or_else(|err| err.exit());
+            remote::Remote::parse(remote, db)
+                .and_then(|remote| remote.pull(db, *force, args.dry_run))
+                .unwrap_or_else(|err| err.exit());
+            return;
+        }
+        Mode::Push { db, remote, force } => {
+            let _lock = DbLock::acquire(db, !args.no_wait).unwrap_or_else(|err| err.exit());
+            remote::Remote::parse(remote, db)
+                .and_then(|remote| remote.push(db, *force, args.dry_run))
+                .unwrap_or_else(|err| err.exit());
+            return;
+        }
+        _ => {}
     }
-    if db.options.stderr_mode.is_none() {
-        db.options.stderr_mode = Some(StreamMode::Print);
+
+    // Every db this run will use, locked until it's done so concurrent runs can't clobber each other
+    let db_paths = match &args.mode {
+        Mode::Update { db, .. } => db::suite_paths(db).unwrap_or_else(|err| err.exit()),
+        _ => db_path(&args.mode).map(String::from).into_iter().collect(),
+    };
+    let _locks = if args.print_config || args.plan {
+        Vec::new()
+    } else {
+        lock::acquire_all(&db_paths, !args.no_wait).unwrap_or_else(|err| err.exit())
+    };
+
+    // Load each suite to run
+    let mut suites = match &args.mode {
+        Mode::Run { metadata, options }
+        | Mode::Record {
+            metadata, options, ..
+        } => {
+            // Recording over an existing db replaces it, unless someone else changes it first
+            let path = db_path(&args.mode).map(String::from);
+            let mut suite = TestSuite::new(metadata.clone(), options.clone());
+            suite.loaded_hash = path
+                .as_deref()
+                .map(Path::new)
+                .filter(|path| path.exists())
+                .map(|path| hash::hash_file(path).unwrap_or_else(|err| err.exit()));
+            vec![(path, suite, None)]
+        }
+        Mode::Update {
+            options,
+            no_save_options,
+            profile,
+            save_profile,
+            ..
+        } => db_paths
+            .into_iter()
+            .map(|path| {
+                let mut suite = TestSuite::load(&path).unwrap_or_else(|err| err.exit());
+                let saved_options = override_options(
+                    &args,
+                    &path,
+                    &mut suite.db,
+                    options,
+                    *no_save_options,
+                    profile.as_deref(),
+                    save_profile.as_deref(),
+                );
+                (Some(path), suite, saved_options)
+            })
+            .collect(),
+        Mode::Ab { .. }
+        | Mode::Import { .. }
+        | Mode::Bless { .. }
+        | Mode::Merge { .. }
+        | Mode::Export { .. }
+        | Mode::Stats { .. }
+        | Mode::History { .. }
+        | Mode::Compare { .. }
+        | Mode::Convert { .. }
+        | Mode::Migrate { .. }
+        | Mode::Doctor { .. }
+        | Mode::Pull { .. }
+        | Mode::Push { .. } => {
+            unreachable!()
+        }
+    };
+
+    // Replace any unset values with their defaults, then check everything before running anything
+    for (_, suite, _) in suites.iter_mut() {
+        suite.db.options.apply_defaults();
     }
-    if db.options.preserve_env.is_none() {
-        db.options.preserve_env = Some(false);
+    validate(&args, &suites);
+
+    if args.print_config {
+        let config = suites
+            .iter()
+            .map(|(path, suite, _)| {
+                serde_json::json!({
+                    "db": path,
+                    "metadata": suite.db.metadata,
+                    "options": suite.db.options,
+                })
+            })
+            .collect::<Vec<_>>();
+        println!("{}", serde_json::to_string_pretty(&config).unwrap());
+        return;
     }
-    if db.options.timeout.is_none() {
-        db.options.timeout = Some(10);
+
+    // The list of files to run (if given) is read once and shared by every suite
+    let files_from = args.files_from.as_deref().map(read_file_list);
+
+    if args.plan {
+        for (path, suite, _) in suites {
+            let files = select_files(&args, &suite, files_from.as_deref());
+            print_plan(&args, path.as_deref(), suite, &files);
+        }
+        return;
     }
 
-    // Debug print options
-    log::debug!("Options:\n{:#?}\n{:#?}", db.metadata, db.options);
-
-    // Build the absolute glob pattern
-    // This is based on the working directory (or cwd) from the args + the files pattern
-    let pattern = format!(
-        "{}/{}",
-        db.metadata
-            .directory
-            .clone()
-            .unwrap_or_else(|| ".".to_string()),
-        db.metadata.files
-    );
+    let events = EventStream::open(args.events.as_deref()).unwrap_or_else(|err| err.exit());
+
+    // Clean up artifacts from old runs before starting this one
+    let artifacts = args.artifacts.as_deref().map(|root| {
+        artifacts::cleanup(
+            root,
+            args.artifact_retention.map(Into::into),
+            args.artifact_max_size,
+        );
+        Artifacts::start(root).unwrap_or_else(|err| err.exit())
+    });
+
+    // The time budget covers every suite, starting from when testit started
+    let deadline = args
+        .max_total_time
+        .map(|max_total_time| start + std::time::Duration::from(max_total_time));
+
+    // Set by the first failure with --fail-fast, so later suites are skipped too
+    let failed_fast = AtomicBool::new(false);
 
-    // Glob the list of all files that we want to test
-    let files = glob::glob(&pattern)
-        .unwrap()
-        .map(|x| x.unwrap())
-        .collect::<Vec<path::PathBuf>>();
-
-    // Parse environment variables
-    // There should be exactly one =
-    let env: BTreeMap<String, String> = db
-        .options
-        .env
+    // Everything the run will save, to commit afterwards (see --git-commit)
+    let saved_paths = suites
         .iter()
-        .map(|x| {
-            assert!(x.matches('=').count() == 1);
-            let mut split = x.split("=");
-            (
-                split.next().unwrap().to_string(),
-                split.next().unwrap().to_string(),
+        .filter_map(|(path, suite, saved_options)| {
+            let path = path.as_deref()?;
+            let options = saved_options.as_ref().unwrap_or(&suite.db.options);
+            let snapshots = options
+                .snapshots
+                .as_ref()
+                .map(|snapshots| snapshots::directory(path, snapshots));
+            let db_dir = options
+                .db_dir
+                .as_ref()
+                .map(|directory| db_dir::directory(path, directory));
+            Some(
+                std::iter::once(PathBuf::from(path))
+                    .chain(snapshots)
+                    .chain(db_dir),
             )
         })
-        .collect();
+        .flatten()
+        .collect::<Vec<_>>();
 
-    // Progress adaptor
-    let it = ProgressAdaptor::new(&files);
-    let progress = it.items_processed();
-    let total = files.len();
-    let start = std::time::Instant::now();
+    let mut summary = report::Summary::default();
+    let mut report_entries = Vec::new();
+
+    // Run each suite, collecting results for a unified summary
+    // A suite that can't be run or saved doesn't stop the others; the errors are reported at the end
+    let mut suite_errors = Vec::new();
+    let suite_count = suites.len();
+    for (path, suite, saved_options) in suites {
+        if suite_count > 1 && !args.verbose.is_silent() {
+            println!("=== {} ===", path.as_deref().unwrap_or_default());
+        }
+
+        let (suite_summary, suite_entries, finished) = run_suite(
+            &args,
+            path.as_deref(),
+            suite,
+            saved_options,
+            files_from.as_deref(),
+            deadline,
+            args.fail_fast.then_some(&failed_fast),
+            &events,
+            artifacts.as_ref(),
+        );
+
+        if suite_count > 1 {
+            print_summary(&args, &suite_summary);
+            if !args.verbose.is_silent() {
+                println!();
+            }
+        }
+
+        summary.add(&suite_summary);
+        report_entries.extend(suite_entries);
+        if let Err(err) = finished {
+            suite_errors.push(err);
+        }
+    }
+
+    events.emit(Event::Summary {
+        successes: summary.successes,
+        new_successes: summary.new_successes,
+        failures: summary.failures,
+        timeouts: summary.timeouts,
+        errors: summary.errors,
+        skipped: summary.skipped,
+        unchanged: summary.unchanged,
+        unsaved: summary.unsaved,
+        incidents: summary.incidents,
+        not_run: summary.not_run,
+    });
+
+    report::write(&args.report, &report_entries, &summary).unwrap_or_else(|err| err.exit());
+    if let Some(path) = &args.summary_json {
+        report::summary_json(path, &report_entries, &summary, args.sort)
+            .unwrap_or_else(|err| err.exit());
+    }
+    if let Some(url) = &args.notify_webhook {
+        notify::webhook(url, &report_entries, &summary, start.elapsed());
+    }
 
-    // Additional thread that displays progress over time
-    std::thread::spawn(move || {
-        let mut last_progress = 0;
-        let mut last_print = std::time::Instant::now();
-        let mut delay = 1000;
+    // Output a summary
+    if suite_count > 1 && !args.verbose.is_silent() {
+        println!("=== Total ({} suites) ===", suite_count);
+    }
+    print_summary(&args, &summary);
+    print_failures(&args, &report_entries);
+    if let Some(count) = args.show_slowest {
+        print_slowest(&args, &report_entries, count);
+    }
+    print_usage_regressions(&args, &report_entries);
+    print_owners(&args, &report_entries);
 
-        loop {
-            std::thread::sleep(Duration::from_millis(1000));
+    // Nothing is committed if any suite couldn't be run or saved
+    if !suite_errors.is_empty() {
+        for error in &suite_errors {
+            eprintln!("{}", error);
+        }
+        std::process::exit(error::EXIT_ERROR);
+    }
 
-            let new_progress = progress.get();
-            let time_spent = start.elapsed().as_secs();
+    if let Some(message) = &args.git_commit {
+        commit(&args, &summary, &saved_paths, message);
+    }
 
-            if new_progress != last_progress {
-                
-                // Made progress, reset delay
-                log::debug!("Progress: {}/{} files, {}/{} sec (max)", new_progress, total, time_spent, db.options.timeout.unwrap());
-                last_print = std::time::Instant::now();
-                delay = 1000;
-                last_progress = new_progress;
-                
-            } else if last_print.elapsed().as_millis() > delay {
-                
-                // Met delay, print and increment delay
-                log::debug!("Progress: {}/{} files, {}/{} sec (max)", new_progress, total, time_spent, db.options.timeout.unwrap());
-                last_print = std::time::Instant::now();
-                delay = 30000.min(delay * 2);
+    // Exit a success unless the failure policy (--max-failures, --allow-timeouts, --fail-on-new) says otherwise
+    // Running out of time and a dry run that would have changed a db get their own statuses, so CI can tell them apart from failures
+    if failed(&args, &summary) {
+        std::process::exit(1);
+    } else if summary.not_run > 0 {
+        std::process::exit(EXIT_OUT_OF_TIME);
+    } else if summary.unsaved > 0 {
+        std::process::exit(EXIT_UNSAVED_CHANGES);
+    } else {
+        std::process::exit(0);
+    }
+}
 
+// Commit the saved db files, unless the run failed or didn't save anything
+fn commit(args: &Args, summary: &report::Summary, paths: &[PathBuf], message: &str) {
+    if args.dry_run || paths.is_empty() {
+        return;
+    }
+    if failed(args, summary) {
+        eprintln!("Not committing the database: the run failed");
+        return;
+    }
+
+    // Snapshot directories only exist once something has been saved to them
+    let paths = paths
+        .iter()
+        .filter(|path| path.exists())
+        .cloned()
+        .collect::<Vec<_>>();
+    match git::commit(&paths, message) {
+        Ok(true) => {
+            if !args.verbose.is_silent() {
+                println!("Committed {}: {}", list_paths(&paths), message);
+            }
+        }
+        Ok(false) => {
+            if !args.verbose.is_silent() {
+                println!("Nothing to commit: {} unchanged", list_paths(&paths));
             }
         }
+        Err(err) => {
+            Error::Other(format!("Unable to commit {}: {}", list_paths(&paths), err)).exit()
+        }
+    }
+}
+
+// Paths for a message, like: a.json, snapshots
+fn list_paths(paths: &[PathBuf]) -> String {
+    paths
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// If a run should exit with an error, by default because of any failures or timeouts (and always for tests that couldn't run)
+fn failed(args: &Args, summary: &report::Summary) -> bool {
+    summary.errors > 0
+        || summary.failures > args.max_failures.unwrap_or(0)
+        || (summary.timeouts > 0 && !args.allow_timeouts)
+        || (summary.new_successes > 0 && args.fail_on_new)
+}
+
+// Apply options from the command line over those saved in the db
+// Returns the original options if they should be saved instead (see --no-save-options)
+fn override_options(
+    args: &Args,
+    path: &str,
+    db: &mut Db,
+    options: &Options,
+    no_save_options: bool,
+    profile: Option<&str>,
+    save_profile: Option<&str>,
+) -> Option<Options> {
+    let loaded_options = db.options.clone();
+
+    // With --save-profile, the options given are saved as that profile rather than over the db's
+    if let Some(name) = save_profile {
+        db.profiles.insert(name.to_string(), options.clone());
+    }
+    let profile = profile.or(save_profile);
+    let profile_options = profile.map(|name| match db.profiles.get(name) {
+        Some(profile_options) => profile_options.clone(),
+        None => Error::Config(format!(
+            "Profile {} is not defined in {} (defined: {})",
+            name,
+            path,
+            db.profiles.keys().cloned().collect::<Vec<_>>().join(", ")
+        ))
+        .exit(),
     });
 
-    // For each file, run the command and compare the output
-    let results = it
-        .map(|file| {
-            log::info!("Testing {}", file.display());
-            let start = std::time::Instant::now();
-
-            let command = db.metadata.command.clone();
-            let cwd = db.metadata.directory.clone();
-            let stdin = std::fs::File::open(&file).unwrap();
-            let timeout = Duration::from_secs(db.options.timeout.unwrap());
-
-            // Create the child process
-            let mut command_builder = Command::new("bash");
-            command_builder
-                .arg("-c")
-                .arg(command)
-                .current_dir(&cwd.unwrap_or_else(|| ".".to_string()))
-                .stdin(stdin)
-                .stderr(std::process::Stdio::piped()) // TODO: Do we want to capture this?
-                .stdout(std::process::Stdio::piped());
-
-            // Add environment variables
-            if !db.options.preserve_env.unwrap() {
-                command_builder.env_clear();
-            }
-            for (key, value) in env.iter() {
-                command_builder.env(key, value);
-            }
-
-            // Start the child
-            let mut child = command_builder.spawn().expect("Failed to execute command");
-
-            // Wait for the child to finish up to timeout
-            // If timeout is reached, kill the thread (or it may outlast us...)
-            match child.wait_timeout(timeout) {
-                Ok(Some(status)) => {
-                    let mut output = String::new();
-                    child
-                        .stdout
-                        .as_mut()
-                        .unwrap()
-                        .read_to_string(&mut output)
-                        .unwrap();
-
-                    let mut error = String::new();
-                    child
-                        .stderr
-                        .as_mut()
-                        .unwrap()
-                        .read_to_string(&mut error)
-                        .unwrap();
-
-                    if status.success() {
-                        let elapsed = start.elapsed().as_millis();
-                        log::info!("Success after {}ms: {}", elapsed, file.display());
-                        TestResult::Success(output, error, elapsed)
-                    } else {
-                        log::info!("Failure {}", file.display());
-                        TestResult::Failure(output, error)
-                    }
-                }
-                Ok(None) => {
-                    // Timeout passed without exit
-                    log::info!("Timeout {}", file.display());
-                    child.kill().unwrap();
-                    TestResult::Timeout
-                }
-                Err(_) => {
-                    // Process errored out
-                    child.kill().unwrap();
-                    unimplemented!("Process errored out")
-                }
+    // The options to save: the db's, overridden by the command line (unless it's a profile)
+    let mut saved_options = loaded_options.clone();
+    if save_profile.is_none() {
+        apply_options(&mut saved_options, options);
+    }
+
+    // For this run: the db's options, then the profile's (if any), then the command line's
+    if let Some(profile_options) = &profile_options {
+        apply_options(&mut db.options, profile_options);
+    }
+    apply_options(&mut db.options, options);
+
+    // Make it obvious when this run changes the saved options
+    // (unless only printing the config, which should be nothing but JSON)
+    if !args.verbose.is_silent() && !args.print_config {
+        let changed = saved_options.changes_from(&loaded_options);
+        if !changed.is_empty() {
+            if no_save_options || args.plan {
+                println!("Options overridden for this run of {} (not saved):", path);
+            } else {
+                println!("Options changed by this run of {} (will be saved):", path);
             }
-        })
+            print_changes(&changed);
+        }
+
+        if let Some(name) = profile {
+            let changed = db.options.changes_from(&saved_options);
+            println!(
+                "Options from profile {} for this run of {} (not saved):",
+                name, path
+            );
+            print_changes(&changed);
+        }
+    }
+
+    if no_save_options {
+        Some(loaded_options)
+    } else if profile.is_some() {
+        Some(saved_options)
+    } else {
+        None
+    }
+}
+
+// Print each option that changed, from changes_from
+fn print_changes(changed: &[(String, String, String)]) {
+    for (name, old, new) in changed.iter() {
+        println!("\t{}: {} -> {}", name, old, new);
+    }
+    println!();
+}
+
+// Set every option that's set on the command line (or in a profile)
+fn apply_options(target: &mut Options, options: &Options) {
+    macro_rules! override_option {
+        ($target:expr, $args:expr, $field:ident) => {
+            if let Some(value) = &$args.$field {
+                $target.$field = Some(value.clone());
+            }
+        };
+    }
+
+    override_option!(target, options, stdout_mode);
+    override_option!(target, options, stderr_mode);
+    override_option!(target, options, preserve_env);
+    override_option!(target, options, timeout);
+    override_option!(target, options, cpu_limit);
+    override_option!(target, options, warn_timeout);
+    override_option!(target, options, max_saved_bytes);
+    override_option!(target, options, history_length);
+    override_option!(target, options, sandbox);
+    override_option!(target, options, input_mode);
+    override_option!(target, options, preprocess);
+    override_option!(target, options, postprocess);
+    override_option!(target, options, output_encoding);
+    override_option!(target, options, env_file);
+    override_option!(target, options, fixtures);
+    override_option!(target, options, fixture_dir);
+    override_option!(target, options, comparator);
+    override_option!(target, options, snapshots);
+    override_option!(target, options, db_dir);
+    override_option!(target, options, results_as_lines);
+    override_option!(target, options, normalize_eol);
+    override_option!(target, options, strip_ansi);
+    override_option!(target, options, version_command);
+    override_option!(target, options, setup);
+    override_option!(target, options, teardown);
+    override_option!(target, options, before_each);
+    override_option!(target, options, after_each);
+
+    // Env is a vec, so set it only if it's not empty
+    if !options.env.is_empty() {
+        target.env = options.env.clone();
+    }
+    if !options.skip.is_empty() {
+        target.skip = options.skip.clone();
+    }
+    if !options.unordered.is_empty() {
+        target.unordered = options.unordered.clone();
+    }
+    if !options.normalize.is_empty() {
+        target.normalize = options.normalize.clone();
+    }
+    if !options.only_lines.is_empty() {
+        target.only_lines = options.only_lines.clone();
+    }
+    if !options.ignore_lines.is_empty() {
+        target.ignore_lines = options.ignore_lines.clone();
+    }
+    if !options.redact_env.is_empty() {
+        target.redact_env = options.redact_env.clone();
+    }
+    if !options.redact_pattern.is_empty() {
+        target.redact_pattern = options.redact_pattern.clone();
+    }
+    if !options.stdout_must_match.is_empty() {
+        target.stdout_must_match = options.stdout_must_match.clone();
+    }
+    if !options.stdout_must_not_match.is_empty() {
+        target.stdout_must_not_match = options.stdout_must_not_match.clone();
+    }
+    if !options.stderr_must_match.is_empty() {
+        target.stderr_must_match = options.stderr_must_match.clone();
+    }
+    if !options.stderr_must_not_match.is_empty() {
+        target.stderr_must_not_match = options.stderr_must_not_match.clone();
+    }
+    if !options.owners.is_empty() {
+        target.owners = options.owners.clone();
+    }
+    if !options.serial.is_empty() {
+        target.serial = options.serial.clone();
+    }
+    if !options.expect_timeout.is_empty() {
+        target.expect_timeout = options.expect_timeout.clone();
+    }
+    if !options.expect_failure.is_empty() {
+        target.expect_failure = options.expect_failure.clone();
+    }
+    if !options.resources.is_empty() {
+        target.resources = options.resources.clone();
+    }
+    if !options.resource_limits.is_empty() {
+        target.resource_limits = options.resource_limits.clone();
+    }
+    if !options.matrix.is_empty() {
+        target.matrix = options.matrix.clone();
+    }
+}
+
+// Read a newline separated list of files, - for stdin
+fn read_file_list(path: &str) -> Vec<String> {
+    let contents = if path == "-" {
+        std::io::read_to_string(std::io::stdin())
+    } else {
+        std::fs::read_to_string(path)
+    };
+
+    match contents {
+        Ok(contents) => contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(String::from)
+            .collect(),
+        Err(err) => Error::io("read file list", path, err).exit(),
+    }
+}
+
+// Check the arguments and each suite's configuration, exiting with every problem found
+fn validate(args: &Args, suites: &[(Option<String>, TestSuite, Option<Options>)]) {
+    let mut errors = Vec::new();
+
+    if args.artifacts.is_none()
+        && (args.artifact_retention.is_some() || args.artifact_max_size.is_some())
+    {
+        errors.push("--artifact-retention and --artifact-max-size require --artifacts".to_string());
+    }
+
+    if args.manifest.is_some() && !args.reproducible {
+        errors.push("--manifest requires --reproducible".to_string());
+    }
+    if args.manifest.is_some() && suites.len() > 1 {
+        errors.push("--manifest can only be used with a single suite".to_string());
+    }
+    if args.stream && args.events.as_deref() == Some("-") {
+        errors.push("--stream can't be used with --events - (both write to stdout)".to_string());
+    }
+    if args.events.as_deref() == Some("-") && !args.verbose.is_silent() {
+        errors.push(
+            "--events - requires -q, since the rest of the output also goes to stdout".to_string(),
+        );
+    }
+    if args.reproducible && args.incremental {
+        errors.push(
+            "--reproducible runs every test, so can't be used with --incremental".to_string(),
+        );
+    }
+
+    for (path, suite, _) in suites {
+        let name = path.as_deref().unwrap_or("command line");
+
+        for error in suite.validate() {
+            errors.push(format!("{}: {}", name, error));
+        }
+
+        if args.update_fixtures && suite.db.options.fixtures.is_none() {
+            errors.push(format!("{}: --update-fixtures requires --fixtures", name));
+        }
+    }
+
+    if !errors.is_empty() {
+        invalid_configuration(errors).exit();
+    }
+}
+
+// A single error listing every problem found while validating
+fn invalid_configuration(errors: Vec<String>) -> Error {
+    let mut message = "Invalid configuration:".to_string();
+    for error in errors {
+        message.push_str(&format!("\n\t{}", error));
+    }
+    Error::Config(message)
+}
+
+// Print the summary of a run, as a table of each status with its count and total time
+fn print_summary(args: &Args, summary: &report::Summary) {
+    if !args.verbose.is_silent() {
+        // Only color counts that need attention (padding first, so colors don't break the alignment)
+        let count = |color, count: usize| {
+            let padded = format!("{:>6}", count);
+            if count > 0 {
+                paint(args, color, &padded)
+            } else {
+                padded
+            }
+        };
+        let time = |ms: u128| format!("{:>10}", format!("{}ms", ms));
+
+        println!("\nSummary:");
+        println!("\t{:<12} {:>6} {:>10}", "Status", "Count", "Time");
+        println!(
+            "\t{:<12} {} {}  ({} new)",
+            "Successes",
+            count(Color::Green, summary.successes),
+            time(summary.success_ms),
+            summary.new_successes
+        );
+        println!(
+            "\t{:<12} {} {}",
+            "Failures",
+            count(Color::Red, summary.failures),
+            time(summary.failure_ms)
+        );
+        println!(
+            "\t{:<12} {} {}",
+            "Timeouts",
+            count(Color::Yellow, summary.timeouts),
+            time(summary.timeout_ms)
+        );
+        if summary.errors > 0 {
+            println!(
+                "\t{:<12} {}  (couldn't run)",
+                "Errors",
+                count(Color::Red, summary.errors)
+            );
+        }
+        println!("\t{:<12} {:>6}", "Skipped", summary.skipped);
+        println!("\t{:<12} {:>6}", "Unchanged", summary.unchanged);
+        if summary.not_run > 0 {
+            println!(
+                "\t{:<12} {}  (--max-total-time)",
+                "Not run",
+                count(Color::Yellow, summary.not_run)
+            );
+        }
+
+        if summary.incidents > 0 {
+            println!("\tIncidents: {}", summary.incidents);
+        }
+        if summary.encoding_notes > 0 {
+            println!(
+                "\tEncoding notes (output not valid UTF-8): {}",
+                summary.encoding_notes
+            );
+        }
+        if summary.orphaned > 0 || summary.unrecorded > 0 {
+            println!(
+                "\tOrphaned: {} (accepted outputs for files that are gone), unrecorded: {} (files without accepted outputs)",
+                paint(args, Color::Yellow, &summary.orphaned.to_string()),
+                summary.unrecorded
+            );
+        }
+        if summary.slow > 0 {
+            println!(
+                "\tSlow (--warn-timeout): {}",
+                paint(args, Color::Yellow, &summary.slow.to_string())
+            );
+        }
+        if summary.usage_regressions > 0 {
+            println!(
+                "\tResource regressions: {}",
+                paint(args, Color::Yellow, &summary.usage_regressions.to_string())
+            );
+        }
+        if summary.unsaved > 0 {
+            println!(
+                "\tUnsaved (--dry-run): {} suite(s) would have changed",
+                summary.unsaved
+            );
+        }
+    }
+}
+
+// Print a compact list of the failures and timeouts (ordered by --sort) with how long each took
+fn print_failures(args: &Args, entries: &[report::Entry]) {
+    if args.verbose.is_silent() {
+        return;
+    }
+
+    let failed = report::failing(entries, args.sort);
+    if failed.is_empty() {
+        return;
+    }
+
+    println!("\nFailures and timeouts:");
+    for entry in failed {
+        let color = if entry.status == "timeout" {
+            Color::Yellow
+        } else {
+            Color::Red
+        };
+        let elapsed = entry
+            .elapsed_ms
+            .map_or(String::new(), |elapsed_ms| format!("{}ms", elapsed_ms));
+        println!(
+            "\t{} {:>10}  {}",
+            paint(args, color, &format!("{:<9}", entry.status)),
+            elapsed,
+            entry.file
+        );
+    }
+}
+
+// Print the slowest tests in the run, and how much slower they were than their fastest recorded success
+fn print_slowest(args: &Args, entries: &[report::Entry], count: usize) {
+    if args.verbose.is_silent() {
+        return;
+    }
+
+    let mut slowest = entries
+        .iter()
+        .filter_map(|entry| Some((entry, entry.elapsed_ms?)))
         .collect::<Vec<_>>();
+    if slowest.is_empty() {
+        return;
+    }
+    slowest.sort_by(|(a, a_ms), (b, b_ms)| b_ms.cmp(a_ms).then_with(|| a.file.cmp(&b.file)));
+
+    println!("\nSlowest tests:");
+    for (entry, elapsed_ms) in slowest.into_iter().take(count) {
+        let compared = match entry.fastest_ms {
+            Some(fastest_ms) => format!(
+                "fastest {}ms, {:+.0}%",
+                fastest_ms,
+                (elapsed_ms as f64 - fastest_ms as f64) / (fastest_ms.max(1) as f64) * 100.0
+            ),
+            None => "no recorded time".to_string(),
+        };
+        println!(
+            "\t{:>8}ms  {} ({}, {})",
+            elapsed_ms, entry.file, entry.status, compared
+        );
+    }
+}
 
-    let mut success_count = 0;
-    let mut new_success_count = 0;
-    let mut failure_count = 0;
-    let mut timeout_count = 0;
-
-    // Write results
-    // This will only print failures, timeouts, and new successes
-    // If the output file is set and we see the same success again, it will be ignored
-    for (file, result) in files.iter().zip(results.iter()) {
-        // Remove the directory prefix if it exists
-        // This will apply to the printed output + the output file
-        let file = if let Some(prefix) = db.metadata.directory.clone() {
-            file.strip_prefix(prefix).unwrap()
+// Print the tests that used far more resources than in their previous success
+fn print_usage_regressions(args: &Args, entries: &[report::Entry]) {
+    if args.verbose.is_silent()
+        || entries
+            .iter()
+            .all(|entry| entry.usage_regressions.is_empty())
+    {
+        return;
+    }
+
+    println!("\nResource regressions:");
+    for entry in entries {
+        for regression in entry.usage_regressions.iter() {
+            println!("\t{}: {}", entry.file, regression);
+        }
+    }
+}
+
+// Print failures and timeouts grouped by owner, if any tests have owners
+fn print_owners(args: &Args, entries: &[report::Entry]) {
+    if args.verbose.is_silent() || entries.iter().all(|entry| entry.owner.is_none()) {
+        return;
+    }
+
+    let mut by_owner: BTreeMap<&str, Vec<&report::Entry>> = BTreeMap::new();
+    for entry in entries {
+        if entry.failed() {
+            by_owner
+                .entry(entry.owner.as_deref().unwrap_or("(no owner)"))
+                .or_default()
+                .push(entry);
+        }
+    }
+
+    if by_owner.is_empty() {
+        return;
+    }
+
+    println!("\nFailures by owner:");
+    for (owner, entries) in by_owner {
+        println!("\t{}: {}", owner, entries.len());
+        for entry in entries {
+            println!("\t\t{} ({})", entry.file, entry.status);
+        }
+    }
+}
+
+// Run an old and new command against the same files (one after the other), then report the differences
+// Exits with an error if any outputs differ
+fn run_ab(args: &Args, old: &str, new: &str, metadata: Metadata, options: &Options) -> ! {
+    let suite = |command: &str| {
+        let mut suite = TestSuite::new(
+            Metadata {
+                command: TestCommand::Shell(command.to_string()),
+                ..metadata.clone()
+            },
+            options.clone(),
+        );
+        suite.db.options.apply_defaults();
+        suite
+    };
+
+    // Both suites have the same configuration, so check it once
+    let suites = vec![(None, suite(old), None)];
+    validate(args, &suites);
+    let (_, old_suite, _) = suites.into_iter().next().unwrap();
+    let new_suite = suite(new);
+
+    let files = old_suite.files().unwrap_or_else(|err| err.exit());
+    let run = |suite: &TestSuite| {
+        Runner::new(suite)
+            .unwrap_or_else(|err| err.exit())
+            .reproducible(args.reproducible)
+            .stream(args.stream)
+            .run(&files)
+            .unwrap_or_else(|err| err.exit())
+    };
+    log::info!("Running the old command on {} files", files.len());
+    let old_outcomes = run(&old_suite);
+    log::info!("Running the new command on {} files", files.len());
+    let new_outcomes = run(&new_suite);
+
+    if ab::print(&new_suite, &files, &old_outcomes, &new_outcomes).unwrap_or_else(|err| err.exit())
+    {
+        std::process::exit(1);
+    }
+    std::process::exit(0);
+}
+
+// The files to run in a suite: its files (or those listed), limited to the owner and shard (if any)
+fn select_files(args: &Args, suite: &TestSuite, files_from: Option<&[String]>) -> Vec<PathBuf> {
+    let mut files = match files_from {
+        Some(list) => suite.files_from(list),
+        None => suite.files().unwrap_or_else(|err| err.exit()),
+    };
+
+    // Only run tests belonging to the given owner
+    if let Some(owner) = &args.owner {
+        let owner = if owner == "me" {
+            std::env::var("USER").unwrap_or_default()
         } else {
-            file
+            owner.clone()
         };
+        files.retain(|file| suite.owner(file).as_ref() == Some(&owner));
+    }
 
-        match result {
-            TestResult::Success(output, error, elapsed_ms) => {
-                success_count += 1;
+    // Only rerun the tests that didn't match last time (with any variant)
+    if args.failed_only {
+        let variants = suite.db.options.variants();
+        files.retain(|file| {
+            variants.iter().any(|variant| {
+                matches!(
+                    suite
+                        .db
+                        .status
+                        .get(&suite.variant_key(file, variant.as_ref())),
+                    Some(Status::Failure | Status::Timeout | Status::Error | Status::New)
+                )
+            })
+        });
+    }
 
-                // TODO: This is ugly, fix it with a function or something
+    // Only run the files that have never been run (with some variant), not even failing
+    if args.new_only {
+        let variants = suite.db.options.variants();
+        files.retain(|file| {
+            variants.iter().any(|variant| {
+                let key = suite.variant_key(file, variant.as_ref());
+                !suite.db.results.contains_key(&key) && !suite.db.status.contains_key(&key)
+            })
+        });
+    }
 
-                let mut to_print = String::new();
-                match db.options.stdout_mode {
-                    Some(StreamMode::Print) | Some(StreamMode::Both) => {
-                        to_print.push_str(&output);
-                    }
-                    _ => {}
-                }
-                match db.options.stderr_mode {
-                    Some(StreamMode::Print) | Some(StreamMode::Both) => {
-                        to_print.push_str(&error);
-                    }
-                    _ => {}
-                }
+    // Only run this shard's part of the suite
+    if let Some(shard) = &args.shard {
+        let total = files.len();
+        files = shard.select(suite, &files);
+        log::info!(
+            "Running {} of {} files in shard {}",
+            files.len(),
+            total,
+            shard
+        );
+    }
 
-                let mut to_save = String::new();
-                match db.options.stdout_mode {
-                    Some(StreamMode::Save) | Some(StreamMode::Both) => {
-                        to_save.push_str(&output);
-                    }
-                    _ => {}
-                }
-                match db.options.stderr_mode {
-                    Some(StreamMode::Save) | Some(StreamMode::Both) => {
-                        to_save.push_str(&error);
-                    }
-                    _ => {}
-                }
-            
-                // Update timing data, even if we have a previous success
-                let timing_data = db.timing
-                    .entry(file.to_str().unwrap().to_string())
-                    .or_insert(TimingData {
-                        fastest: *elapsed_ms,
-                        most_recent: *elapsed_ms,
-                    });
-
-                if timing_data.most_recent > *elapsed_ms * 2 {
-                    log::warn!("{}: Slow test (2x previous): {}ms", file.display(), elapsed_ms);
-                }
-                timing_data.most_recent = *elapsed_ms;
+    files
+}
 
-                if *elapsed_ms < timing_data.fastest {
-                    timing_data.fastest = *elapsed_ms;
-                    log::info!("New fastest time for {}: {}ms", file.display(), elapsed_ms);
-                }
+// Print what running a suite would do, without running anything (see --plan)
+fn print_plan(args: &Args, path: Option<&str>, mut suite: TestSuite, files: &[PathBuf]) {
+    let db = &suite.db;
+    println!("Plan for {}:", path.unwrap_or("command line"));
+    println!(
+        "\tDirectory: {}",
+        db.metadata.directory.as_deref().unwrap_or(".")
+    );
+    let mut limits = Vec::new();
+    if let Some(cpu_limit) = db.options.cpu_limit {
+        limits.push(format!("CPU limit: {}s", cpu_limit));
+    }
+    if let Some(warn_timeout) = db.options.warn_timeout {
+        limits.push(format!("slow after: {}s", warn_timeout));
+    }
+    if limits.is_empty() {
+        println!("\tTimeout: {}s", db.options.timeout.unwrap());
+    } else {
+        println!(
+            "\tTimeout: {}s ({})",
+            db.options.timeout.unwrap(),
+            limits.join(", ")
+        );
+    }
+    for (name, hook) in [
+        ("Preprocess", &db.options.preprocess),
+        ("Postprocess", &db.options.postprocess),
+        ("Setup", &db.options.setup),
+        ("Teardown", &db.options.teardown),
+        ("Before each", &db.options.before_each),
+        ("After each", &db.options.after_each),
+    ] {
+        if let Some(hook) = hook {
+            println!("\t{}: {}", name, hook);
+        }
+    }
 
-                // Don't update results if we've already seen it
-                if let Some(previous) = db.results.get(file.to_str().unwrap()) {
-                    if previous.contains(&to_save) {
-                        // We have a previously logged success, do nothing
-                        continue;
-                    }
-                }
-                new_success_count += 1;
+    for variant in db.options.variants() {
+        suite.variant = variant;
+        let runner = Runner::new(&suite)
+            .unwrap_or_else(|err| err.exit())
+            .incremental(args.incremental)
+            .reproducible(args.reproducible);
 
-                // We have successful output we haven't seen before, log it and potentially save it
-                if !args.verbose.is_silent() {
-                    println!("{}: New success:\n{}\n===\n", file.display(), to_print);
-                }
+        match &suite.variant {
+            Some(variant) => println!("\tFiles ({}, variant {}):", files.len(), variant.name),
+            None => println!("\tFiles ({}):", files.len()),
+        }
+        for (file, plan) in files.iter().zip(runner.plan(files)) {
+            println!("\t\t{}: {}", suite.key(file), plan);
+        }
+    }
+}
+
+// Run all tests in a single suite, saving the results to db_path (if set)
+// This fails (without saving) if the setup hook fails, or if the db changed during the run and --on-conflict is abort
+// No new tests are started after the deadline (if any), or once failed_fast is set (if given)
+#[allow(clippy::too_many_arguments)]
+fn run_suite(
+    args: &Args,
+    db_path: Option<&str>,
+    mut suite: TestSuite,
+    saved_options: Option<Options>,
+    files_from: Option<&[String]>,
+    deadline: Option<std::time::Instant>,
+    failed_fast: Option<&AtomicBool>,
+    events: &EventStream,
+    artifacts: Option<&Artifacts>,
+) -> (report::Summary, Vec<report::Entry>, error::Result<()>) {
+    let accept_all = matches!(
+        args.mode,
+        Mode::Update {
+            accept_all: true,
+            ..
+        }
+    );
+
+    let started = std::time::Instant::now();
+
+    // Debug print options
+    log::debug!(
+        "Options:\n{:#?}\n{:#?}",
+        suite.db.metadata,
+        suite.db.options
+    );
+
+    let files = select_files(args, &suite, files_from);
+    let mut summary = report::Summary::default();
+    let mut report_entries = Vec::new();
+
+    // Check that no inputs have changed since their results were recorded, since their accepted outputs may be stale
+    let verify_mode = match (args.strict_inputs, &args.verify_inputs) {
+        (true, _) => VerifyMode::Error,
+        (false, Some(verify_mode)) => verify_mode.clone(),
+        (false, None) => VerifyMode::Warn,
+    };
+    let changed = match Runner::new(&suite) {
+        Ok(runner) => runner.db(db_path).changed_inputs(&files),
+        Err(err) => return (summary, report_entries, Err(err)),
+    };
+
+    for file in changed.iter() {
+        eprintln!(
+            "Input changed since it was recorded: {}",
+            suite.relative_path(file).display()
+        );
+    }
 
-                db.results
-                    .entry(file.to_str().unwrap().to_string())
-                    .or_insert(Vec::new())
-                    .push(to_save.clone());
+    if !changed.is_empty() {
+        match verify_mode {
+            VerifyMode::Warn => eprintln!(
+                "{} input(s) changed since they were recorded, so their accepted outputs may be stale (use --strict-inputs to refuse to run)\n",
+                changed.len()
+            ),
+            VerifyMode::Error => {
+                Error::Other(format!("Refusing to run, {} input(s) changed", changed.len())).exit()
             }
-            TestResult::Failure(output, error) => {
-                // TODO: This is ugly, fix it with a function or something
+        }
+    }
 
-                let mut to_print = String::new();
-                match db.options.stdout_mode {
-                    Some(StreamMode::Print) | Some(StreamMode::Both) => {
-                        to_print.push_str(&output);
-                    }
-                    _ => {}
+    // Check that the tool under test is the version the baseline was recorded with, since its outputs may be stale if not
+    // The first version seen is remembered, and so is the current one when re-baselining with --accept-all
+    let tool_version = match Runner::new(&suite) {
+        Ok(runner) => runner.db(db_path).tool_version(),
+        Err(err) => return (summary, report_entries, Err(err)),
+    };
+    match tool_version {
+        Some(Ok(version)) => match &suite.db.tool_version {
+            Some(recorded) if *recorded != version => {
+                eprintln!(
+                    "Tool version changed since the baseline was recorded: {} -> {}",
+                    recorded, version
+                );
+                if args.strict_version {
+                    Error::Other("Refusing to compare against the baseline of another version (--strict-version)".to_string()).exit();
                 }
-                match db.options.stderr_mode {
-                    Some(StreamMode::Print) | Some(StreamMode::Both) => {
-                        to_print.push_str(&error);
-                    }
-                    _ => {}
+                eprintln!("Its accepted outputs may be stale (use --strict-version to refuse to run, or update --accept-all to re-baseline)\n");
+                if accept_all {
+                    suite.db.tool_version = Some(version);
                 }
+            }
+            Some(_) => {}
+            None => suite.db.tool_version = Some(version),
+        },
+        Some(Err(err)) => Error::Other(format!("Unable to check the tool version: {}", err)).exit(),
+        None => {}
+    }
+
+    // Record what this run depends on before running, so it describes the expectations compared against
+    if args.reproducible {
+        let path = args.manifest.clone().unwrap_or_else(|| match db_path {
+            Some(db_path) => format!("{}.manifest.json", db_path),
+            None => "testit.manifest.json".to_string(),
+        });
+        let saved = Runner::new(&suite).and_then(|runner| {
+            let runner = runner.db(db_path).reproducible(true);
+            Manifest::new(&suite, &runner, &files)?.save(&path)
+        });
+        if let Err(err) = saved {
+            return (summary, report_entries, Err(err));
+        }
+        log::info!("Wrote manifest to {}", path);
+    }
+
+    // With a matrix, every file is run (and recorded) once per variant
+    for variant in suite.db.options.variants() {
+        if let Some(variant) = &variant {
+            log::info!("Running variant {}: {}", variant.name, variant.args);
+        }
+        suite.variant = variant;
+
+        // For each file, run the command
+        let runner = match Runner::new(&suite) {
+            Ok(runner) => runner,
+            Err(err) => return (summary, report_entries, Err(err)),
+        };
+        let runner = runner
+            .db(db_path)
+            .incremental(args.incremental)
+            .failed_first(args.failed_first)
+            .update_fixtures(args.update_fixtures)
+            .keep_failed_tmp(args.keep_failed_tmp)
+            .reproducible(args.reproducible)
+            .stream(args.stream)
+            .deadline(deadline)
+            .fail_fast(failed_fast)
+            .events(events);
+
+        // A suite whose setup hook fails isn't run (or saved), but later suites still are
+        let outcomes = match runner.run(&files) {
+            Ok(outcomes) => outcomes,
+            Err(err) => return (summary, report_entries, Err(err)),
+        };
+        let incidents = runner.incidents();
+        let encoding_notes = runner.encoding_notes();
+        let kept_tmpdirs = runner.kept_tmpdirs();
+        let durations = runner.durations();
+        summary.incidents += incidents.len();
+        summary.encoding_notes += encoding_notes.len();
+
+        // Problems with running tests are reported separately from the tests themselves
+        for incident in incidents.iter() {
+            eprintln!(
+                "{}: Incident: {}",
+                suite.key(&incident.file),
+                incident.description
+            );
+        }
+        for (file, note) in encoding_notes.iter() {
+            eprintln!("{}: Encoding: {}", suite.key(file), note);
+        }
+        for (file, tmpdir) in kept_tmpdirs.iter() {
+            eprintln!(
+                "{}: Kept temporary directory {}",
+                suite.key(file),
+                tmpdir.display()
+            );
+        }
 
-                failure_count += 1;
+        // Compare and write results
+        // This will only print failures, timeouts, and new successes
+        // If the output file is set and we see the same success again, it will be ignored
+        for (input, outcome) in files.iter().zip(outcomes.iter()) {
+            // Remove the directory prefix if it exists (and add the variant, if any)
+            // This will apply to the printed output + the output file
+            let key = suite.key(input);
+            let file = Path::new(&key);
+            let previous = suite
+                .db
+                .results
+                .get(file.to_str().unwrap())
+                .and_then(|outputs| outputs.last())
+                .cloned();
+            let previous_status = suite.db.status.get(file.to_str().unwrap()).copied();
+            let fastest_ms = suite
+                .db
+                .timing
+                .get(file.to_str().unwrap())
+                .map(|timing| timing.fastest);
+            let comparison = match suite.record(input, outcome) {
+                Ok(comparison) => comparison,
+                Err(err) => return (summary, report_entries, Err(err)),
+            };
+            let elapsed_ms = durations.get(input).copied();
+            let slow = match (suite.db.options.warn_timeout, outcome, elapsed_ms) {
+                _ if suite.expects_timeout(input) => false,
+                (
+                    Some(warn_timeout),
+                    TestOutcome::Success(..) | TestOutcome::Failure(..),
+                    Some(elapsed_ms),
+                ) => elapsed_ms > u128::from(warn_timeout) * 1000,
+                _ => false,
+            };
+            if accept_all && matches!(outcome, TestOutcome::Success(..)) {
+                suite.db.bless(file.to_str().unwrap());
+            }
+
+            events.emit(Event::TestResult {
+                file: file.to_str().unwrap(),
+                status: outcome.name(),
+                new: comparison.new,
+            });
+            report_entries.push(report::Entry {
+                file: file.to_str().unwrap().to_string(),
+                path: input.to_str().unwrap().to_string(),
+                status: outcome.name(),
+                output: comparison.printed.clone(),
+                elapsed_ms,
+                fastest_ms,
+                slow,
+                expected: previous.clone(),
+                owner: suite.owner(input),
+                regression: matches!(previous_status, Some(Status::Success | Status::New))
+                    && matches!(
+                        outcome,
+                        TestOutcome::Failure(..) | TestOutcome::Timeout(..) | TestOutcome::Error(_)
+                    ),
+                usage_regressions: comparison.usage_regressions.clone(),
+            });
+            if !comparison.usage_regressions.is_empty() {
+                summary.usage_regressions += 1;
+            }
 
-                if !args.verbose.is_silent() {
-                    println!("{}: Failure\n{}\n===\n", file.display(), to_print);
+            // Slow tests still count as whatever they were, this is just a warning before they start timing out
+            if slow {
+                summary.slow += 1;
+                if prints_tests(args) {
+                    println!(
+                        "{}: {} ({}ms, over --warn-timeout of {}s)",
+                        file.display(),
+                        paint(args, Color::Yellow, "SLOW"),
+                        elapsed_ms.unwrap(),
+                        suite.db.options.warn_timeout.unwrap()
+                    );
                 }
             }
-            TestResult::Timeout => {
-                timeout_count += 1;
 
-                if !args.verbose.is_silent() {
-                    println!("{}: Timeout", file.display());
+            match outcome {
+                TestOutcome::Success(..) => {
+                    summary.successes += 1;
+                    summary.success_ms += elapsed_ms.unwrap_or_default();
+
+                    // We have successful output we haven't seen before, log it
+                    if comparison.new {
+                        summary.new_successes += 1;
+
+                        let to_print = truncate_output(args, artifacts, file, &comparison.printed);
+                        let to_print = highlight(args, previous.as_deref(), &to_print);
+                        if prints_tests(args) {
+                            println!(
+                                "{}: {}\n{}\n===\n",
+                                file.display(),
+                                paint(args, Color::Green, "New success:"),
+                                to_print
+                            );
+                        }
+                    }
+                }
+                TestOutcome::Failure(..) => {
+                    summary.failures += 1;
+                    summary.failure_ms += elapsed_ms.unwrap_or_default();
+
+                    let to_print = truncate_output(args, artifacts, file, &comparison.printed);
+                    let to_print = highlight(args, previous.as_deref(), &to_print);
+                    if prints_tests(args) {
+                        println!(
+                            "{}: {}\n{}\n===\n",
+                            file.display(),
+                            paint(args, Color::Red, "Failure"),
+                            to_print
+                        );
+                    }
+                }
+                TestOutcome::Timeout(limit) => {
+                    summary.timeouts += 1;
+                    summary.timeout_ms += elapsed_ms.unwrap_or_default();
+
+                    if prints_tests(args) {
+                        let message = match limit {
+                            Limit::WallClock => "Timeout",
+                            Limit::Cpu => "Timeout (CPU limit)",
+                        };
+                        println!(
+                            "{}: {}",
+                            file.display(),
+                            paint(args, Color::Yellow, message)
+                        );
+                    }
+                }
+                TestOutcome::Error(reason) => {
+                    summary.errors += 1;
+
+                    if prints_tests(args) {
+                        println!(
+                            "{}: {} {}",
+                            file.display(),
+                            paint(args, Color::Red, "Error:"),
+                            reason
+                        );
+                    }
+                }
+                TestOutcome::Skipped => {
+                    summary.skipped += 1;
+                }
+                TestOutcome::Unchanged => {
+                    summary.unchanged += 1;
+                }
+                TestOutcome::NotRun => {
+                    summary.not_run += 1;
                 }
             }
         }
     }
 
-    // Save the new results (if requested)
-    if !args.dry_run {
-        if let Some(db_path) = match args.mode {
-            Mode::Record { db, .. } => Some(db),
-            Mode::Update { db, .. } => Some(db),
-            _ => None,
-        } {
-            let f = std::fs::File::create(db_path).expect("Unable to write to db file: {db_path}");
-            serde_json::to_writer_pretty(f, &db).unwrap();
+    // Point out what the db is carrying for files that are gone, and files it has no accepted output for
+    // Every file the suite has is checked, even if only some of them were run
+    if db_path.is_some() {
+        let all = suite.files().unwrap_or_else(|err| err.exit());
+        let orphaned = suite.orphaned(&all);
+        let unrecorded = suite.unrecorded(&all);
+
+        for key in orphaned.iter() {
+            log::warn!(
+                "Orphaned: {} has accepted output, but its file is gone",
+                key
+            );
+        }
+        for file in unrecorded.iter() {
+            log::warn!(
+                "Unrecorded: {} has no accepted output",
+                suite.relative_path(file).display()
+            );
         }
+        summary.orphaned = orphaned.len();
+        summary.unrecorded = unrecorded.len();
     }
 
-    // Output a summary
-    if !args.verbose.is_silent() {
-        println!(
-            "\nSummary:\n\tSuccesses: {} ({} new)\n\tFailures: {}\n\tTimeouts: {}",
-            success_count, new_success_count, failure_count, timeout_count
-        );
+    if let Some(options) = saved_options {
+        suite.db.options = options;
     }
 
-    // Exit a success if there were no failures or timeouts
-    if failure_count == 0 && timeout_count == 0 {
-        std::process::exit(0);
+    // Remember when and where this run happened, if it's saved
+    if db_path.is_some() {
+        suite.db.add_run(db::RunRecord {
+            successes: summary.successes,
+            new_successes: summary.new_successes,
+            failures: summary.failures,
+            timeouts: summary.timeouts,
+            errors: summary.errors,
+            skipped: summary.skipped,
+            ..db::RunRecord::now(started.elapsed().as_millis())
+        });
+    }
+
+    // Save the new results (if requested)
+    let saved = match db_path {
+        Some(db_path) => save_suite(args, db_path, &mut suite, &mut summary),
+        None => Ok(()),
+    };
+
+    (summary, report_entries, saved)
+}
+
+// Save a suite's results to its db, unless the db was changed during the run (see --on-conflict)
+// On a dry run, just check if saving would have changed anything
+fn save_suite(
+    args: &Args,
+    db_path: &str,
+    suite: &mut TestSuite,
+    summary: &mut report::Summary,
+) -> error::Result<()> {
+    if args.dry_run {
+        if !Path::new(db_path).exists() || !suite.db.same_expectations(&Db::read(db_path)?) {
+            summary.unsaved += 1;
+        }
+        return Ok(());
+    }
+
+    let current_hash = if Path::new(db_path).exists() {
+        Some(hash::hash_file(Path::new(db_path))?)
     } else {
-        std::process::exit(1);
+        None
+    };
+
+    if current_hash != suite.loaded_hash {
+        eprintln!("Database file was changed during the run: {}", db_path);
+
+        match args.on_conflict {
+            OnConflict::Abort => {
+                return Err(Error::Other(format!(
+                    "Not saving results to {}; rerun or use --on-conflict merge|overwrite",
+                    db_path
+                )));
+            }
+            OnConflict::Merge => {
+                if current_hash.is_some() {
+                    eprintln!("Merging results into the changed database");
+                    suite.db.merge(Db::read(db_path)?);
+                }
+            }
+            OnConflict::Overwrite => {
+                eprintln!("Overwriting the changed database");
+            }
+        }
     }
+
+    suite.db.save(db_path, args.compress, args.db_format)
 }
@@ -0,0 +1,136 @@
+// A record of everything a reproducible run depends on, so two runs can be checked for equivalence
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::error::{Error, Result};
+use crate::hash::{hash_bytes, hash_dependencies, hash_file};
+use crate::options::{Metadata, Options, TestCommand};
+use crate::runner::{interpolate, Runner, REDACTED};
+use crate::suite::TestSuite;
+
+/// Two reproducible runs with identical manifests ran the same command the same way against the
+/// same inputs and expectations, so they should have had identical results
+#[derive(Debug, Serialize)]
+pub struct Manifest {
+    /// The version of testit that did the run
+    pub testit: String,
+
+    pub metadata: Metadata,
+
+    pub options: Options,
+
+    /// The complete environment each test ran with, with the values of redact_env variables hidden
+    pub env: BTreeMap<String, String>,
+
+    /// The program the command runs, if it could be found
+    pub binary: Option<Binary>,
+
+    /// Hash of each input file that was run
+    pub inputs: BTreeMap<String, String>,
+
+    /// Hashes of the dependencies of each input file
+    pub dependencies: BTreeMap<String, BTreeMap<String, String>>,
+
+    /// Hash of the accepted outputs the run was compared against
+    pub expectations: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Binary {
+    pub path: String,
+    pub sha256: String,
+}
+
+impl Manifest {
+    /// Describe a run of these files; the suite should not have recorded any results yet
+    pub fn new(suite: &TestSuite, runner: &Runner, files: &[PathBuf]) -> Result<Manifest> {
+        let mut inputs = BTreeMap::new();
+        let mut dependencies = BTreeMap::new();
+        for file in files {
+            let key = suite.relative_path(file).to_string_lossy().to_string();
+            inputs.insert(key.clone(), hash_file(file)?);
+
+            let hashes = hash_dependencies(file)?;
+            if !hashes.is_empty() {
+                dependencies.insert(key, hashes);
+            }
+        }
+
+        // The values of redact_env variables are hidden, both as given with --env and in the environment
+        let redact = |key: &str, value: &str| {
+            if suite.db.options.redact_env.iter().any(|name| name == key) {
+                REDACTED.to_string()
+            } else {
+                value.to_string()
+            }
+        };
+        let mut options = suite.db.options.clone();
+        for pair in options.env.iter_mut() {
+            if let Some((key, value)) = pair.split_once('=') {
+                *pair = format!("{}={}", key, redact(key, value));
+            }
+        }
+        let env = runner
+            .env()
+            .iter()
+            .map(|(key, value)| (key.clone(), redact(key, value)))
+            .collect();
+
+        Ok(Manifest {
+            testit: env!("CARGO_PKG_VERSION").to_string(),
+            metadata: suite.db.metadata.clone(),
+            options,
+            env,
+            binary: binary(suite, runner.env())?,
+            inputs,
+            dependencies,
+            expectations: hash_bytes(&serde_json::to_vec(&suite.db.results).unwrap()),
+        })
+    }
+
+    /// Write the manifest as pretty printed JSON
+    pub fn save(&self, path: &str) -> Result<()> {
+        let json = serde_json::to_string_pretty(self).unwrap();
+        std::fs::write(path, json + "\n").map_err(|err| Error::io("write manifest", path, err))
+    }
+}
+
+// Find and hash the program the command runs: its first word, from the directory if it's a path, else on $PATH
+fn binary(suite: &TestSuite, env: &BTreeMap<String, String>) -> Result<Option<Binary>> {
+    let program = match &suite.db.metadata.command {
+        TestCommand::Shell(command) => interpolate(command, env)
+            .split_whitespace()
+            .next()
+            .map(str::to_string),
+        TestCommand::Argv(argv) => argv.first().map(|program| interpolate(program, env)),
+    };
+    let Some(program) = program else {
+        return Ok(None);
+    };
+    let program = program.as_str();
+    let directory = Path::new(suite.db.metadata.directory.as_deref().unwrap_or("."));
+
+    let path = if program.contains('/') {
+        Some(directory.join(program))
+    } else {
+        let search = env
+            .get("PATH")
+            .cloned()
+            .or_else(|| std::env::var("PATH").ok())
+            .unwrap_or_default();
+        std::env::split_paths(&search)
+            .map(|dir| dir.join(program))
+            .find(|path| path.is_file())
+    }
+    .filter(|path| path.is_file());
+    let Some(path) = path else {
+        return Ok(None);
+    };
+
+    Ok(Some(Binary {
+        path: path.display().to_string(),
+        sha256: hash_file(&path)?,
+    }))
+}
@@ -0,0 +1,50 @@
+// Database schema versions and upgrades between them
+// Migrations work on raw JSON so they can handle layouts the current Db struct can't
+use serde_json::Value;
+
+/// The current version of the database schema
+pub const VERSION: u32 = 2;
+
+/// Get the schema version of a raw db; dbs from before versioning are version 0
+pub fn version(db: &Value) -> u32 {
+    db.get("version").and_then(Value::as_u64).unwrap_or(0) as u32
+}
+
+/// Upgrade a raw db to the current version in place, returning the version it started at
+pub fn migrate(db: &mut Value) -> u32 {
+    let original = version(db);
+
+    if version(db) == 0 {
+        to_v1(db);
+    }
+    if version(db) == 1 {
+        to_v2(db);
+    }
+
+    original
+}
+
+// v1: Drop the old %name% keys in favor of plain names
+fn to_v1(db: &mut Value) {
+    let object = db.as_object_mut().expect("Database must be a JSON object");
+
+    for name in ["metadata", "options", "timing"] {
+        if let Some(value) = object.remove(&format!("%{}%", name)) {
+            object.entry(name).or_insert(value);
+        }
+    }
+
+    object.insert("version".to_string(), Value::from(1));
+}
+
+// v2: metadata.files is a list of patterns rather than a single one
+fn to_v2(db: &mut Value) {
+    if let Some(metadata) = db.get_mut("metadata").and_then(Value::as_object_mut) {
+        if let Some(Value::String(files)) = metadata.get("files") {
+            let files = Value::from(vec![files.clone()]);
+            metadata.insert("files".to_string(), files);
+        }
+    }
+
+    db["version"] = Value::from(2);
+}
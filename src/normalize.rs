@@ -0,0 +1,85 @@
+// Built-in normalizers for common sources of nondeterminism in output (see --normalize)
+// Each replaces what it matches with a fixed placeholder, so outputs that only differ there are the same once saved
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum, Serialize, Deserialize)]
+pub enum Normalizer {
+    /// ISO 8601 style dates with times, such as 2024-01-02T03:04:05.678Z, replaced with <TIMESTAMP>
+    Timestamps,
+
+    /// UUIDs, such as 123e4567-e89b-12d3-a456-426614174000, replaced with <UUID>
+    Uuids,
+
+    /// Paths in the temporary directory (/tmp, $TMPDIR, or /var/folders on macOS), replaced with <TMP>
+    TmpPaths,
+
+    /// Hex memory addresses (0x followed by 6 to 16 hex digits), such as 0x7ffd5e8c1a2c, replaced with 0x<ADDR>
+    HexAddresses,
+}
+
+impl Normalizer {
+    // The regex matching what to replace, and what to replace it with
+    fn pattern(self) -> (String, &'static str) {
+        match self {
+            Normalizer::Timestamps => (
+                r"\d{4}-\d{2}-\d{2}[T ]\d{2}:\d{2}:\d{2}(?:[.,]\d+)?(?:Z|[+-]\d{2}:?\d{2})?"
+                    .to_string(),
+                "<TIMESTAMP>",
+            ),
+            Normalizer::Uuids => (
+                r"(?i)\b[0-9a-f]{8}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{12}\b".to_string(),
+                "<UUID>",
+            ),
+            Normalizer::TmpPaths => {
+                // $TMPDIR is usually one of these, but not always (and may be a symlink, like /tmp on macOS)
+                let mut directories = vec![
+                    "/tmp".to_string(),
+                    "/private/tmp".to_string(),
+                    "/private/var/folders".to_string(),
+                    "/var/folders".to_string(),
+                ];
+                let temp_dir = std::env::temp_dir();
+                let temp_dir = temp_dir.to_str().unwrap_or("/tmp").trim_end_matches('/');
+                if !temp_dir.is_empty() && !directories.iter().any(|dir| dir == temp_dir) {
+                    directories.push(temp_dir.to_string());
+                }
+
+                // Longest first, so /private/tmp isn't matched as /tmp
+                directories.sort_by_key(|dir| std::cmp::Reverse(dir.len()));
+                let directories = directories
+                    .iter()
+                    .map(|dir| regex::escape(dir))
+                    .collect::<Vec<_>>()
+                    .join("|");
+                (
+                    format!(r#"(?:{})(?:/[^\s'"`:,;)\]]*)?"#, directories),
+                    "<TMP>",
+                )
+            }
+            Normalizer::HexAddresses => (r"\b0x[0-9a-fA-F]{6,16}\b".to_string(), "0x<ADDR>"),
+        }
+    }
+}
+
+/// Remove ANSI escape sequences (colors, cursor movement, and terminal titles) from an output
+pub fn strip_ansi(output: &str) -> String {
+    // CSI sequences (like \x1b[31m), OSC sequences (like \x1b]0;title\x07), then any other two character escape
+    let ansi =
+        Regex::new(r"\x1b\[[0-?]*[ -/]*[@-~]|\x1b\][^\x07\x1b]*(?:\x07|\x1b\\)|\x1b[@-Z\\-_]")
+            .unwrap();
+    ansi.replace_all(output, "").into_owned()
+}
+
+/// Apply each normalizer to an output in turn
+pub fn normalize(output: &str, normalizers: &[Normalizer]) -> String {
+    let mut output = output.to_string();
+    for normalizer in normalizers {
+        let (pattern, replacement) = normalizer.pattern();
+        output = Regex::new(&pattern)
+            .unwrap()
+            .replace_all(&output, replacement)
+            .into_owned();
+    }
+    output
+}
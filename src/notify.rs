@@ -0,0 +1,94 @@
+// Notifications sent once a run is done, for chat integrations (Slack, Discord, Teams, ...)
+// Requests go through curl, like remote syncing goes through the aws cli
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use crate::report::{Entry, Summary};
+
+// How many failing tests to name in the message text (all of them are in the JSON fields)
+const MAX_LISTED: usize = 10;
+
+/// POST a JSON summary of a run to a webhook
+/// `text` (Slack, Teams) and `content` (Discord) hold a readable message, the other fields the details
+/// Failing to notify is reported but doesn't fail the run
+pub fn webhook(url: &str, entries: &[Entry], summary: &Summary, duration: Duration) {
+    let failing = entries
+        .iter()
+        .filter(|entry| entry.failed())
+        .map(|entry| entry.file.as_str())
+        .collect::<Vec<_>>();
+    let regressions = entries
+        .iter()
+        .filter(|entry| entry.regression)
+        .map(|entry| entry.file.as_str())
+        .collect::<Vec<_>>();
+
+    let text = message(summary, &failing, &regressions, duration);
+    let payload = serde_json::json!({
+        "text": text,
+        "content": text,
+        "successes": summary.successes,
+        "new_successes": summary.new_successes,
+        "failures": summary.failures,
+        "timeouts": summary.timeouts,
+        "errors": summary.errors,
+        "skipped": summary.skipped,
+        "unchanged": summary.unchanged,
+        "incidents": summary.incidents,
+        "not_run": summary.not_run,
+        "regressions": regressions,
+        "failing": failing,
+        "duration_ms": duration.as_millis(),
+    });
+
+    let child = Command::new("curl")
+        .args(["--silent", "--show-error", "--fail", "--max-time", "30"])
+        .args(["--header", "Content-Type: application/json"])
+        .args(["--data-binary", "@-", "--output", "/dev/null", url])
+        .stdin(Stdio::piped())
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(err) => {
+            eprintln!("Unable to notify webhook (is curl installed?): {}", err);
+            return;
+        }
+    };
+
+    let written = child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(payload.to_string().as_bytes());
+    match (written, child.wait()) {
+        (Ok(()), Ok(status)) if status.success() => log::info!("Notified webhook {}", url),
+        _ => eprintln!("Unable to notify webhook {}", url),
+    }
+}
+
+// A short summary, like: testit: 2 failures (1 regressions), 0 timeouts, 10 successes (3 new) in 4.2s
+fn message(
+    summary: &Summary,
+    failing: &[&str],
+    regressions: &[&str],
+    duration: Duration,
+) -> String {
+    let mut text = format!(
+        "testit: {} failures ({} regressions), {} timeouts, {} successes ({} new) in {:.1}s",
+        summary.failures,
+        regressions.len(),
+        summary.timeouts,
+        summary.successes,
+        summary.new_successes,
+        duration.as_secs_f64()
+    );
+
+    for file in failing.iter().take(MAX_LISTED) {
+        text.push_str(&format!("\n- {}", file));
+    }
+    if failing.len() > MAX_LISTED {
+        text.push_str(&format!("\n- and {} more", failing.len() - MAX_LISTED));
+    }
+    text
+}
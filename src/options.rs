@@ -0,0 +1,916 @@
+// How a suite is run: the metadata fixed when it was recorded and the options that can change
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::process::Command;
+
+use clap::Parser;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use crate::normalize::{self, Normalizer};
+use crate::runner::shell_quote;
+
+/// Options that are saved with record and cannot be overridden
+#[derive(Parser, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Metadata {
+    /// The command to run; should read from stdin and write to stdout and/or stderr
+    /// A JSON list of strings (like '["./tool", "--check"]') is run as a program and its arguments, without bash
+    pub command: TestCommand,
+
+    /// The working directory to run the command from (default: cwd)
+    #[arg(short, long)]
+    pub directory: Option<String>,
+
+    /// Glob style patterns defining the files to test; files matching more than one are only tested once
+    #[arg(required = true)]
+    pub files: Vec<String>,
+
+    /// Glob patterns (relative to the directory) of files to leave out entirely; multiple can be specified (default: [])
+    #[arg(long)]
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+/// Options that can be changed on each update
+#[derive(Parser, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Options {
+    /// How to direct stdout (default: both)
+    #[arg(long)]
+    pub stdout_mode: Option<StreamMode>,
+
+    /// How to direct stderr (default: print)
+    #[arg(long)]
+    pub stderr_mode: Option<StreamMode>,
+
+    /// Specify environment variables as key=value pairs; multiple can be specified (default: [])
+    #[arg(short, long)]
+    pub env: Vec<String>,
+
+    /// How each test gets its input file (default: stdin)
+    #[arg(long)]
+    #[serde(default)]
+    pub input_mode: Option<InputMode>,
+
+    /// A command each input is piped through (with the file as $1) before the test command gets it on stdin,
+    /// such as `gunzip -c`; inputs are hashed (to tell if they've changed) as it outputs them
+    #[arg(long)]
+    #[serde(default)]
+    pub preprocess: Option<String>,
+
+    /// A command each successful test's stdout is piped through (with the file as $1) before it's compared and saved,
+    /// such as `jq -S .` to canonicalize JSON; the test fails if it does
+    #[arg(long)]
+    #[serde(default)]
+    pub postprocess: Option<String>,
+
+    /// How to handle output that isn't valid UTF-8 (default: lossy)
+    #[arg(long)]
+    #[serde(default)]
+    pub output_encoding: Option<OutputEncoding>,
+
+    /// Extra environment variables for individual tests (by file, relative to the directory) over env; only set in the db
+    #[arg(skip)]
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub test_env: BTreeMap<String, BTreeMap<String, String>>,
+
+    /// Load environment variables from a .env style file of KEY=VALUE lines; -e takes precedence
+    #[arg(long)]
+    #[serde(default)]
+    pub env_file: Option<String>,
+
+    /// Preserve the environment of the parent process (default: false)
+    #[arg(short = 'E', long)]
+    pub preserve_env: Option<bool>,
+
+    /// The time to allow for each test in seconds (default: 10)
+    #[arg(short, long)]
+    pub timeout: Option<u64>,
+
+    /// The CPU time to allow for each test in seconds, separate from the timeout (default: no limit)
+    #[arg(long)]
+    #[serde(default)]
+    pub cpu_limit: Option<u64>,
+
+    /// Flag tests that take longer than this many seconds as slow, without stopping them (default: never)
+    #[arg(long)]
+    #[serde(default)]
+    pub warn_timeout: Option<u64>,
+
+    /// Run each test in a sandbox with a read-only view of the filesystem, a private /tmp, and no network (default: none)
+    #[arg(long)]
+    #[serde(default)]
+    pub sandbox: Option<Sandbox>,
+
+    /// Only save this many bytes of each output, marked with its full length and hash (default: no limit)
+    #[arg(long)]
+    #[serde(default)]
+    pub max_saved_bytes: Option<usize>,
+
+    /// How many runs of each test to keep in its history, 0 to keep none (default: 20)
+    #[arg(long)]
+    #[serde(default)]
+    pub history_length: Option<usize>,
+
+    /// Glob patterns (relative to the directory) of files to skip; multiple can be specified (default: [])
+    #[arg(long)]
+    #[serde(default)]
+    pub skip: Vec<String>,
+
+    /// Glob patterns (relative to the directory) of files whose output lines can come in any order (default: [])
+    #[arg(long)]
+    #[serde(default)]
+    pub unordered: Vec<String>,
+
+    /// Remove ANSI escape sequences (such as colors) from output before saving (and comparing) it (default: false)
+    #[arg(long)]
+    #[serde(default)]
+    pub strip_ansi: Option<bool>,
+
+    /// Convert CRLF line endings to LF in output before saving (and comparing) it, so dbs work across platforms (default: false)
+    #[arg(long)]
+    #[serde(default)]
+    pub normalize_eol: Option<bool>,
+
+    /// Replace common sources of nondeterminism in output before saving (and comparing) it, comma separated (default: none)
+    #[arg(long, value_delimiter = ',')]
+    #[serde(default)]
+    pub normalize: Vec<Normalizer>,
+
+    /// Only save (and compare) output lines matching any of these regexes, such as ^RESULT: (default: every line)
+    #[arg(long)]
+    #[serde(default)]
+    pub only_lines: Vec<String>,
+
+    /// Don't save (or compare) output lines matching any of these regexes, such as progress messages (default: [])
+    #[arg(long)]
+    #[serde(default)]
+    pub ignore_lines: Vec<String>,
+
+    /// Replace the values of these environment variables (set for the test, or in our own environment) with *** in output (default: [])
+    #[arg(long)]
+    #[serde(default)]
+    pub redact_env: Vec<String>,
+
+    /// Replace text matching any of these regexes with *** in output, such as 'token=[a-z0-9]+' (default: [])
+    #[arg(long)]
+    #[serde(default)]
+    pub redact_pattern: Vec<String>,
+
+    /// Regexes that stderr must match for a test to succeed, as well as matching its accepted output (default: [])
+    #[arg(long)]
+    #[serde(default)]
+    pub stderr_must_match: Vec<String>,
+
+    /// Regexes that stderr must not match for a test to succeed (default: [])
+    #[arg(long)]
+    #[serde(default)]
+    pub stderr_must_not_match: Vec<String>,
+
+    /// Regexes that stdout must match for a test to succeed, for key markers when comparing the whole output is too brittle (default: [])
+    #[arg(long)]
+    #[serde(default)]
+    pub stdout_must_match: Vec<String>,
+
+    /// Regexes that stdout must not match for a test to succeed (default: [])
+    #[arg(long)]
+    #[serde(default)]
+    pub stdout_must_not_match: Vec<String>,
+
+    /// Extra output assertions for individual tests (by file, relative to the directory) on top of the suite's; only set in the db
+    #[arg(skip)]
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub test_assertions: BTreeMap<String, Assertions>,
+
+    /// Compare the directory each test writes to $TESTIT_OUTPUT_DIR against fixture trees under this directory
+    #[arg(long)]
+    #[serde(default)]
+    pub fixtures: Option<String>,
+
+    /// Run each test in a fresh copy of this directory (relative to the directory), also set as $TESTIT_TMPDIR
+    #[arg(long)]
+    #[serde(default)]
+    pub fixture_dir: Option<String>,
+
+    /// A command to decide if an output matches an accepted one, exiting with 0 if so; {expected} and {actual} are replaced with files holding each
+    #[arg(long)]
+    #[serde(default)]
+    pub comparator: Option<String>,
+
+    /// Store accepted outputs as files in this directory (relative to the db) instead of in the db itself
+    #[arg(long)]
+    #[serde(default)]
+    pub snapshots: Option<String>,
+
+    /// Store everything about each test as its own file in this directory (relative to the db) instead of in the db itself
+    #[arg(long)]
+    #[serde(default)]
+    pub db_dir: Option<String>,
+
+    /// Store each output in the db as an array of its lines, so diffs of the db are line oriented (default: false)
+    #[arg(long)]
+    #[serde(default)]
+    pub results_as_lines: Option<bool>,
+
+    /// Owners of tests as PATTERN=OWNER (relative to the directory); the last match wins, and a <file>.owner sidecar takes precedence
+    #[arg(long)]
+    #[serde(default)]
+    pub owners: Vec<String>,
+
+    /// Glob patterns (relative to the directory) of files to run one at a time after all the others, such as tests that
+    /// change shared global state; multiple can be specified (default: [])
+    #[arg(long)]
+    #[serde(default)]
+    pub serial: Vec<String>,
+
+    /// Glob patterns (relative to the directory) of files expected to hang (such as watchdog tests): reaching the timeout
+    /// is their success, and exiting before it a failure; multiple can be specified (default: [])
+    #[arg(long)]
+    #[serde(default)]
+    pub expect_timeout: Vec<String>,
+
+    /// Glob patterns (relative to the directory) of files expected to exit with a non-zero status (such as error message
+    /// tests), which is their success while exiting with 0 is a failure; use '**' for a whole suite (default: [])
+    #[arg(long)]
+    #[serde(default)]
+    pub expect_failure: Vec<String>,
+
+    /// Named resources used by tests as PATTERN=RESOURCE (relative to the directory), such as 'net/**=port-8080'; tests using
+    /// a resource run one at a time (or up to its --resource-limits) while everything else stays parallel (default: [])
+    #[arg(long)]
+    #[serde(default)]
+    pub resources: Vec<String>,
+
+    /// How many tests can use a resource at once as RESOURCE=N, such as gpu=2 (default: 1 for each resource)
+    #[arg(long)]
+    #[serde(default)]
+    pub resource_limits: Vec<String>,
+
+    /// Named argument sets as NAME=ARGS, each appended to the command for a run of every file; multiple can be specified (default: [])
+    #[arg(long)]
+    #[serde(default)]
+    pub matrix: Vec<String>,
+
+    /// A command printing the version of the tool under test, such as 'mytool --version'; a baseline recorded with another
+    /// version may be stale (default: none)
+    #[arg(long)]
+    #[serde(default)]
+    pub version_command: Option<String>,
+
+    /// A command to run (from the directory) before any tests; if it fails, no tests are run
+    #[arg(long, visible_alias = "pre")]
+    #[serde(default, alias = "pre")]
+    pub setup: Option<String>,
+
+    /// A command to run (from the directory) after all tests
+    #[arg(long, visible_alias = "post")]
+    #[serde(default, alias = "post")]
+    pub teardown: Option<String>,
+
+    /// A command to run before each test, with the input file as $1; the test fails if this does
+    #[arg(long)]
+    #[serde(default)]
+    pub before_each: Option<String>,
+
+    /// A command to run after each test, with the input file as $1
+    #[arg(long)]
+    #[serde(default)]
+    pub after_each: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, clap::ValueEnum, Serialize, Deserialize)]
+pub enum StreamMode {
+    /// Don't save or print
+    None,
+
+    /// Save to database, don't print
+    Save,
+
+    /// Print as normal, but don't save
+    Print,
+
+    /// Save to database and print
+    Both,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum, Serialize, Deserialize)]
+pub enum InputMode {
+    /// Pipe the file to the command's stdin
+    Stdin,
+
+    /// Pass the file's path (relative to the directory) as the command's final argument
+    Arg,
+
+    /// Set $TESTIT_INPUT to the file's path (relative to the directory)
+    Env,
+
+    /// Run the command with no input, such as for tests defined entirely by their file name
+    None,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum, Serialize, Deserialize)]
+pub enum OutputEncoding {
+    /// Replace invalid bytes with U+FFFD (so outputs that only differ in invalid bytes are the same)
+    Lossy,
+
+    /// Keep every byte, escaping invalid bytes as \xNN (and backslashes in every output as \\, so the escaping is unambiguous)
+    Bytes,
+}
+
+/// The command a suite runs for each test, either a command line or (as a list in the db) a program and its arguments
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum TestCommand {
+    /// Run with `bash -c`, so it can use pipes, redirects, and so on
+    Shell(String),
+
+    /// Run directly, so nothing needs quoting and no shell is started for each test
+    Argv(Vec<String>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum, Serialize, Deserialize)]
+pub enum Sandbox {
+    /// Run tests directly
+    None,
+
+    /// Run tests with bubblewrap (bwrap), which must be installed
+    Bwrap,
+}
+
+impl std::str::FromStr for TestCommand {
+    type Err = std::convert::Infallible;
+
+    // A JSON list of strings is a program and its arguments, anything else (including `[ -f x ] && ...`) a command line
+    fn from_str(command: &str) -> std::result::Result<Self, Self::Err> {
+        if command.trim_start().starts_with('[') {
+            if let Ok(argv) = serde_json::from_str(command) {
+                return Ok(TestCommand::Argv(argv));
+            }
+        }
+        Ok(TestCommand::Shell(command.to_string()))
+    }
+}
+
+impl std::fmt::Display for TestCommand {
+    // A program and its arguments are shell quoted, so either can be pasted into a shell
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TestCommand::Shell(command) => write!(f, "{}", command),
+            TestCommand::Argv(argv) => {
+                let words = argv.iter().map(|arg| shell_quote(arg)).collect::<Vec<_>>();
+                write!(f, "{}", words.join(" "))
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for StreamMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StreamMode::None => write!(f, "none"),
+            StreamMode::Save => write!(f, "save"),
+            StreamMode::Print => write!(f, "print"),
+            StreamMode::Both => write!(f, "both"),
+        }
+    }
+}
+
+impl Metadata {
+    /// Check for problems that would otherwise only show up partway through a run
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        if let Some(directory) = &self.directory {
+            if !Path::new(directory).is_dir() {
+                errors.push(format!("directory {} does not exist", directory));
+            }
+        }
+
+        if self.command == TestCommand::Argv(Vec::new()) {
+            errors.push("command list must have at least the program to run".to_string());
+        }
+
+        for pattern in self.files.iter() {
+            if let Err(err) = glob::Pattern::new(pattern) {
+                errors.push(format!("files pattern {} is invalid: {}", pattern, err));
+            }
+        }
+
+        for pattern in self.exclude.iter() {
+            if let Err(err) = glob::Pattern::new(pattern) {
+                errors.push(format!("exclude pattern {} is invalid: {}", pattern, err));
+            }
+        }
+
+        errors
+    }
+}
+
+/// Regexes a test's output must (or must not) match, see test_assertions
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Assertions {
+    #[serde(default)]
+    pub stdout_must_match: Vec<String>,
+
+    #[serde(default)]
+    pub stdout_must_not_match: Vec<String>,
+
+    #[serde(default)]
+    pub stderr_must_match: Vec<String>,
+
+    #[serde(default)]
+    pub stderr_must_not_match: Vec<String>,
+}
+
+impl Assertions {
+    /// Add another set of assertions to these
+    pub fn extend(&mut self, other: &Assertions) {
+        self.stdout_must_match
+            .extend(other.stdout_must_match.iter().cloned());
+        self.stdout_must_not_match
+            .extend(other.stdout_must_not_match.iter().cloned());
+        self.stderr_must_match
+            .extend(other.stderr_must_match.iter().cloned());
+        self.stderr_must_not_match
+            .extend(other.stderr_must_not_match.iter().cloned());
+    }
+
+    /// Every pattern, with the stream it's checked against
+    pub fn patterns(&self) -> impl Iterator<Item = (&'static str, &String)> {
+        let stdout = self
+            .stdout_must_match
+            .iter()
+            .chain(self.stdout_must_not_match.iter());
+        let stderr = self
+            .stderr_must_match
+            .iter()
+            .chain(self.stderr_must_not_match.iter());
+        stdout
+            .map(|pattern| ("stdout", pattern))
+            .chain(stderr.map(|pattern| ("stderr", pattern)))
+    }
+
+    /// Check a test's stdout and stderr, describing each assertion that doesn't hold
+    /// The patterns should already be validated
+    pub fn check(&self, output: &str, error: &str) -> Vec<String> {
+        let mut problems = Vec::new();
+        for (name, text, must_match, must_not_match) in [
+            (
+                "stdout",
+                output,
+                &self.stdout_must_match,
+                &self.stdout_must_not_match,
+            ),
+            (
+                "stderr",
+                error,
+                &self.stderr_must_match,
+                &self.stderr_must_not_match,
+            ),
+        ] {
+            for pattern in must_match.iter() {
+                if !Regex::new(pattern).unwrap().is_match(text) {
+                    problems.push(format!("{} doesn't match {}", name, pattern));
+                }
+            }
+            for pattern in must_not_match.iter() {
+                if Regex::new(pattern).unwrap().is_match(text) {
+                    problems.push(format!("{} matches {}", name, pattern));
+                }
+            }
+        }
+        problems
+    }
+}
+
+/// One configuration of the command in a matrix: its name (for results) and the arguments it appends
+#[derive(Debug, Clone, PartialEq)]
+pub struct Variant {
+    pub name: String,
+    pub args: String,
+}
+
+impl StreamMode {
+    pub fn prints(&self) -> bool {
+        matches!(self, StreamMode::Print | StreamMode::Both)
+    }
+
+    pub fn saves(&self) -> bool {
+        matches!(self, StreamMode::Save | StreamMode::Both)
+    }
+}
+
+impl Options {
+    /// Replace any unset values with their defaults
+    pub fn apply_defaults(&mut self) {
+        if self.stdout_mode.is_none() {
+            self.stdout_mode = Some(StreamMode::Both);
+        }
+        if self.stderr_mode.is_none() {
+            self.stderr_mode = Some(StreamMode::Print);
+        }
+        if self.preserve_env.is_none() {
+            self.preserve_env = Some(false);
+        }
+        if self.timeout.is_none() {
+            self.timeout = Some(10);
+        }
+    }
+
+    /// The variants to run each file with (see --matrix), or just None without a matrix
+    pub fn variants(&self) -> Vec<Option<Variant>> {
+        if self.matrix.is_empty() {
+            return vec![None];
+        }
+
+        self.matrix
+            .iter()
+            .filter_map(|variant| variant.split_once('='))
+            .map(|(name, args)| {
+                Some(Variant {
+                    name: name.to_string(),
+                    args: args.to_string(),
+                })
+            })
+            .collect()
+    }
+
+    /// Check for problems that would otherwise only show up partway through a run
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        if self.timeout == Some(0) {
+            errors.push("timeout must be at least 1 second".to_string());
+        }
+        if self.cpu_limit == Some(0) {
+            errors.push("cpu limit must be at least 1 second".to_string());
+        }
+        if let (Some(warn_timeout), Some(timeout)) = (self.warn_timeout, self.timeout) {
+            if warn_timeout == 0 || warn_timeout >= timeout {
+                errors.push(format!(
+                    "warn timeout must be at least 1 second and less than the timeout ({}s)",
+                    timeout
+                ));
+            }
+        }
+        if self.snapshots.is_some() && self.db_dir.is_some() {
+            errors.push("snapshots and db dir can't both be used".to_string());
+        }
+        if self.max_saved_bytes == Some(0) {
+            errors.push("max saved bytes must be at least 1".to_string());
+        }
+
+        for env in self.env.iter() {
+            match env.split_once('=') {
+                Some((key, value)) if !key.is_empty() && !value.contains('=') => {}
+                _ => errors.push(format!(
+                    "env {} must be KEY=VALUE (with exactly one =)",
+                    env
+                )),
+            }
+        }
+
+        for (file, env) in self.test_env.iter() {
+            for key in env.keys() {
+                if key.is_empty() || key.contains('=') {
+                    errors.push(format!(
+                        "test env for {} has an invalid name: {}",
+                        file, key
+                    ));
+                }
+            }
+        }
+
+        if self.preprocess.is_some() && !matches!(self.input_mode, Some(InputMode::Stdin) | None) {
+            errors.push("preprocess requires the stdin input mode".to_string());
+        }
+
+        if self.sandbox == Some(Sandbox::Bwrap)
+            && Command::new("bwrap").arg("--version").output().is_err()
+        {
+            errors.push("sandbox bwrap requires bwrap to be installed".to_string());
+        }
+
+        if let Some(env_file) = &self.env_file {
+            if !Path::new(env_file).is_file() {
+                errors.push(format!("env file {} does not exist", env_file));
+            } else if let Err(err) = parse_env_file(env_file) {
+                errors.push(err.to_string());
+            }
+        }
+
+        let mut names = Vec::new();
+        for variant in self.matrix.iter() {
+            match variant.split_once('=') {
+                Some((name, _))
+                    if !name.is_empty()
+                        && name
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c)) =>
+                {
+                    if names.contains(&name) {
+                        errors.push(format!("matrix variant {} is defined more than once", name));
+                    }
+                    names.push(name);
+                }
+                _ => errors.push(format!(
+                    "matrix {} must be NAME=ARGS (with a name of letters, digits, -, _, and .)",
+                    variant
+                )),
+            }
+        }
+
+        for pattern in self.skip.iter() {
+            if let Err(err) = glob::Pattern::new(pattern) {
+                errors.push(format!("skip pattern {} is invalid: {}", pattern, err));
+            }
+        }
+
+        for pattern in self.serial.iter() {
+            if let Err(err) = glob::Pattern::new(pattern) {
+                errors.push(format!("serial pattern {} is invalid: {}", pattern, err));
+            }
+        }
+
+        for pattern in self.expect_timeout.iter() {
+            if let Err(err) = glob::Pattern::new(pattern) {
+                errors.push(format!(
+                    "expect timeout pattern {} is invalid: {}",
+                    pattern, err
+                ));
+            }
+        }
+
+        for pattern in self.expect_failure.iter() {
+            if let Err(err) = glob::Pattern::new(pattern) {
+                errors.push(format!(
+                    "expect failure pattern {} is invalid: {}",
+                    pattern, err
+                ));
+            }
+        }
+
+        for pattern in self.unordered.iter() {
+            if let Err(err) = glob::Pattern::new(pattern) {
+                errors.push(format!("unordered pattern {} is invalid: {}", pattern, err));
+            }
+        }
+
+        for pattern in self.only_lines.iter().chain(self.ignore_lines.iter()) {
+            if let Err(err) = Regex::new(pattern) {
+                errors.push(format!("line pattern {} is invalid: {}", pattern, err));
+            }
+        }
+
+        for pattern in self.redact_pattern.iter() {
+            if let Err(err) = Regex::new(pattern) {
+                errors.push(format!("redact pattern {} is invalid: {}", pattern, err));
+            }
+        }
+
+        let mut assertions = self.suite_assertions();
+        for test in self.test_assertions.values() {
+            assertions.extend(test);
+        }
+        for (stream, pattern) in assertions.patterns() {
+            if let Err(err) = Regex::new(pattern) {
+                errors.push(format!(
+                    "{} pattern {} is invalid: {}",
+                    stream, pattern, err
+                ));
+            }
+        }
+
+        for resource in self.resources.iter() {
+            match resource.rsplit_once('=') {
+                Some((pattern, name)) if !name.is_empty() => {
+                    if let Err(err) = glob::Pattern::new(pattern) {
+                        errors.push(format!("resources pattern {} is invalid: {}", pattern, err));
+                    }
+                }
+                _ => errors.push(format!("resources {} must be PATTERN=RESOURCE", resource)),
+            }
+        }
+
+        for limit in self.resource_limits.iter() {
+            match limit.rsplit_once('=') {
+                Some((name, n)) if !name.is_empty() && n.parse::<usize>().is_ok_and(|n| n > 0) => {}
+                _ => errors.push(format!(
+                    "resource limit {} must be RESOURCE=N (with N at least 1)",
+                    limit
+                )),
+            }
+        }
+
+        for owner in self.owners.iter() {
+            match owner.rsplit_once('=') {
+                Some((pattern, owner)) if !owner.is_empty() => {
+                    if let Err(err) = glob::Pattern::new(pattern) {
+                        errors.push(format!("owners pattern {} is invalid: {}", pattern, err));
+                    }
+                }
+                _ => errors.push(format!("owners {} must be PATTERN=OWNER", owner)),
+            }
+        }
+
+        errors
+    }
+
+    /// The output assertions for a test (relative to the directory): the suite's, then the test's own
+    pub fn assertions(&self, file: &str) -> Assertions {
+        let mut assertions = self.suite_assertions();
+        if let Some(test) = self.test_assertions.get(file) {
+            assertions.extend(test);
+        }
+        assertions
+    }
+
+    // The output assertions that apply to every test in the suite
+    fn suite_assertions(&self) -> Assertions {
+        Assertions {
+            stdout_must_match: self.stdout_must_match.clone(),
+            stdout_must_not_match: self.stdout_must_not_match.clone(),
+            stderr_must_match: self.stderr_must_match.clone(),
+            stderr_must_not_match: self.stderr_must_not_match.clone(),
+        }
+    }
+
+    /// Parse environment variables from the env file (if set), then env (with exactly one = in each)
+    pub fn env_vars(&self) -> Result<BTreeMap<String, String>> {
+        let mut env = match &self.env_file {
+            Some(path) => parse_env_file(path)?,
+            None => BTreeMap::new(),
+        };
+
+        for pair in self.env.iter() {
+            match pair.split_once('=') {
+                Some((key, value)) if !key.is_empty() && !value.contains('=') => {
+                    env.insert(key.to_string(), value.to_string());
+                }
+                _ => {
+                    return Err(Error::Config(format!(
+                        "Invalid env {}: must be KEY=VALUE (with exactly one =)",
+                        pair
+                    )))
+                }
+            }
+        }
+
+        Ok(env)
+    }
+
+    /// The output of a test that should be printed
+    pub fn printed(&self, output: &str, error: &str) -> String {
+        self.select(output, error, StreamMode::prints)
+    }
+
+    /// The output of a test that should be saved to the db, normalized (see strip_ansi, normalize_eol, and normalize) and
+    /// keeping only the lines to compare (see only_lines and ignore_lines)
+    pub fn saved(&self, output: &str, error: &str) -> String {
+        let mut saved = self.select(output, error, StreamMode::saves);
+        if self.strip_ansi == Some(true) {
+            saved = normalize::strip_ansi(&saved);
+        }
+        if self.normalize_eol == Some(true) {
+            saved = saved.replace("\r\n", "\n");
+        }
+        let saved = normalize::normalize(&saved, &self.normalize);
+        if self.only_lines.is_empty() && self.ignore_lines.is_empty() {
+            return saved;
+        }
+
+        let only = self
+            .only_lines
+            .iter()
+            .map(|pattern| Regex::new(pattern).unwrap())
+            .collect::<Vec<_>>();
+        let ignore = self
+            .ignore_lines
+            .iter()
+            .map(|pattern| Regex::new(pattern).unwrap())
+            .collect::<Vec<_>>();
+        saved
+            .split_inclusive('\n')
+            .filter(|line| {
+                let line = line.trim_end_matches('\n');
+                (only.is_empty() || only.iter().any(|regex| regex.is_match(line)))
+                    && !ignore.iter().any(|regex| regex.is_match(line))
+            })
+            .collect()
+    }
+
+    /// A regex matching everything to redact from the output of a test with the given env, or None if there's nothing to redact
+    /// Values of redact_env variables come from the test's env, or else our own environment
+    pub fn redaction(&self, env: &BTreeMap<String, String>) -> Option<Regex> {
+        let mut values = self
+            .redact_env
+            .iter()
+            .filter_map(|name| env.get(name).cloned().or_else(|| std::env::var(name).ok()))
+            .filter(|value| !value.is_empty())
+            .collect::<Vec<_>>();
+
+        // Longest first, so a value that contains another is still redacted whole
+        values.sort_by_key(|value| std::cmp::Reverse(value.len()));
+
+        let alternatives = values
+            .iter()
+            .map(|value| regex::escape(value))
+            .chain(
+                self.redact_pattern
+                    .iter()
+                    .map(|pattern| format!("(?:{})", pattern)),
+            )
+            .collect::<Vec<_>>();
+        if alternatives.is_empty() {
+            return None;
+        }
+        Some(Regex::new(&alternatives.join("|")).unwrap())
+    }
+
+    // Combine stdout and stderr, keeping each only if its mode includes the given direction
+    fn select(&self, output: &str, error: &str, include: fn(&StreamMode) -> bool) -> String {
+        let mut selected = String::new();
+        if self.stdout_mode.as_ref().is_some_and(include) {
+            selected.push_str(output);
+        }
+        if self.stderr_mode.as_ref().is_some_and(include) {
+            selected.push_str(error);
+        }
+        selected
+    }
+
+    /// List options that differ from another set of options as (name, old, new)
+    pub fn changes_from(&self, old: &Options) -> Vec<(String, String, String)> {
+        let old = serde_json::to_value(old).unwrap();
+        let new = serde_json::to_value(self).unwrap();
+
+        new.as_object()
+            .unwrap()
+            .iter()
+            .filter(|(name, value)| old.get(name.as_str()) != Some(value))
+            .map(|(name, value)| {
+                let previous = old
+                    .get(name.as_str())
+                    .map_or("unset".to_string(), |v| v.to_string());
+                (name.clone(), previous, value.to_string())
+            })
+            .collect()
+    }
+}
+
+// Load a .env style file: KEY=VALUE per line, with blank lines and # comments ignored
+// Values can be 'single quoted' (literal) or "double quoted" (with \n, \t, \", and \\ escapes); a leading export is allowed
+fn parse_env_file(path: &str) -> Result<BTreeMap<String, String>> {
+    let contents =
+        std::fs::read_to_string(path).map_err(|err| Error::io("read env file", path, err))?;
+    let invalid =
+        |line: usize, problem: &str| Error::Config(format!("{}:{}: {}", path, line, problem));
+
+    let mut env = BTreeMap::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(invalid(i + 1, "Expected KEY=VALUE"));
+        };
+        let value = value.trim();
+
+        let value = if let Some(quoted) = value.strip_prefix('\'') {
+            match quoted.split_once('\'') {
+                Some((value, _)) => value.to_string(),
+                None => return Err(invalid(i + 1, "Unterminated quote")),
+            }
+        } else if let Some(quoted) = value.strip_prefix('"') {
+            let mut unescaped = String::new();
+            let mut chars = quoted.chars();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some('n') => unescaped.push('\n'),
+                        Some('t') => unescaped.push('\t'),
+                        Some(c) => unescaped.push(c),
+                        None => {}
+                    },
+                    Some(c) => unescaped.push(c),
+                    None => return Err(invalid(i + 1, "Unterminated quote")),
+                }
+            }
+            unescaped
+        } else {
+            // Unquoted values end at an inline comment
+            match value.split_once(" #") {
+                Some((value, _)) => value.trim_end().to_string(),
+                None => value.to_string(),
+            }
+        };
+
+        env.insert(key.trim().to_string(), value);
+    }
+
+    Ok(env)
+}
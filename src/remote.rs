@@ -0,0 +1,708 @@
+// Sync database files (and their snapshot or db dir trees) with shared object storage, through the aws cli
+// The db is transferred in chunks that are remembered next to it, so an interrupted pull or push picks up where it stopped
+// Each upload is a conditional put against the ETag that was last pulled, so concurrent pushes can't overwrite each other
+// A db with trees is stored as one bundle of the db and the trees' files, so its ETag covers both
+use std::collections::BTreeMap;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{self, Component, Path, PathBuf};
+use std::process::{Command, Output};
+
+use crate::db::Db;
+use crate::error::{Error, Result};
+use crate::hash::{hash_bytes, hash_file};
+
+// A db and the files of its trees (by their paths relative to the db), read from a bundle
+type Unbundled = (Vec<u8>, Vec<(String, Vec<u8>)>);
+
+// The start of a bundle of a db and its trees, which no db file starts with
+const BUNDLE_HEADER: &[u8] = b"testit-bundle\n";
+
+// Dbs larger than this are uploaded as a multipart upload in parts of this size, which is also the download chunk size
+const CHUNK_SIZE: u64 = 8 * 1024 * 1024;
+
+/// An object in remote storage, parsed from a url like s3://bucket/suite
+pub struct Remote {
+    bucket: String,
+    key: String,
+}
+
+// The state of the db the last time it was synced, stored next to it as `<db>.sync`
+// The hash covers the db file and the files in its trees
+struct SyncState {
+    etag: String,
+    hash: String,
+}
+
+// What the remote object must be for an upload to go through
+enum Precondition {
+    Any,
+    Missing,
+    Matches(String),
+}
+
+impl Remote {
+    /// Parse a remote url; the db's file name is appended to the given prefix
+    pub fn parse(url: &str, db: &str) -> Result<Remote> {
+        let Some(rest) = url.strip_prefix("s3://") else {
+            return Err(Error::Config(format!(
+                "Unsupported remote (only s3:// is supported): {}",
+                url
+            )));
+        };
+
+        let (bucket, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+        let Some(name) = path::Path::new(db).file_name() else {
+            return Err(Error::Config(format!(
+                "Unable to sync {}: it isn't a file",
+                db
+            )));
+        };
+        let name = name.to_string_lossy();
+        let key = if prefix.trim_matches('/').is_empty() {
+            name.to_string()
+        } else {
+            format!("{}/{}", prefix.trim_matches('/'), name)
+        };
+
+        Ok(Remote {
+            bucket: bucket.to_string(),
+            key,
+        })
+    }
+
+    fn url(&self) -> String {
+        format!("s3://{}/{}", self.bucket, self.key)
+    }
+
+    // Run an aws s3api command against the remote object
+    fn s3api(&self, command: &str, args: &[&str]) -> Result<Output> {
+        Command::new("aws")
+            .args([
+                "s3api",
+                command,
+                "--bucket",
+                &self.bucket,
+                "--key",
+                &self.key,
+            ])
+            .args(args)
+            .output()
+            .map_err(|err| Error::io("run", "aws", err))
+    }
+
+    // Run an aws s3api command that's expected to succeed, returning its (text) output
+    fn s3api_text(&self, command: &str, args: &[&str]) -> Result<String> {
+        let output = self.s3api(command, args)?;
+        if output.status.success() {
+            Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+        } else {
+            Err(self.failure(command, &output))
+        }
+    }
+
+    // The error for a failed aws command, a conflict if the remote changed under a conditional request
+    fn failure(&self, command: &str, output: &Output) -> Error {
+        let error = String::from_utf8_lossy(&output.stderr);
+        if error.contains("PreconditionFailed") || error.contains("ConditionalRequestConflict") {
+            Error::Other(format!(
+                "{} has changed since it was last pulled; pull first or use --force to overwrite it",
+                self.url()
+            ))
+        } else {
+            Error::Other(format!(
+                "Unable to {} {}:\n{}",
+                command,
+                self.url(),
+                error.trim()
+            ))
+        }
+    }
+
+    // Get the current ETag and size of the remote object, None if it doesn't exist
+    fn head(&self) -> Result<Option<(String, u64)>> {
+        let output = self.s3api(
+            "head-object",
+            &["--query", "[ETag, ContentLength]", "--output", "text"],
+        )?;
+
+        if output.status.success() {
+            let text = String::from_utf8_lossy(&output.stdout);
+            let (etag, size) = text.trim().split_once('\t').unwrap_or((text.trim(), "0"));
+            Ok(Some((etag.to_string(), size.parse().unwrap_or(0))))
+        } else {
+            let error = String::from_utf8_lossy(&output.stderr);
+            if error.contains("404") || error.contains("Not Found") {
+                Ok(None)
+            } else {
+                Err(self.failure("check", &output))
+            }
+        }
+    }
+
+    /// Download the remote db (and its trees), refusing to overwrite local changes unless forced
+    pub fn pull(&self, db: &str, force: bool, dry_run: bool) -> Result<()> {
+        let Some((etag, size)) = self.head()? else {
+            return Err(Error::Other(format!(
+                "Remote does not exist: {}",
+                self.url()
+            )));
+        };
+
+        let state = SyncState::load(db);
+        if path::Path::new(db).exists() {
+            let bytes =
+                std::fs::read(db).map_err(|err| Error::io("read database file", db, err))?;
+            let hash = local_hash(db, &bytes)?;
+
+            if let Some(state) = &state {
+                if state.etag == etag && state.hash == hash {
+                    println!("{} is up to date with {}", db, self.url());
+                    return Ok(());
+                }
+            }
+
+            let modified = match &state {
+                Some(state) => state.hash != hash,
+                None => true,
+            };
+            if modified && !force {
+                return Err(Error::Other(format!(
+                    "{} has local changes that haven't been pushed; use --force to overwrite them",
+                    db
+                )));
+            }
+        }
+
+        if dry_run {
+            println!("Would pull {} to {}", self.url(), db);
+            return Ok(());
+        }
+
+        // Download next to the db and rename, so an interrupted download can't leave a partial db
+        let partial = format!("{}.part", db);
+        self.download(&etag, size, &partial)?;
+
+        // Trees are unpacked first, so the db is only replaced once they match it
+        let downloaded = std::fs::read(&partial)
+            .map_err(|err| Error::io("read downloaded database", &partial, err))?;
+        let bytes = match unbundle(&downloaded) {
+            Some(None) => {
+                return Err(Error::Other(format!(
+                    "Downloaded {} is not a valid bundle of a db and its trees",
+                    self.url()
+                )))
+            }
+            Some(Some((bytes, files))) => {
+                self.unpack(db, &bytes, files)?;
+                std::fs::write(&partial, &bytes)
+                    .map_err(|err| Error::io("write", &partial, err))?;
+                bytes
+            }
+            None => downloaded,
+        };
+
+        std::fs::rename(&partial, db).map_err(|err| Error::io("replace", db, err))?;
+        let _ = std::fs::remove_file(format!("{}-etag", partial));
+
+        SyncState {
+            etag,
+            hash: local_hash(db, &bytes)?,
+        }
+        .save(db)?;
+        println!("Pulled {} to {}", self.url(), db);
+        Ok(())
+    }
+
+    // Replace the trees of a db with the files from a bundle
+    // Every file is checked first, then each tree is written next to its directory and only swapped in once all of
+    // them were written, so a bad or interrupted pull leaves the existing trees alone
+    fn unpack(&self, db: &str, bytes: &[u8], files: Vec<(String, Vec<u8>)>) -> Result<()> {
+        let trees = trees(db, bytes)?;
+        for (name, _) in &files {
+            let inside = trees
+                .iter()
+                .any(|tree| name.starts_with(&format!("{}/", tree)))
+                && Path::new(name)
+                    .components()
+                    .all(|component| matches!(component, Component::Normal(_)));
+            if !inside {
+                return Err(Error::Other(format!(
+                    "Downloaded {} has a file outside of the db's trees: {}",
+                    self.url(),
+                    name
+                )));
+            }
+        }
+
+        let staged = trees
+            .iter()
+            .map(|tree| {
+                let directory = tree_directory(db, tree);
+                let staging = PathBuf::from(format!("{}.part", directory.display()));
+                (directory, staging)
+            })
+            .collect::<Vec<_>>();
+        if let Err(err) = stage(&trees, &staged, &files) {
+            for (_, staging) in &staged {
+                let _ = std::fs::remove_dir_all(staging);
+            }
+            return Err(err);
+        }
+
+        for (directory, staging) in &staged {
+            let old = PathBuf::from(format!("{}.old", directory.display()));
+            remove_tree(&old)?;
+            match std::fs::rename(directory, &old) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(Error::io("move aside", directory.display(), err));
+                }
+                _ => {}
+            }
+            std::fs::rename(staging, directory)
+                .map_err(|err| Error::io("replace", directory.display(), err))?;
+            let _ = std::fs::remove_dir_all(&old);
+        }
+        Ok(())
+    }
+
+    // Download the remote object in chunks, appending to a partial file
+    // A partial file left by an earlier pull of the same ETag is continued rather than started over
+    fn download(&self, etag: &str, size: u64, partial: &str) -> Result<()> {
+        let marker = format!("{}-etag", partial);
+        let resume = std::fs::read_to_string(&marker).is_ok_and(|saved| saved.trim() == etag);
+
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(partial)
+            .map_err(|err| Error::io("create", partial, err))?;
+        if !resume {
+            file.set_len(0)
+                .map_err(|err| Error::io("truncate", partial, err))?;
+            std::fs::write(&marker, format!("{}\n", etag))
+                .map_err(|err| Error::io("write", &marker, err))?;
+        }
+
+        let mut offset = file
+            .metadata()
+            .map_err(|err| Error::io("read", partial, err))?
+            .len();
+        if offset > 0 {
+            log::info!("Resuming download of {} at byte {}", self.url(), offset);
+        }
+
+        let chunk = format!("{}.chunk", partial);
+        while offset < size {
+            let end = (offset + CHUNK_SIZE).min(size) - 1;
+            let range = format!("bytes={}-{}", offset, end);
+            self.s3api_text(
+                "get-object",
+                &[
+                    "--range",
+                    &range,
+                    "--if-match",
+                    etag,
+                    &chunk,
+                    "--query",
+                    "ETag",
+                    "--output",
+                    "text",
+                ],
+            )?;
+
+            let bytes = std::fs::read(&chunk).map_err(|err| Error::io("read", &chunk, err))?;
+            file.write_all(&bytes)
+                .map_err(|err| Error::io("write", partial, err))?;
+            offset += bytes.len() as u64;
+            log::debug!("Downloaded {} of {} bytes of {}", offset, size, self.url());
+        }
+
+        let _ = std::fs::remove_file(&chunk);
+        Ok(())
+    }
+
+    /// Upload the local db (and its trees), refusing to overwrite changes someone else pushed unless forced
+    pub fn push(&self, db: &str, force: bool, dry_run: bool) -> Result<()> {
+        if !path::Path::new(db).exists() {
+            return Err(Error::Db {
+                path: db.to_string(),
+                message: "does not exist".to_string(),
+            });
+        }
+
+        let bytes = std::fs::read(db).map_err(|err| Error::io("read database file", db, err))?;
+        let state = SyncState::load(db);
+        let hash = local_hash(db, &bytes)?;
+
+        let precondition = match (self.head()?, &state) {
+            (Some((etag, _)), Some(state)) if etag == state.etag && hash == state.hash => {
+                println!("{} is up to date with {}", db, self.url());
+                return Ok(());
+            }
+            _ if force => Precondition::Any,
+            (Some((etag, _)), state) if state.as_ref().map(|s| &s.etag) != Some(&etag) => {
+                return Err(Error::Other(format!(
+                    "{} has changed since it was last pulled; pull first or use --force to overwrite it",
+                    self.url()
+                )));
+            }
+            (Some((etag, _)), _) => Precondition::Matches(etag),
+            (None, _) => Precondition::Missing,
+        };
+
+        if dry_run {
+            println!("Would push {} to {}", db, self.url());
+            return Ok(());
+        }
+
+        // A db with trees is uploaded as a bundle of the db and its trees' files
+        let body = if let Some(files) = tree_files(db, &bytes)? {
+            let body = format!("{}.bundle", db);
+            std::fs::write(&body, bundle(&bytes, &files)?)
+                .map_err(|err| Error::io("write", &body, err))?;
+            body
+        } else {
+            db.to_string()
+        };
+
+        let size = std::fs::metadata(&body)
+            .map_err(|err| Error::io("read", &body, err))?
+            .len();
+        let etag = if size <= CHUNK_SIZE {
+            let mut args = vec!["--body", &body];
+            args.extend(precondition.args());
+            args.extend(["--query", "ETag", "--output", "text"]);
+            self.s3api_text("put-object", &args)
+        } else {
+            self.upload(db, &body, &precondition)
+        };
+        if body != db {
+            let _ = std::fs::remove_file(&body);
+        }
+        let etag = etag?;
+
+        SyncState { etag, hash }.save(db)?;
+        println!("Pushed {} to {}", db, self.url());
+        Ok(())
+    }
+
+    // Upload a large db (or bundle) as a multipart upload, completed only if the precondition still holds
+    // The upload id is remembered in `<db>.upload`, so parts uploaded by an interrupted push aren't sent again
+    fn upload(&self, db: &str, body: &str, precondition: &Precondition) -> Result<String> {
+        let marker = format!("{}.upload", db);
+        let body_hash = hash_file(Path::new(body))?;
+        let saved = std::fs::read_to_string(&marker).ok().and_then(|contents| {
+            let (id, hash) = contents.trim().split_once('\n')?;
+            (hash == body_hash).then(|| id.to_string())
+        });
+
+        let mut parts = BTreeMap::new();
+        let id = match saved {
+            Some(id) => {
+                let listed = self.s3api_text(
+                    "list-parts",
+                    &[
+                        "--upload-id",
+                        &id,
+                        "--query",
+                        "Parts[].[PartNumber, ETag]",
+                        "--output",
+                        "text",
+                    ],
+                )?;
+                for line in listed.lines() {
+                    if let Some((number, etag)) = line.split_once('\t') {
+                        if let Ok(number) = number.parse::<u64>() {
+                            parts.insert(number, etag.to_string());
+                        }
+                    }
+                }
+                log::info!(
+                    "Resuming upload of {} with {} parts already sent",
+                    db,
+                    parts.len()
+                );
+                id
+            }
+            None => {
+                let id = self.s3api_text(
+                    "create-multipart-upload",
+                    &["--query", "UploadId", "--output", "text"],
+                )?;
+                std::fs::write(&marker, format!("{}\n{}\n", id, body_hash))
+                    .map_err(|err| Error::io("write", &marker, err))?;
+                id
+            }
+        };
+
+        let mut file = std::fs::File::open(body).map_err(|err| Error::io("read", body, err))?;
+        let size = file
+            .metadata()
+            .map_err(|err| Error::io("read", body, err))?
+            .len();
+        let chunk = format!("{}.chunk", marker);
+        for number in 1..=size.div_ceil(CHUNK_SIZE) {
+            if parts.contains_key(&number) {
+                continue;
+            }
+
+            let mut bytes = Vec::new();
+            file.seek(SeekFrom::Start((number - 1) * CHUNK_SIZE))
+                .and_then(|_| (&mut file).take(CHUNK_SIZE).read_to_end(&mut bytes))
+                .map_err(|err| Error::io("read", body, err))?;
+            std::fs::write(&chunk, &bytes).map_err(|err| Error::io("write", &chunk, err))?;
+
+            let etag = self.s3api_text(
+                "upload-part",
+                &[
+                    "--upload-id",
+                    &id,
+                    "--part-number",
+                    &number.to_string(),
+                    "--body",
+                    &chunk,
+                    "--query",
+                    "ETag",
+                    "--output",
+                    "text",
+                ],
+            )?;
+            parts.insert(number, etag);
+            log::debug!("Uploaded part {} of {}", number, db);
+        }
+        let _ = std::fs::remove_file(&chunk);
+
+        let layout = serde_json::json!({
+            "Parts": parts
+                .iter()
+                .map(|(number, etag)| serde_json::json!({"PartNumber": number, "ETag": etag}))
+                .collect::<Vec<_>>(),
+        })
+        .to_string();
+        let mut args = vec!["--upload-id", &id, "--multipart-upload", &layout];
+        args.extend(precondition.args());
+        args.extend(["--query", "ETag", "--output", "text"]);
+        let etag = self.s3api_text("complete-multipart-upload", &args)?;
+
+        let _ = std::fs::remove_file(&marker);
+        Ok(etag)
+    }
+}
+
+impl Precondition {
+    fn args(&self) -> Vec<&str> {
+        match self {
+            Precondition::Any => vec![],
+            Precondition::Missing => vec!["--if-none-match", "*"],
+            Precondition::Matches(etag) => vec!["--if-match", etag],
+        }
+    }
+}
+
+// The trees (--snapshots and --db-dir) a db is stored with, relative to the db, from the contents of the db file
+// Each is normalized (without . or trailing slashes), and has to be a directory inside the db's directory
+fn trees(db: &str, bytes: &[u8]) -> Result<Vec<String>> {
+    let options = Db::from_bytes(bytes, db)?.options;
+    let mut trees: Vec<String> = Vec::new();
+    for tree in [options.snapshots, options.db_dir].into_iter().flatten() {
+        let path = Path::new(&tree);
+        let normalized = path
+            .components()
+            .filter_map(|component| match component {
+                Component::Normal(part) => Some(part.to_string_lossy()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join("/");
+        let inside = path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+        if !inside || normalized.is_empty() {
+            return Err(Error::Config(format!(
+                "Unable to sync {}: it must be inside the db's directory to be pushed or pulled",
+                tree
+            )));
+        }
+        // Each tree is replaced as a whole when pulling, so one can't be inside another
+        if let Some(other) = trees.iter().find(|other| {
+            normalized.starts_with(&format!("{}/", other))
+                || other.starts_with(&format!("{}/", normalized))
+        }) {
+            return Err(Error::Config(format!(
+                "Unable to sync {} and {}: one is inside the other",
+                other, normalized
+            )));
+        }
+        if !trees.contains(&normalized) {
+            trees.push(normalized);
+        }
+    }
+    Ok(trees)
+}
+
+// Write the files of a bundle into a fresh staging directory for each tree they're in
+fn stage(
+    trees: &[String],
+    staged: &[(PathBuf, PathBuf)],
+    files: &[(String, Vec<u8>)],
+) -> Result<()> {
+    for (_, staging) in staged {
+        remove_tree(staging)?;
+        std::fs::create_dir_all(staging)
+            .map_err(|err| Error::io("create directory", staging.display(), err))?;
+    }
+
+    for (name, contents) in files {
+        for (tree, (_, staging)) in trees.iter().zip(staged) {
+            let Some(relative) = name.strip_prefix(&format!("{}/", tree)) else {
+                continue;
+            };
+            let path = staging.join(relative);
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)
+                    .map_err(|err| Error::io("create directory", parent.display(), err))?;
+            }
+            std::fs::write(&path, contents)
+                .map_err(|err| Error::io("write", path.display(), err))?;
+        }
+    }
+    Ok(())
+}
+
+// Remove a directory left over from an earlier pull, if there is one
+fn remove_tree(directory: &Path) -> Result<()> {
+    match std::fs::remove_dir_all(directory) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+            Err(Error::io("remove", directory.display(), err))
+        }
+        _ => Ok(()),
+    }
+}
+
+// A tree's directory on disk
+fn tree_directory(db: &str, tree: &str) -> PathBuf {
+    Path::new(db).parent().unwrap_or(Path::new("")).join(tree)
+}
+
+// Every file in a db's trees, by its path relative to the db (in a stable order), None if the db has no trees
+fn tree_files(db: &str, bytes: &[u8]) -> Result<Option<Vec<(String, PathBuf)>>> {
+    let trees = trees(db, bytes)?;
+    if trees.is_empty() {
+        return Ok(None);
+    }
+
+    let mut files = Vec::new();
+    for tree in trees {
+        let directory = tree_directory(db, &tree);
+        let mut paths = Vec::new();
+        list(&directory, &mut paths);
+        paths.sort();
+        for path in paths {
+            let Some(relative) = path.strip_prefix(&directory).unwrap().to_str() else {
+                return Err(Error::Config(format!(
+                    "Unable to sync {}: file names must be valid UTF-8",
+                    path.display()
+                )));
+            };
+            files.push((format!("{}/{}", tree, relative), path));
+        }
+    }
+    Ok(Some(files))
+}
+
+// Hash the db along with every file in its trees, so changes to either count as local changes
+// Without trees this is just the hash of the db file
+fn local_hash(db: &str, bytes: &[u8]) -> Result<String> {
+    let Some(files) = tree_files(db, bytes)? else {
+        return Ok(hash_bytes(bytes));
+    };
+
+    let mut listing = format!("{}\n", hash_bytes(bytes));
+    for (name, path) in files {
+        listing += &format!("{} {}\n", name, hash_file(&path)?);
+    }
+    Ok(hash_bytes(listing.as_bytes()))
+}
+
+// Bundle a db with the files of its trees: the header, then each file as its path, its length, and its contents
+// The db itself comes first, with an empty path
+fn bundle(bytes: &[u8], files: &[(String, PathBuf)]) -> Result<Vec<u8>> {
+    let mut bundle = BUNDLE_HEADER.to_vec();
+    let mut append = |name: &str, contents: &[u8]| {
+        bundle.extend(format!("{}\n{}\n", name, contents.len()).as_bytes());
+        bundle.extend(contents);
+    };
+
+    append("", bytes);
+    for (name, path) in files {
+        let contents = std::fs::read(path).map_err(|err| Error::io("read", path.display(), err))?;
+        append(name, &contents);
+    }
+    Ok(bundle)
+}
+
+// Split a downloaded object into the db and the files of its trees
+// None if it's just a db, Some(None) if it's a bundle that can't be read
+fn unbundle(bytes: &[u8]) -> Option<Option<Unbundled>> {
+    let mut rest = bytes.strip_prefix(BUNDLE_HEADER)?;
+
+    let mut entries = Vec::new();
+    while !rest.is_empty() {
+        let Some((name, contents, after)) = entry(rest) else {
+            return Some(None);
+        };
+        entries.push((name, contents.to_vec()));
+        rest = after;
+    }
+
+    if entries.first().map(|(name, _)| name.as_str()) != Some("") {
+        return Some(None);
+    }
+    let (_, db) = entries.remove(0);
+    Some(Some((db, entries)))
+}
+
+// Read the next file in a bundle: its path, its contents, and everything after it
+fn entry(bytes: &[u8]) -> Option<(String, &[u8], &[u8])> {
+    let mut lines = bytes.splitn(3, |byte| *byte == b'\n');
+    let name = String::from_utf8(lines.next()?.to_vec()).ok()?;
+    let length: usize = std::str::from_utf8(lines.next()?).ok()?.parse().ok()?;
+    let rest = lines.next()?;
+    (rest.len() >= length).then(|| (name, &rest[..length], &rest[length..]))
+}
+
+// Every file under a directory, recursively
+fn list(directory: &Path, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = std::fs::read_dir(directory) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            list(&path, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
+
+impl SyncState {
+    fn path(db: &str) -> String {
+        format!("{}.sync", db)
+    }
+
+    fn load(db: &str) -> Option<SyncState> {
+        let contents = std::fs::read_to_string(SyncState::path(db)).ok()?;
+        let mut lines = contents.lines();
+        Some(SyncState {
+            etag: lines.next()?.to_string(),
+            hash: lines.next()?.to_string(),
+        })
+    }
+
+    fn save(&self, db: &str) -> Result<()> {
+        let path = SyncState::path(db);
+        std::fs::write(&path, format!("{}\n{}\n", self.etag, self.hash))
+            .map_err(|err| Error::io("write sync state", path, err))
+    }
+}
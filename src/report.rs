@@ -0,0 +1,406 @@
+// Reports written once a run is done, for CI systems and other tools
+use std::io::Write;
+
+use crate::diff;
+use crate::error::{self, Error};
+
+/// A kind of report to generate, given as --report <kind>
+#[derive(Debug, Clone)]
+pub enum Report {
+    /// GitHub Actions annotations for failures plus a job summary
+    Github,
+
+    /// A GitHub flavored markdown summary written to a file, such as for a PR comment
+    Markdown(String),
+}
+
+impl std::str::FromStr for Report {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            None if s == "github" => Ok(Report::Github),
+            Some(("markdown", path)) if !path.is_empty() => Ok(Report::Markdown(path.to_string())),
+            _ => Err(format!(
+                "unknown report: {} (expected github or markdown=<path>)",
+                s
+            )),
+        }
+    }
+}
+
+/// The result of a single test, as needed for reports
+pub struct Entry {
+    /// The file relative to the directory, as stored in the db
+    pub file: String,
+
+    /// The file as it was actually run (including the directory)
+    pub path: String,
+
+    pub status: &'static str,
+    pub output: String,
+
+    /// How long the test took in ms, if it ran
+    pub elapsed_ms: Option<u128>,
+
+    /// The fastest recorded success before this run, in ms, if any
+    pub fastest_ms: Option<u128>,
+
+    /// If the test took longer than --warn-timeout (but didn't time out)
+    pub slow: bool,
+
+    /// The most recently accepted output before this run, if any
+    pub expected: Option<String>,
+
+    /// Who owns the test, if known
+    pub owner: Option<String>,
+
+    /// If the test succeeded in the previous run, but failed or timed out in this one
+    pub regression: bool,
+
+    /// Resources the test used far more of than in its previous success (see suite::Comparison)
+    pub usage_regressions: Vec<String>,
+}
+
+impl Entry {
+    /// If the test failed, timed out, or couldn't be run at all
+    pub fn failed(&self) -> bool {
+        matches!(self.status, "failure" | "timeout" | "error")
+    }
+}
+
+/// How to order the list of failing tests, given as --sort <by>
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum SortBy {
+    /// By file name
+    Name,
+
+    /// Slowest first
+    Duration,
+
+    /// Errors, failures, then timeouts, then by file name
+    Status,
+}
+
+impl std::fmt::Display for SortBy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SortBy::Name => write!(f, "name"),
+            SortBy::Duration => write!(f, "duration"),
+            SortBy::Status => write!(f, "status"),
+        }
+    }
+}
+
+/// Counts of each kind of result
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub successes: usize,
+    pub new_successes: usize,
+    pub failures: usize,
+    pub timeouts: usize,
+    pub skipped: usize,
+    pub unchanged: usize,
+
+    /// Tests that couldn't be run at all (see TestOutcome::Error)
+    pub errors: usize,
+
+    /// Total time in ms spent on tests with each status
+    pub success_ms: u128,
+    pub failure_ms: u128,
+    pub timeout_ms: u128,
+
+    /// Suites with changes that weren't saved because of --dry-run
+    pub unsaved: usize,
+
+    /// Problems running tests (rather than test failures), see runner::Incident
+    pub incidents: usize,
+
+    /// Outputs (stdout or stderr) that weren't valid UTF-8, see Runner::encoding_notes
+    pub encoding_notes: usize,
+
+    /// Tests that weren't started because of --max-total-time
+    pub not_run: usize,
+
+    /// Successful tests that used far more resources than in their previous success
+    pub usage_regressions: usize,
+
+    /// Tests that took longer than --warn-timeout
+    pub slow: usize,
+
+    /// Tests with accepted outputs whose files are gone, and files without accepted outputs (see TestSuite::orphaned)
+    pub orphaned: usize,
+    pub unrecorded: usize,
+}
+
+impl Summary {
+    /// Add the counts from another summary to this one
+    pub fn add(&mut self, other: &Summary) {
+        self.successes += other.successes;
+        self.new_successes += other.new_successes;
+        self.failures += other.failures;
+        self.timeouts += other.timeouts;
+        self.errors += other.errors;
+        self.skipped += other.skipped;
+        self.unchanged += other.unchanged;
+        self.success_ms += other.success_ms;
+        self.failure_ms += other.failure_ms;
+        self.timeout_ms += other.timeout_ms;
+        self.unsaved += other.unsaved;
+        self.incidents += other.incidents;
+        self.encoding_notes += other.encoding_notes;
+        self.not_run += other.not_run;
+        self.usage_regressions += other.usage_regressions;
+        self.slow += other.slow;
+        self.orphaned += other.orphaned;
+        self.unrecorded += other.unrecorded;
+    }
+
+    /// Each status with its count and total time in ms (if tests with that status take time), in summary order
+    pub fn rows(&self) -> Vec<(&'static str, usize, Option<u128>)> {
+        vec![
+            ("success", self.successes, Some(self.success_ms)),
+            ("failure", self.failures, Some(self.failure_ms)),
+            ("timeout", self.timeouts, Some(self.timeout_ms)),
+            ("error", self.errors, None),
+            ("skipped", self.skipped, None),
+            ("unchanged", self.unchanged, None),
+            ("not_run", self.not_run, None),
+        ]
+    }
+}
+
+/// The failures, timeouts, and errors, in the given order
+pub fn failing(entries: &[Entry], sort: SortBy) -> Vec<&Entry> {
+    let mut failing = entries
+        .iter()
+        .filter(|entry| entry.failed())
+        .collect::<Vec<_>>();
+
+    match sort {
+        SortBy::Name => failing.sort_by(|a, b| a.file.cmp(&b.file)),
+        SortBy::Duration => failing.sort_by(|a, b| {
+            b.elapsed_ms
+                .cmp(&a.elapsed_ms)
+                .then_with(|| a.file.cmp(&b.file))
+        }),
+        SortBy::Status => {
+            failing.sort_by(|a, b| a.status.cmp(b.status).then_with(|| a.file.cmp(&b.file)))
+        }
+    }
+    failing
+}
+
+/// Write the summary table and the failing tests (in the given order) to a JSON file
+pub fn summary_json(
+    path: &str,
+    entries: &[Entry],
+    summary: &Summary,
+    sort: SortBy,
+) -> error::Result<()> {
+    let statuses = summary
+        .rows()
+        .into_iter()
+        .map(|(status, count, total_ms)| {
+            serde_json::json!({
+                "status": status,
+                "count": count,
+                "total_ms": total_ms,
+            })
+        })
+        .collect::<Vec<_>>();
+    let failing = failing(entries, sort)
+        .into_iter()
+        .map(|entry| {
+            serde_json::json!({
+                "file": entry.file,
+                "status": entry.status,
+                "elapsed_ms": entry.elapsed_ms,
+                "owner": entry.owner,
+                "regression": entry.regression,
+                "slow": entry.slow,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let json = serde_json::json!({
+        "statuses": statuses,
+        "new_successes": summary.new_successes,
+        "incidents": summary.incidents,
+        "encoding_notes": summary.encoding_notes,
+        "usage_regressions": summary.usage_regressions,
+        "slow": summary.slow,
+        "orphaned": summary.orphaned,
+        "unrecorded": summary.unrecorded,
+        "unsaved": summary.unsaved,
+        "failing": failing,
+    });
+
+    std::fs::write(path, serde_json::to_string_pretty(&json).unwrap())
+        .map_err(|err| Error::io("write summary", path, err))
+}
+
+/// Write each requested report
+pub fn write(reports: &[Report], entries: &[Entry], summary: &Summary) -> error::Result<()> {
+    for report in reports {
+        match report {
+            Report::Github => github(entries, summary)?,
+            Report::Markdown(path) => markdown(path, entries, summary)?,
+        }
+    }
+    Ok(())
+}
+
+// Annotations are printed to stdout; the summary is appended to $GITHUB_STEP_SUMMARY if set
+fn github(entries: &[Entry], summary: &Summary) -> error::Result<()> {
+    for entry in entries {
+        if entry.failed() {
+            println!(
+                "::error file={},title=testit {}::{}",
+                github_escape_property(&entry.path),
+                entry.status,
+                github_escape(&format!(
+                    "{}: {}{}\n{}",
+                    entry.file,
+                    entry.status,
+                    entry
+                        .owner
+                        .as_ref()
+                        .map_or(String::new(), |owner| format!(" (owner: {})", owner)),
+                    entry.output
+                ))
+            );
+        }
+    }
+
+    let Ok(path) = std::env::var("GITHUB_STEP_SUMMARY") else {
+        return Ok(());
+    };
+
+    let mut markdown = String::new();
+    markdown.push_str("## testit\n\n");
+    markdown.push_str(&counts_table(summary));
+
+    let failing = entries
+        .iter()
+        .filter(|entry| entry.failed())
+        .collect::<Vec<_>>();
+    if !failing.is_empty() {
+        markdown.push_str("\n| File | Status | Owner |\n| --- | --- | --- |\n");
+        for entry in failing {
+            markdown.push_str(&format!(
+                "| `{}` | {} | {} |\n",
+                entry.file,
+                entry.status,
+                entry.owner.as_deref().unwrap_or("")
+            ));
+        }
+    }
+
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut f| writeln!(f, "{}", markdown))
+        .map_err(|err| Error::io("write $GITHUB_STEP_SUMMARY", path, err))
+}
+
+// A summary table of failures, then a collapsible diff against the accepted output for each
+fn markdown(path: &str, entries: &[Entry], summary: &Summary) -> error::Result<()> {
+    let mut markdown = String::new();
+    markdown.push_str("## testit\n\n");
+    markdown.push_str(&counts_table(summary));
+
+    let failing = entries
+        .iter()
+        .filter(|entry| entry.failed())
+        .collect::<Vec<_>>();
+    if !failing.is_empty() {
+        markdown.push_str("\n| File | Status | Owner |\n| --- | --- | --- |\n");
+        for entry in failing.iter() {
+            markdown.push_str(&format!(
+                "| `{}` | {}{} | {} |\n",
+                entry.file,
+                entry.status,
+                if entry.regression {
+                    " (regression)"
+                } else {
+                    ""
+                },
+                entry.owner.as_deref().unwrap_or("")
+            ));
+        }
+
+        markdown.push_str("\n### Failures\n");
+        for entry in failing {
+            markdown.push_str(&format!(
+                "\n<details>\n<summary><code>{}</code> ({})</summary>\n\n",
+                html_escape(&entry.file),
+                entry.status
+            ));
+            match &entry.expected {
+                Some(expected) if entry.status == "failure" => {
+                    markdown.push_str(&code_block("diff", &diff::unified(expected, &entry.output)))
+                }
+                _ if !entry.output.is_empty() => markdown.push_str(&code_block("", &entry.output)),
+                _ => markdown.push_str("No output\n"),
+            }
+            markdown.push_str("\n</details>\n");
+        }
+    }
+
+    std::fs::write(path, markdown).map_err(|err| Error::io("write markdown report", path, err))
+}
+
+// A markdown table with the count of each kind of result
+fn counts_table(summary: &Summary) -> String {
+    let mut table = String::new();
+    table.push_str("| Status | Count |\n| --- | --- |\n");
+    table.push_str(&format!(
+        "| Successes | {} ({} new) |\n",
+        summary.successes, summary.new_successes
+    ));
+    table.push_str(&format!("| Failures | {} |\n", summary.failures));
+    table.push_str(&format!("| Timeouts | {} |\n", summary.timeouts));
+    if summary.errors > 0 {
+        table.push_str(&format!("| Errors | {} |\n", summary.errors));
+    }
+    table.push_str(&format!("| Skipped | {} |\n", summary.skipped));
+    table.push_str(&format!("| Unchanged | {} |\n", summary.unchanged));
+    table
+}
+
+// A fenced code block, with a fence longer than any run of backticks in the content
+fn code_block(language: &str, content: &str) -> String {
+    let mut longest = 0;
+    let mut run = 0;
+    for c in content.chars() {
+        run = if c == '`' { run + 1 } else { 0 };
+        longest = longest.max(run);
+    }
+    let fence = "`".repeat(longest.max(2) + 1);
+
+    let newline = if content.ends_with('\n') { "" } else { "\n" };
+    format!("{}{}\n{}{}{}\n", fence, language, content, newline, fence)
+}
+
+// Text inside html tags (like the summary of a details block) can't contain markup
+fn html_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+// Workflow commands are line based, so messages need newlines (and %) escaped
+fn github_escape(value: &str) -> String {
+    value
+        .replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+// Properties additionally can't contain the separators
+fn github_escape_property(value: &str) -> String {
+    github_escape(value).replace(':', "%3A").replace(',', "%2C")
+}
@@ -0,0 +1,109 @@
+// Limiting how many tests using a named resource (like a fixed port or a GPU) run at once (see --resource)
+// Tests wait for every resource they use at once (or none), so tests using several can't deadlock each other
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::{Condvar, Mutex};
+
+use crate::options::Options;
+
+/// How many tests can use a resource at once, unless set with --resource-limit
+pub const DEFAULT_LIMIT: usize = 1;
+
+/// The resources used by each test in a suite, and how many tests are using each right now
+pub struct Resources {
+    patterns: Vec<(glob::Pattern, String)>,
+    limits: BTreeMap<String, usize>,
+    in_use: Mutex<BTreeMap<String, usize>>,
+    released: Condvar,
+}
+
+/// Resources held by a running test, released when dropped
+pub struct Held<'a> {
+    resources: &'a Resources,
+    names: Vec<String>,
+}
+
+impl Resources {
+    /// Load the resources and limits from a suite's (already validated) options
+    pub fn new(options: &Options) -> Resources {
+        let patterns = options
+            .resources
+            .iter()
+            .map(|entry| {
+                let (pattern, resource) = entry.rsplit_once('=').unwrap();
+                (glob::Pattern::new(pattern).unwrap(), resource.to_string())
+            })
+            .collect();
+        let limits = options
+            .resource_limits
+            .iter()
+            .map(|entry| {
+                let (resource, limit) = entry.rsplit_once('=').unwrap();
+                (resource.to_string(), limit.parse().unwrap())
+            })
+            .collect();
+
+        Resources {
+            patterns,
+            limits,
+            in_use: Mutex::new(BTreeMap::new()),
+            released: Condvar::new(),
+        }
+    }
+
+    /// The resources a test (relative to the directory) uses, in order without duplicates
+    pub fn of(&self, file: &Path) -> Vec<String> {
+        let mut names = self
+            .patterns
+            .iter()
+            .filter(|(pattern, _)| pattern.matches_path(file))
+            .map(|(_, resource)| resource.clone())
+            .collect::<Vec<_>>();
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    /// Wait until every resource a test (relative to the directory) uses is available, then hold them
+    pub fn acquire(&self, file: &Path) -> Held<'_> {
+        let names = self.of(file);
+        if !names.is_empty() {
+            let mut in_use = self.in_use.lock().unwrap();
+            while names
+                .iter()
+                .any(|name| in_use.get(name).copied().unwrap_or(0) >= self.limit(name))
+            {
+                log::debug!("{}: waiting for {}", file.display(), names.join(", "));
+                in_use = self.released.wait(in_use).unwrap();
+            }
+            for name in names.iter() {
+                *in_use.entry(name.clone()).or_default() += 1;
+            }
+        }
+
+        Held {
+            resources: self,
+            names,
+        }
+    }
+
+    fn limit(&self, name: &str) -> usize {
+        self.limits.get(name).copied().unwrap_or(DEFAULT_LIMIT)
+    }
+}
+
+impl Drop for Held<'_> {
+    fn drop(&mut self) {
+        if self.names.is_empty() {
+            return;
+        }
+
+        let mut in_use = self.resources.in_use.lock().unwrap();
+        for name in self.names.iter() {
+            if let Some(count) = in_use.get_mut(name) {
+                *count -= 1;
+            }
+        }
+        self.resources.released.notify_all();
+    }
+}
@@ -0,0 +1,1859 @@
+// Running a suite's command against each of its input files
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Mutex};
+use std::time::{Duration, Instant};
+
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use rayon_progress::ProgressAdaptor;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::db::{Status, Usage};
+use crate::error::{self, Error};
+use crate::events::{Event, EventStream};
+use crate::fixtures;
+use crate::hash::{hash_bytes, hash_file};
+use crate::log_file;
+use crate::options::{InputMode, OutputEncoding, Sandbox, StreamMode, TestCommand};
+use crate::resources::Resources;
+use crate::suite::TestSuite;
+
+/// The outcome of running a single test, before it's compared against the db
+#[derive(Debug, Serialize, Deserialize)]
+pub enum TestOutcome {
+    /// stdout, stderr, how long it took in ms, the resources it used, and the hash of its input if it was preprocessed
+    /// (see --preprocess), since that's what the command saw
+    Success(String, String, u128, Usage, Option<String>),
+
+    /// stdout and stderr
+    Failure(String, String),
+
+    /// Which limit was hit
+    Timeout(Limit),
+
+    /// Why the test couldn't be run at all, such as an input that can't be opened or a command that can't be started
+    Error(String),
+
+    Skipped,
+    Unchanged,
+
+    /// Not started because the run's time budget ran out (see --max-total-time)
+    NotRun,
+}
+
+impl TestOutcome {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TestOutcome::Success(..) => "success",
+            TestOutcome::Failure(..) => "failure",
+            TestOutcome::Timeout(_) => "timeout",
+            TestOutcome::Error(_) => "error",
+            TestOutcome::Skipped => "skipped",
+            TestOutcome::Unchanged => "unchanged",
+            TestOutcome::NotRun => "not_run",
+        }
+    }
+
+    /// The status to remember for this outcome; unchanged and unrun tests keep their previous status
+    pub fn status(&self) -> Option<Status> {
+        match self {
+            TestOutcome::Success(..) => Some(Status::Success),
+            TestOutcome::Failure(..) => Some(Status::Failure),
+            TestOutcome::Timeout(_) => Some(Status::Timeout),
+            TestOutcome::Error(_) => Some(Status::Error),
+            TestOutcome::Skipped => Some(Status::Skipped),
+            TestOutcome::Unchanged | TestOutcome::NotRun => None,
+        }
+    }
+}
+
+/// The limit a test ran out of time against
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Limit {
+    /// The wall clock timeout (see --timeout)
+    WallClock,
+
+    /// The CPU time limit (see --cpu-limit)
+    Cpu,
+}
+
+impl Limit {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Limit::WallClock => "wall_clock",
+            Limit::Cpu => "cpu",
+        }
+    }
+}
+
+/// How far under --cpu-limit a test's measured CPU time can be and still count as hitting it, since rusage is
+/// sampled and can come in a few ms under the limit the kernel enforced
+const CPU_LIMIT_SLACK_MS: u64 = 100;
+
+/// How long to wait for a test's output pipes to close after it exits
+/// Longer than this means something else (usually a background process) is holding them open
+const PIPE_GRACE: Duration = Duration::from_secs(5);
+
+/// How long a timed out test gets to clean up after SIGTERM before anything left is sent SIGKILL
+const KILL_GRACE: Duration = Duration::from_secs(2);
+
+/// Environment variables pinned by reproducible runs, so output doesn't depend on the machine's locale or timezone
+const REPRODUCIBLE_ENV: &[(&str, &str)] = &[("LANG", "C"), ("LC_ALL", "C"), ("TZ", "UTC")];
+
+/// Environment variables set for each test unless the environment is preserved (or they're set with -e), so tools that
+/// mistake the pipe for a terminal don't color their output
+const NO_COLOR_ENV: &[(&str, &str)] = &[("NO_COLOR", "1"), ("TERM", "dumb")];
+
+/// What redacted text in a test's output is replaced with (see --redact-env and --redact-pattern)
+pub const REDACTED: &str = "***";
+
+/// Something that went wrong with running a test itself, rather than the test failing
+#[derive(Debug, Clone)]
+pub struct Incident {
+    pub file: PathBuf,
+    pub description: String,
+}
+
+/// Runs the tests in a suite (in parallel) with the suite's options
+pub struct Runner<'a> {
+    suite: &'a TestSuite,
+    // Unique to each runner in this process, so their tests' temporary directories can't collide
+    id: usize,
+    env: BTreeMap<String, String>,
+    skip: Vec<glob::Pattern>,
+    serial: Vec<glob::Pattern>,
+    resources: Resources,
+    incremental: bool,
+    failed_first: bool,
+    update_fixtures: bool,
+    keep_failed_tmp: bool,
+    reproducible: bool,
+    stream: bool,
+    deadline: Option<Instant>,
+    failed: Option<&'a AtomicBool>,
+    events: Option<&'a EventStream>,
+    incidents: Mutex<Vec<Incident>>,
+    // Notes on tests whose output wasn't valid UTF-8, by their path
+    encoding_notes: Mutex<Vec<(PathBuf, String)>>,
+    // Temporary directories kept for failing tests (see --keep-failed-tmp), by their path
+    kept_tmpdirs: Mutex<Vec<(PathBuf, PathBuf)>>,
+    // How long each test that ran took in ms, by its path, including failures and timeouts
+    durations: Mutex<BTreeMap<PathBuf, u128>>,
+    // Each file's (1 based) position in the current run, by its path relative to the directory, for {index}
+    positions: Mutex<BTreeMap<PathBuf, usize>>,
+}
+
+impl<'a> Runner<'a> {
+    /// Create a runner for a suite; the suite's options should already have defaults applied
+    pub fn new(suite: &'a TestSuite) -> error::Result<Runner<'a>> {
+        let skip = suite
+            .db
+            .options
+            .skip
+            .iter()
+            .map(|pattern| glob::Pattern::new(pattern).unwrap())
+            .collect();
+        let serial = suite
+            .db
+            .options
+            .serial
+            .iter()
+            .map(|pattern| glob::Pattern::new(pattern).unwrap())
+            .collect();
+
+        // Env values can refer to each other (or the parent environment)
+        // Options are validated before running, so this only fails if the env file changed since
+        let env = suite.db.options.env_vars()?;
+        let mut env = env
+            .iter()
+            .map(|(key, value)| (key.clone(), interpolate(value, &env)))
+            .collect::<BTreeMap<_, _>>();
+        if !suite.db.options.preserve_env.unwrap() {
+            no_color(&mut env);
+        }
+
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+        Ok(Runner {
+            suite,
+            id: NEXT.fetch_add(1, Ordering::Relaxed),
+            env,
+            skip,
+            serial,
+            resources: Resources::new(&suite.db.options),
+            incremental: false,
+            failed_first: false,
+            update_fixtures: false,
+            keep_failed_tmp: false,
+            reproducible: false,
+            stream: false,
+            deadline: None,
+            failed: None,
+            events: None,
+            incidents: Mutex::new(Vec::new()),
+            encoding_notes: Mutex::new(Vec::new()),
+            kept_tmpdirs: Mutex::new(Vec::new()),
+            durations: Mutex::new(BTreeMap::new()),
+            positions: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    /// Don't rerun tests that already passed with the same inputs
+    pub fn incremental(mut self, incremental: bool) -> Self {
+        self.incremental = incremental;
+        self
+    }
+
+    /// Run the tests that failed or timed out in their last recorded run first, as a (parallel) batch of their own
+    pub fn failed_first(mut self, failed_first: bool) -> Self {
+        self.failed_first = failed_first;
+        self
+    }
+
+    /// Replace fixture trees that don't match with the actual output instead of failing
+    pub fn update_fixtures(mut self, update_fixtures: bool) -> Self {
+        self.update_fixtures = update_fixtures;
+        self
+    }
+
+    /// Keep the temporary directory ($TESTIT_TMPDIR) of each test that fails, timed out, or had an error
+    pub fn keep_failed_tmp(mut self, keep_failed_tmp: bool) -> Self {
+        self.keep_failed_tmp = keep_failed_tmp;
+        self
+    }
+
+    /// The db (if any) the suite was loaded from, given to every command and hook as $TESTIT_DB
+    pub fn db(mut self, path: Option<&str>) -> Self {
+        if let Some(path) = path {
+            let path = std::path::absolute(path).unwrap_or_else(|_| PathBuf::from(path));
+            self.env
+                .entry("TESTIT_DB".to_string())
+                .or_insert_with(|| path.display().to_string());
+        }
+        self
+    }
+
+    /// Run hermetically (ignoring --preserve-env) with a pinned locale and timezone, one test at a time
+    pub fn reproducible(mut self, reproducible: bool) -> Self {
+        self.reproducible = reproducible;
+        if reproducible {
+            no_color(&mut self.env);
+            self.env.extend(
+                REPRODUCIBLE_ENV
+                    .iter()
+                    .map(|(key, value)| (key.to_string(), value.to_string())),
+            );
+        }
+        self
+    }
+
+    /// Echo each line of output (that would be printed) as it's written, prefixed by the file name
+    pub fn stream(mut self, stream: bool) -> Self {
+        self.stream = stream;
+        self
+    }
+
+    /// Don't start any tests after this time, leaving them not run
+    pub fn deadline(mut self, deadline: Option<Instant>) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    /// Stop starting tests once any has failed or timed out, skipping the rest (see --fail-fast)
+    /// The flag is set on the first failure, and can be shared between runners to stop later suites too
+    pub fn fail_fast(mut self, failed: Option<&'a AtomicBool>) -> Self {
+        self.failed = failed;
+        self
+    }
+
+    /// The environment variables set for each test (after interpolation)
+    pub fn env(&self) -> &BTreeMap<String, String> {
+        &self.env
+    }
+
+    /// The environment variables set for a single test (relative to the directory), including its test env
+    /// Test env values can refer to the suite's env; with --input-mode env, $TESTIT_INPUT is the file
+    /// $TESTIT_FILE, $TESTIT_INDEX, and $TESTIT_TOTAL are always set, like the {file}, {index}, and {total} placeholders,
+    /// along with $TESTIT_TMPDIR (see tmpdir)
+    /// Placeholders like {stem} are replaced in every value (see placeholders)
+    pub fn test_env(&self, file: &Path) -> BTreeMap<String, String> {
+        let mut env = self.env.clone();
+        if let Some(test_env) = self.suite.db.options.test_env.get(file.to_str().unwrap()) {
+            env.extend(
+                test_env
+                    .iter()
+                    .map(|(key, value)| (key.clone(), interpolate(value, &self.env))),
+            );
+        }
+
+        let placeholders = self.placeholders(file);
+        for value in env.values_mut() {
+            *value = substitute(value, &placeholders, false);
+        }
+
+        // A test env can still set its own
+        if self.suite.db.options.input_mode == Some(InputMode::Env) {
+            env.entry("TESTIT_INPUT".to_string())
+                .or_insert_with(|| self.input_path(file));
+        }
+        env.entry("TESTIT_FILE".to_string())
+            .or_insert_with(|| self.input_path(file));
+        env.entry("TESTIT_TMPDIR".to_string())
+            .or_insert_with(|| self.tmpdir(file).display().to_string());
+        for (key, name) in [("TESTIT_INDEX", "index"), ("TESTIT_TOTAL", "total")] {
+            if let Some((_, value)) = placeholders
+                .iter()
+                .find(|(placeholder, _)| *placeholder == name)
+            {
+                env.entry(key.to_string()).or_insert_with(|| value.clone());
+            }
+        }
+        env
+    }
+
+    /// The values of the placeholders for a file (relative to the directory) in commands and env values:
+    /// - {file}: the file
+    /// - {basename}: its file name
+    /// - {stem}: its file name without the extension
+    /// - {dir}: its directory (. if none)
+    /// - {ext}: its extension, without the dot (empty if none)
+    /// - {index}: its (1 based) position in this run
+    /// - {total}: how many files are in this run
+    /// - {variant}: the name of the variant it's run with (see --matrix), or empty if none
+    pub fn placeholders(&self, file: &Path) -> Vec<(&'static str, String)> {
+        let positions = self.positions.lock().unwrap();
+        let name = |part: Option<&std::ffi::OsStr>| {
+            part.map(|part| part.to_string_lossy().into_owned())
+                .unwrap_or_default()
+        };
+        let dir = file
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .map(|parent| parent.display().to_string())
+            .unwrap_or_else(|| ".".to_string());
+
+        vec![
+            ("file", file.display().to_string()),
+            ("basename", name(file.file_name())),
+            ("stem", name(file.file_stem())),
+            ("dir", dir),
+            ("ext", name(file.extension())),
+            (
+                "index",
+                positions.get(file).copied().unwrap_or(1).to_string(),
+            ),
+            ("total", positions.len().max(1).to_string()),
+            (
+                "variant",
+                self.suite
+                    .variant
+                    .as_ref()
+                    .map(|variant| variant.name.clone())
+                    .unwrap_or_default(),
+            ),
+        ]
+    }
+
+    // If tests should see the environment testit was run with
+    fn preserve_env(&self) -> bool {
+        !self.reproducible && self.suite.db.options.preserve_env.unwrap()
+    }
+
+    /// Send events for each test to this stream
+    pub fn events(mut self, events: &'a EventStream) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Incidents from the tests run so far, such as output pipes held open after the command exited
+    pub fn incidents(&self) -> Vec<Incident> {
+        self.incidents.lock().unwrap().clone()
+    }
+
+    /// Notes on the tests run so far whose stdout or stderr wasn't valid UTF-8, and how it was handled (see --output-encoding)
+    pub fn encoding_notes(&self) -> Vec<(PathBuf, String)> {
+        self.encoding_notes.lock().unwrap().clone()
+    }
+
+    /// The temporary directories kept for the tests run so far that failed (see --keep-failed-tmp), by their path
+    pub fn kept_tmpdirs(&self) -> Vec<(PathBuf, PathBuf)> {
+        self.kept_tmpdirs.lock().unwrap().clone()
+    }
+
+    /// How long each test run so far took in ms (successes, failures, and timeouts), by its path
+    pub fn durations(&self) -> BTreeMap<PathBuf, u128> {
+        self.durations.lock().unwrap().clone()
+    }
+
+    fn emit(&self, event: Event) {
+        if let Some(events) = self.events {
+            events.emit(event);
+        }
+    }
+
+    // A test that couldn't be run at all, so the rest of the run can go on without it
+    fn error(&self, file: &Path, reason: String) -> TestOutcome {
+        log::warn!(
+            event = "test_finished",
+            file = path_str(file),
+            status = "error";
+            "{}: {}", file.display(), reason
+        );
+        TestOutcome::Error(reason)
+    }
+
+    // If the time budget (if any) has run out
+    fn past_deadline(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    // If a test has failed with --fail-fast
+    fn failed_fast(&self) -> bool {
+        self.failed
+            .is_some_and(|failed| failed.load(Ordering::SeqCst))
+    }
+
+    /// Run every file in parallel, returning outcomes in the same order as the files
+    /// If the setup hook fails, no tests are run and this is an error; the teardown hook isn't run either,
+    /// since there's nothing to tear down (a setup hook should clean up after itself when it fails)
+    pub fn run(&self, files: &[PathBuf]) -> error::Result<Vec<TestOutcome>> {
+        // Out of time or already failed before starting (such as a later suite), so don't run the hooks either
+        if self.past_deadline() {
+            log::info!("Out of time, not running {} files", files.len());
+            return Ok(files.iter().map(|_| TestOutcome::NotRun).collect());
+        }
+        if self.failed_fast() {
+            log::info!("Already failed, skipping {} files", files.len());
+            return Ok(files.iter().map(|_| TestOutcome::Skipped).collect());
+        }
+
+        self.set_positions(files);
+
+        // Progress adaptor
+        let it = ProgressAdaptor::new(files);
+        let progress = it.items_processed();
+        let total = files.len();
+        let timeout = self.suite.db.options.timeout.unwrap();
+        let start = std::time::Instant::now();
+
+        self.emit(Event::RunStarted { total });
+
+        if let Some(setup) = &self.suite.db.options.setup {
+            if let Err(error) = self.run_hook("setup", setup, None) {
+                return Err(Error::Other(format!(
+                    "{}\nNot running any tests, since the setup hook failed",
+                    error
+                )));
+            }
+        }
+
+        // Additional thread that displays progress over time
+        std::thread::spawn(move || {
+            let mut last_progress = 0;
+            let mut last_print = std::time::Instant::now();
+            let mut delay = 1000;
+
+            loop {
+                std::thread::sleep(Duration::from_millis(1000));
+
+                let new_progress = progress.get();
+                let time_spent = start.elapsed().as_secs();
+
+                // Stop once every file is done, the next suite (if any) gets its own thread
+                if new_progress >= total {
+                    break;
+                }
+
+                if new_progress != last_progress {
+                    // Made progress, reset delay
+                    log::debug!(
+                        "Progress: {}/{} files, {}/{} sec (max)",
+                        new_progress,
+                        total,
+                        time_spent,
+                        timeout
+                    );
+                    last_print = std::time::Instant::now();
+                    delay = 1000;
+                    last_progress = new_progress;
+                } else if last_print.elapsed().as_millis() > delay {
+                    // Met delay, print and increment delay
+                    log::debug!(
+                        "Progress: {}/{} files, {}/{} sec (max)",
+                        new_progress,
+                        total,
+                        time_spent,
+                        timeout
+                    );
+                    last_print = std::time::Instant::now();
+                    delay = 30000.min(delay * 2);
+                }
+            }
+        });
+
+        // Reproducible runs go one at a time, so tests can't affect each other's timing or shared state
+        // Tests that failed last time go first (see --failed-first), serial tests are left for after everything else (see --serial)
+        let run = || {
+            let mut outcomes = files
+                .par_iter()
+                .map(|file| self.runs_first(file).then(|| self.run_scheduled(file)))
+                .collect::<Vec<_>>();
+            let rest = it
+                .map(|file| {
+                    (!self.runs_first(file) && !self.is_serial(file))
+                        .then(|| self.run_scheduled(file))
+                })
+                .collect::<Vec<_>>();
+            for (outcome, rest) in outcomes.iter_mut().zip(rest) {
+                if rest.is_some() {
+                    *outcome = rest;
+                }
+            }
+            outcomes
+        };
+        let outcomes = if self.reproducible {
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(1)
+                .build()
+                .unwrap()
+                .install(run)
+        } else {
+            run()
+        };
+
+        // Then the serial tests, one at a time and in order
+        let outcomes = files
+            .iter()
+            .zip(outcomes)
+            .map(|(file, outcome)| outcome.unwrap_or_else(|| self.run_scheduled(file)))
+            .collect();
+
+        if let Some(teardown) = &self.suite.db.options.teardown {
+            if let Err(error) = self.run_hook("teardown", teardown, None) {
+                eprintln!("{}", error);
+            }
+        }
+
+        Ok(outcomes)
+    }
+
+    // Run a file as part of a run: unless the run is out of time (or already failed), wait for its resources, run it,
+    // and record how it went
+    fn run_scheduled(&self, file: &Path) -> TestOutcome {
+        // Tests that already started finish, but no new ones start once the time is up
+        if self.past_deadline() {
+            return TestOutcome::NotRun;
+        }
+        if self.failed_fast() {
+            return TestOutcome::Skipped;
+        }
+
+        // Wait for any resources the test uses, holding them until it's done
+        let _held = self.resources.acquire(self.suite.relative_path(file));
+
+        let started = Instant::now();
+        let outcome = self.run_test(file);
+        let elapsed_ms = match outcome {
+            TestOutcome::Success(_, _, elapsed_ms, ..) => Some(elapsed_ms),
+            TestOutcome::Failure(..) | TestOutcome::Timeout(_) => {
+                Some(started.elapsed().as_millis())
+            }
+            _ => None,
+        };
+        if let Some(elapsed_ms) = elapsed_ms {
+            self.durations
+                .lock()
+                .unwrap()
+                .insert(file.to_path_buf(), elapsed_ms);
+        }
+        if let Some(failed) = self.failed {
+            if matches!(
+                outcome,
+                TestOutcome::Failure(..) | TestOutcome::Timeout(_) | TestOutcome::Error(_)
+            ) && !failed.swap(true, Ordering::SeqCst)
+            {
+                log::warn!(
+                    "{}: {}, skipping the remaining tests (--fail-fast)",
+                    self.suite.key(file),
+                    outcome.name()
+                );
+            }
+        }
+        self.emit(Event::TestFinished {
+            file: &self.suite.key(file),
+            status: outcome.name(),
+            elapsed_ms: match outcome {
+                TestOutcome::Success(_, _, elapsed_ms, ..) => Some(elapsed_ms),
+                _ => None,
+            },
+            limit: match outcome {
+                TestOutcome::Timeout(limit) => Some(limit.name()),
+                _ => None,
+            },
+            reason: match &outcome {
+                TestOutcome::Error(reason) => Some(reason),
+                _ => None,
+            },
+        });
+        outcome
+    }
+
+    // If a file should run before the others, since it failed or timed out last time (see --failed-first)
+    // Serial tests stay serial
+    fn runs_first(&self, file: &Path) -> bool {
+        self.failed_first
+            && !self.is_serial(file)
+            && matches!(
+                self.suite.db.status.get(&self.suite.key(file)),
+                Some(Status::Failure | Status::Timeout | Status::Error)
+            )
+    }
+
+    // If a file should run on its own after the parallel tests (see --serial)
+    fn is_serial(&self, file: &Path) -> bool {
+        let relative = self.suite.relative_path(file);
+        self.serial
+            .iter()
+            .any(|pattern| pattern.matches_path(relative))
+    }
+
+    /// Files that have changed since their results were recorded (with any variant)
+    /// Preprocessed inputs (see --preprocess) are preprocessed to hash them, in parallel
+    pub fn changed_inputs<'b>(&self, files: &'b [PathBuf]) -> Vec<&'b PathBuf> {
+        let variants = self.suite.db.options.variants();
+        files
+            .par_iter()
+            .filter(|file| {
+                let expected = variants.iter().find_map(|variant| {
+                    self.suite
+                        .db
+                        .hashes
+                        .get(&self.suite.variant_key(file, variant.as_ref()))
+                });
+                match expected {
+                    Some(expected) => self.input_hash(file).as_ref() != Some(expected),
+                    None => false,
+                }
+            })
+            .collect()
+    }
+
+    // Hash an input as the command sees it: what the preprocess command outputs for it if there is one, or the file itself
+    // If preprocessing fails, the file is hashed (so it won't match a hash recorded after a success)
+    // None (which never matches) if the file can't be read
+    fn input_hash(&self, file: &Path) -> Option<String> {
+        if self.suite.db.options.preprocess.is_none() {
+            return hash_file(file).ok();
+        }
+
+        let relative = self.suite.relative_path(file);
+        let env = self.test_env(relative);
+        let preprocessed = match self.scratch(relative) {
+            Ok(_scratch) => self.preprocessed(file, &env),
+            Err(err) => Some(Err(err)),
+        };
+        match preprocessed {
+            Some(Ok(bytes)) => Some(hash_bytes(&bytes)),
+            Some(Err(err)) => {
+                log::warn!("{}: {}", file.display(), err);
+                hash_file(file).ok()
+            }
+            None => hash_file(file).ok(),
+        }
+    }
+
+    // Run an input through the preprocess command (if any) like a per test hook, returning what it outputs
+    // The input is on stdin and its path (relative to the directory) is $1
+    fn preprocessed(
+        &self,
+        file: &Path,
+        env: &BTreeMap<String, String>,
+    ) -> Option<Result<Vec<u8>, String>> {
+        let preprocess = self.suite.db.options.preprocess.as_ref()?;
+        let input = match std::fs::File::open(file) {
+            Ok(input) => input,
+            Err(err) => return Some(Err(format!("Unable to open input: {}", err))),
+        };
+
+        let output = self
+            .command(preprocess, env)
+            .arg("preprocess")
+            .arg(self.suite.relative_path(file))
+            .stdin(input)
+            .output();
+
+        Some(match output {
+            Ok(output) if output.status.success() => Ok(output.stdout),
+            Ok(output) => Err(format!(
+                "preprocess failed ({})\n{}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr)
+            )),
+            Err(err) => Err(format!("Unable to run preprocess: {}", err)),
+        })
+    }
+
+    /// What running each file would do, without running anything: the command that would run, or why it wouldn't
+    /// Preprocessed inputs (see --preprocess) can't be checked for --incremental without running the preprocess command,
+    /// so they're planned to run
+    pub fn plan(&self, files: &[PathBuf]) -> Vec<String> {
+        self.set_positions(files);
+        files
+            .iter()
+            .map(|file| {
+                let relative = self.suite.relative_path(file);
+                if self
+                    .skip
+                    .iter()
+                    .any(|pattern| pattern.matches_path(relative))
+                {
+                    "skipped (--skip)".to_string()
+                } else if self.incremental
+                    && self.suite.db.options.preprocess.is_none()
+                    && self.suite.is_unchanged(file, None)
+                {
+                    "unchanged (--incremental)".to_string()
+                } else {
+                    self.reproduction_command(file)
+                }
+            })
+            .collect()
+    }
+
+    // Remember the position of each file in a run, for {index} and {total}
+    fn set_positions(&self, files: &[PathBuf]) {
+        *self.positions.lock().unwrap() = files
+            .iter()
+            .enumerate()
+            .map(|(i, file)| (self.suite.relative_path(file).to_path_buf(), i + 1))
+            .collect();
+    }
+
+    // The temporary directory for a test (relative to the directory), given to its command and hooks as $TESTIT_TMPDIR
+    // It's named for the runner and the file, so every call for a test agrees on it (see scratch)
+    fn tmpdir(&self, file: &Path) -> PathBuf {
+        let name = hash_bytes(file.as_os_str().as_encoded_bytes());
+        std::env::temp_dir().join(format!(
+            "testit-{}-tmp-{}-{}",
+            std::process::id(),
+            self.id,
+            &name[..16]
+        ))
+    }
+
+    // Create a test's (empty) temporary directory, or a fresh copy of the fixture dir if there is one (see --fixture-dir)
+    fn scratch(&self, file: &Path) -> Result<Scratch, String> {
+        let db = &self.suite.db;
+        let path = self.tmpdir(file);
+        let _ = std::fs::remove_dir_all(&path);
+        let scratch = Scratch { path, keep: false };
+
+        let created = match &db.options.fixture_dir {
+            Some(fixture_dir) => {
+                let fixture_dir =
+                    Path::new(db.metadata.directory.as_deref().unwrap_or(".")).join(fixture_dir);
+                std::fs::create_dir_all(&scratch.path)
+                    .and_then(|_| fixtures::copy(&fixture_dir, &scratch.path))
+                    .map_err(|err| format!("Unable to copy {}: {}", fixture_dir.display(), err))
+            }
+            None => std::fs::create_dir_all(&scratch.path).map_err(|err| {
+                format!(
+                    "Unable to create temporary directory {}: {}",
+                    scratch.path.display(),
+                    err
+                )
+            }),
+        };
+        created.map(|_| scratch)
+    }
+
+    /// Run the command for a single file
+    pub fn run_test(&self, file: &Path) -> TestOutcome {
+        let db = &self.suite.db;
+
+        // Skipped files are still reported, just not run
+        let relative = self.suite.relative_path(file);
+        if self
+            .skip
+            .iter()
+            .any(|pattern| pattern.matches_path(relative))
+        {
+            log::info!(
+                event = "test_finished",
+                file = path_str(file),
+                status = "skipped";
+                "Skipping {}", file.display()
+            );
+            return TestOutcome::Skipped;
+        }
+
+        // The test's temporary directory is there for everything run for it, and deleted once it's done
+        let mut scratch = match self.scratch(relative) {
+            Ok(scratch) => scratch,
+            Err(err) => return self.error(file, err),
+        };
+
+        // Preprocessing comes first, so what it outputs can be hashed (for --incremental and the db) as well as run
+        let env = self.test_env(relative);
+        let preprocessed = match self.preprocessed(file, &env) {
+            Some(Ok(bytes)) => Some(bytes),
+            Some(Err(err)) => return self.error(file, err),
+            None => None,
+        };
+        let input_hash = preprocessed.as_deref().map(hash_bytes);
+
+        if self.incremental && self.suite.is_unchanged(file, input_hash.as_deref()) {
+            log::info!(
+                event = "test_finished",
+                file = path_str(file),
+                status = "unchanged";
+                "Unchanged {}", file.display()
+            );
+            return TestOutcome::Unchanged;
+        }
+
+        log::info!(
+            event = "test_started",
+            file = path_str(file);
+            "Testing {}", file.display()
+        );
+        log::debug!("Running: {}", self.reproduction_command(file));
+        self.emit(Event::TestStarted {
+            file: &self.suite.key(file),
+        });
+
+        // Secrets are redacted as soon as output is read, so they're never printed or saved
+        let redaction = db.options.redaction(&env);
+
+        // A failing before_each hook fails the test without running it
+        if let Some(before_each) = &db.options.before_each {
+            if let Err(error) = self.run_hook("before_each", before_each, Some(relative)) {
+                log::info!(
+                    event = "test_finished",
+                    file = path_str(file),
+                    status = "failure";
+                    "Failure {}", file.display()
+                );
+                return redact(
+                    TestOutcome::Failure(String::new(), error),
+                    redaction.as_ref(),
+                );
+            }
+        }
+
+        // Preprocessed inputs are written to the command's stdin once it's started (and don't count towards its time)
+        let start = std::time::Instant::now();
+        let stdin = if preprocessed.is_some() {
+            std::process::Stdio::piped()
+        } else if self.reads_stdin() {
+            match std::fs::File::open(file) {
+                Ok(input) => std::process::Stdio::from(input),
+                Err(err) => return self.error(file, format!("Unable to open input: {}", err)),
+            }
+        } else {
+            std::process::Stdio::null()
+        };
+        let timeout = Duration::from_secs(db.options.timeout.unwrap());
+
+        // Create the child process
+        // When comparing against fixtures, give each test a fresh directory to write to
+        let output_dir = match db
+            .options
+            .fixtures
+            .as_ref()
+            .map(|_| scratch_dir())
+            .transpose()
+        {
+            Ok(output_dir) => output_dir,
+            Err(err) => return self.error(file, err.to_string()),
+        };
+
+        let writable = output_dir
+            .iter()
+            .map(PathBuf::as_path)
+            .chain([scratch.path.as_path()])
+            .collect::<Vec<_>>();
+        let mut parts = self.sandbox(&writable);
+        parts.extend(self.test_argv(relative, &env));
+        let mut command_builder = self.argv_command(parts, &env);
+        if let (TestCommand::Argv(_), Some(limit)) = (&db.metadata.command, db.options.cpu_limit) {
+            cpu_limit(&mut command_builder, limit);
+        }
+        command_builder
+            .stdin(stdin)
+            .stderr(std::process::Stdio::piped()) // TODO: Do we want to capture this?
+            .stdout(std::process::Stdio::piped())
+            .process_group(0);
+
+        if let Some(output_dir) = &output_dir {
+            command_builder.env("TESTIT_OUTPUT_DIR", output_dir);
+        }
+        // With a fixture dir, the test runs in its copy
+        if db.options.fixture_dir.is_some() {
+            command_builder.current_dir(&scratch.path);
+        }
+
+        // Start the child; it's reaped by wait (or kill) rather than Child::wait
+        #[allow(clippy::zombie_processes)]
+        let mut child = match command_builder.spawn() {
+            Ok(child) => child,
+            Err(err) => {
+                if let Some(output_dir) = output_dir {
+                    let _ = std::fs::remove_dir_all(output_dir);
+                }
+                return self.error(file, format!("Unable to start the command: {}", err));
+            }
+        };
+        let exited = wait(&child);
+
+        // On its own thread, so a command that doesn't read all of its input can't block us
+        if let (Some(bytes), Some(mut pipe)) = (preprocessed, child.stdin.take()) {
+            std::thread::spawn(move || {
+                let _ = pipe.write_all(&bytes);
+            });
+        }
+
+        // Read output on separate threads, so a full pipe can't block the child
+        // and a pipe held open by something else can't block us
+        let echo = |stderr: bool, mode: &Option<StreamMode>| {
+            (self.stream && mode.as_ref().is_some_and(StreamMode::prints)).then(|| Echo {
+                prefix: relative.display().to_string(),
+                stderr,
+                redaction: redaction.clone(),
+            })
+        };
+        let output = read_pipe(
+            child.stdout.take().unwrap(),
+            echo(false, &db.options.stdout_mode),
+        );
+        let error = read_pipe(
+            child.stderr.take().unwrap(),
+            echo(true, &db.options.stderr_mode),
+        );
+
+        // Wait for the child to finish up to timeout
+        // If timeout is reached, kill the child and anything it started (or they may outlast us...)
+        let outcome = match exited.recv_timeout(timeout) {
+            Ok((status, usage)) => {
+                let output = self.collect_output(file, &child, output, "stdout");
+                let error = self.collect_output(file, &child, error, "stderr");
+
+                // Killed by SIGXCPU, either directly or as the command bash was running
+                // Only if it used up the limit, since a command could also exit with 128 + SIGXCPU (or raise it) itself
+                let cpu_limited = db.options.cpu_limit.is_some_and(|limit| {
+                    usage.user_ms + usage.system_ms + CPU_LIMIT_SLACK_MS >= limit * 1000
+                        && (status.signal() == Some(libc::SIGXCPU)
+                            || status.code() == Some(128 + libc::SIGXCPU))
+                });
+
+                // A test expected to fail succeeds with any non-zero exit code (but not a signal), and fails with 0
+                let expects_failure = self.suite.expects_failure(file);
+                let succeeded = if expects_failure {
+                    status.code().is_some_and(|code| code != 0)
+                } else {
+                    status.success()
+                };
+
+                if succeeded && !cpu_limited {
+                    let elapsed = start.elapsed().as_millis();
+                    log::info!(
+                        event = "test_finished",
+                        file = path_str(file),
+                        status = "success",
+                        duration_ms = elapsed,
+                        bytes = output.len() + error.len();
+                        "Success after {}ms: {}", elapsed, file.display()
+                    );
+                    TestOutcome::Success(output, error, elapsed, usage, input_hash)
+                } else if cpu_limited {
+                    log::info!(
+                        event = "test_finished",
+                        file = path_str(file),
+                        status = "timeout",
+                        duration_ms = start.elapsed().as_millis();
+                        "CPU limit {}", file.display()
+                    );
+                    log::info!("Reproduce with: {}", self.reproduction_command(file));
+                    TestOutcome::Timeout(Limit::Cpu)
+                } else {
+                    log::info!(
+                        event = "test_finished",
+                        file = path_str(file),
+                        status = "failure",
+                        duration_ms = start.elapsed().as_millis(),
+                        bytes = output.len() + error.len();
+                        "Failure {}", file.display()
+                    );
+                    log::info!("Reproduce with: {}", self.reproduction_command(file));
+                    let mut error = error;
+                    if expects_failure {
+                        match status.signal() {
+                            Some(signal) => error.push_str(&format!(
+                                "Expected a non-zero exit status, but the command was killed by signal {}\n",
+                                signal
+                            )),
+                            None => error.push_str(
+                                "Expected a non-zero exit status, but the command exited with 0\n",
+                            ),
+                        }
+                    }
+                    TestOutcome::Failure(output, error)
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                // Timeout passed without exit
+                log::info!(
+                    event = "test_finished",
+                    file = path_str(file),
+                    status = "timeout",
+                    duration_ms = start.elapsed().as_millis();
+                    "Timeout {}", file.display()
+                );
+                log::info!("Reproduce with: {}", self.reproduction_command(file));
+                kill(&child, &exited);
+                TestOutcome::Timeout(Limit::WallClock)
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                // Waiting for the process failed, so there's no status to go on
+                kill(&child, &exited);
+                TestOutcome::Error("Unable to wait for the command to exit".to_string())
+            }
+        };
+
+        let outcome = self.postprocess(relative, &env, outcome);
+        let outcome = redact(outcome, redaction.as_ref());
+
+        let outcome = match output_dir {
+            Some(output_dir) => {
+                let outcome = self.check_fixtures(relative, &output_dir, outcome);
+                let _ = std::fs::remove_dir_all(output_dir);
+                outcome
+            }
+            None => outcome,
+        };
+        let outcome = self.check_assertions(relative, outcome);
+        let outcome = self.check_expected_timeout(file, outcome, timeout);
+        trace_outcome(file, &outcome);
+
+        let failed = matches!(
+            outcome,
+            TestOutcome::Failure(..) | TestOutcome::Timeout(_) | TestOutcome::Error(_)
+        );
+        if self.keep_failed_tmp && failed {
+            log::info!(
+                "{}: Kept temporary directory {}",
+                file.display(),
+                scratch.path.display()
+            );
+            self.kept_tmpdirs
+                .lock()
+                .unwrap()
+                .push((file.to_path_buf(), scratch.path.clone()));
+            scratch.keep = true;
+        }
+
+        // The test already has its outcome, so a failing after_each hook is only reported
+        if let Some(after_each) = &db.options.after_each {
+            if let Err(error) = self.run_hook("after_each", after_each, Some(relative)) {
+                log::warn!("{}: {}", file.display(), error);
+            }
+        }
+
+        outcome
+    }
+
+    // Pipe a successful test's stdout through the postprocess command (if any), failing the test if that fails
+    // Like hooks, it runs from the directory with the test's env and the input file (relative to the directory) as $1
+    fn postprocess(
+        &self,
+        file: &Path,
+        env: &BTreeMap<String, String>,
+        outcome: TestOutcome,
+    ) -> TestOutcome {
+        let Some(postprocess) = &self.suite.db.options.postprocess else {
+            return outcome;
+        };
+        let TestOutcome::Success(output, error, elapsed, usage, input_hash) = outcome else {
+            return outcome;
+        };
+
+        let child = self
+            .command(postprocess, env)
+            .arg("postprocess")
+            .arg(file)
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            Err(err) => {
+                return TestOutcome::Error(format!("Unable to run postprocess: {}", err));
+            }
+        };
+
+        // On its own thread, so a filter that prints as it reads can't block on a full pipe
+        let mut stdin = child.stdin.take().unwrap();
+        let input = output.clone().into_bytes();
+        std::thread::spawn(move || {
+            let _ = stdin.write_all(&input);
+        });
+
+        match child.wait_with_output() {
+            Ok(filtered) if filtered.status.success() => TestOutcome::Success(
+                String::from_utf8_lossy(&filtered.stdout).into_owned(),
+                error,
+                elapsed,
+                usage,
+                input_hash,
+            ),
+            Ok(filtered) => TestOutcome::Failure(
+                output,
+                format!(
+                    "{}postprocess failed ({})\n{}",
+                    error,
+                    filtered.status,
+                    String::from_utf8_lossy(&filtered.stderr)
+                ),
+            ),
+            Err(err) => TestOutcome::Error(format!("Unable to run postprocess: {}", err)),
+        }
+    }
+
+    // For a test expected to hang, reaching the (wall clock) timeout is a success with no output, and exiting before it a failure
+    // A success has the timeout as its time and no usage, since the command was killed before it could be measured
+    fn check_expected_timeout(
+        &self,
+        file: &Path,
+        outcome: TestOutcome,
+        timeout: Duration,
+    ) -> TestOutcome {
+        if !self.suite.expects_timeout(file) {
+            return outcome;
+        }
+
+        match outcome {
+            TestOutcome::Timeout(Limit::WallClock) => TestOutcome::Success(
+                String::new(),
+                String::new(),
+                timeout.as_millis(),
+                Usage::default(),
+                None,
+            ),
+            TestOutcome::Success(output, error, ..) | TestOutcome::Failure(output, error) => {
+                TestOutcome::Failure(
+                    output,
+                    format!(
+                        "{}Expected a timeout, but the command exited before it\n",
+                        error
+                    ),
+                )
+            }
+            outcome => outcome,
+        }
+    }
+
+    // Compare a successful test's output directory against its fixture, failing the test if they differ
+    // The fixture for an input is <directory>/<fixtures>/<input relative to directory>/
+    fn check_fixtures(&self, file: &Path, output_dir: &Path, outcome: TestOutcome) -> TestOutcome {
+        if !matches!(outcome, TestOutcome::Success(..)) {
+            return outcome;
+        }
+
+        let db = &self.suite.db;
+        let fixture = Path::new(db.metadata.directory.as_deref().unwrap_or("."))
+            .join(db.options.fixtures.as_ref().unwrap())
+            .join(file);
+
+        let changes = match fixtures::compare(&fixture, output_dir) {
+            Ok(changes) => changes,
+            Err(err) => return self.error(file, err.to_string()),
+        };
+        if changes.is_empty() {
+            return outcome;
+        }
+
+        if self.update_fixtures {
+            log::info!("Updating fixture {}", fixture.display());
+            if let Err(err) = fixtures::update(&fixture, output_dir) {
+                return self.error(file, err.to_string());
+            }
+            return outcome;
+        }
+
+        let TestOutcome::Success(output, mut error, ..) = outcome else {
+            unreachable!()
+        };
+        error.push_str(&format!(
+            "Output directory doesn't match {}:\n",
+            fixture.display()
+        ));
+        for change in changes {
+            error.push_str(&format!("{}\n", change));
+        }
+        TestOutcome::Failure(output, error)
+    }
+
+    // Check a successful test's stdout and stderr against the suite's (and the test's own) assertions, failing the test if any don't hold
+    fn check_assertions(&self, file: &Path, outcome: TestOutcome) -> TestOutcome {
+        let TestOutcome::Success(output, error, ..) = &outcome else {
+            return outcome;
+        };
+
+        let problems = self
+            .suite
+            .db
+            .options
+            .assertions(file.to_str().unwrap())
+            .check(output, error);
+        if problems.is_empty() {
+            return outcome;
+        }
+
+        let TestOutcome::Success(output, mut error, ..) = outcome else {
+            unreachable!()
+        };
+        for problem in problems {
+            error.push_str(&format!("{}\n", problem));
+        }
+        TestOutcome::Failure(output, error)
+    }
+
+    // Wait for a pipe to be fully read after the child exits, then decode it (see decode)
+    // If it's still open after the grace period, kill anything left in the child's process group and record an incident
+    fn collect_output(
+        &self,
+        file: &Path,
+        child: &Child,
+        pipe: mpsc::Receiver<Vec<u8>>,
+        stream: &str,
+    ) -> String {
+        let output = match pipe.recv_timeout(PIPE_GRACE) {
+            Ok(output) => output,
+            Err(_) => {
+                self.pipe_incident(file, child);
+                pipe.recv_timeout(PIPE_GRACE).unwrap_or_default()
+            }
+        };
+
+        let encoding = self
+            .suite
+            .db
+            .options
+            .output_encoding
+            .unwrap_or(OutputEncoding::Lossy);
+        let (output, valid) = decode(output, encoding);
+        if !valid {
+            let note = match encoding {
+                OutputEncoding::Lossy => format!(
+                    "{} was not valid UTF-8, invalid bytes were replaced (see --output-encoding)",
+                    stream
+                ),
+                OutputEncoding::Bytes => {
+                    format!("{} was not valid UTF-8, invalid bytes were escaped", stream)
+                }
+            };
+            log::info!("{}: {}", file.display(), note);
+            self.encoding_notes
+                .lock()
+                .unwrap()
+                .push((file.to_path_buf(), note));
+        }
+        output
+    }
+
+    // Record an incident for a pipe still held open after the child exits, and kill anything left in its process group
+    fn pipe_incident(&self, file: &Path, child: &Child) {
+        let description = format!(
+            "output was still open {}s after the command exited (a background process may have inherited it); killed its process group",
+            PIPE_GRACE.as_secs()
+        );
+        log::warn!("{}: {}", file.display(), description);
+        self.emit(Event::Incident {
+            file: &self.suite.key(file),
+            description: &description,
+        });
+        self.incidents.lock().unwrap().push(Incident {
+            file: file.to_path_buf(),
+            description,
+        });
+
+        signal_group(child.id(), libc::SIGKILL);
+    }
+
+    // The path a test's command is given for its input (in arg or env mode): relative to the directory,
+    // or absolute if it's run in a temporary directory instead (see --fixture-dir)
+    fn input_path(&self, file: &Path) -> String {
+        let db = &self.suite.db;
+        if db.options.fixture_dir.is_none() {
+            return file.display().to_string();
+        }
+
+        let path = Path::new(db.metadata.directory.as_deref().unwrap_or(".")).join(file);
+        std::path::absolute(&path)
+            .unwrap_or(path)
+            .display()
+            .to_string()
+    }
+
+    // The program and arguments to run the suite's command for a file (relative to the directory) with an env
+    // A command line is run with bash, limited to the CPU time limit (if any); a list is run directly (see cpu_limit)
+    // ${VAR} is expanded first, then placeholders are replaced with shell quoted values in a command line (so they're
+    // safe to use anywhere in it), and as they are in each argument of a list, so a file name is never expanded
+    // The variant's arguments (if any) come after the command, but before the file in arg mode; in a list they're split on whitespace
+    // Only the soft limit is set, so going over it sends SIGXCPU (which we can tell apart) rather than SIGKILL
+    fn test_argv(&self, file: &Path, env: &BTreeMap<String, String>) -> Vec<String> {
+        let db = &self.suite.db;
+        let placeholders = self.placeholders(file);
+        let input = (db.options.input_mode == Some(InputMode::Arg)).then(|| self.input_path(file));
+
+        match &db.metadata.command {
+            TestCommand::Shell(command) => {
+                let command = match &self.suite.variant {
+                    Some(variant) => format!("{} {}", command, variant.args),
+                    None => command.clone(),
+                };
+                let command = substitute(&interpolate(&command, env), &placeholders, true);
+                let command = match input {
+                    Some(input) => format!("{} {}", command, shell_quote(&input)),
+                    None => command,
+                };
+                let command = match db.options.cpu_limit {
+                    Some(limit) => format!("ulimit -S -t {}; {}", limit, command),
+                    None => command,
+                };
+                vec!["bash".to_string(), "-c".to_string(), command]
+            }
+            TestCommand::Argv(argv) => {
+                let variant_args = self
+                    .suite
+                    .variant
+                    .iter()
+                    .flat_map(|variant| variant.args.split_whitespace());
+                argv.iter()
+                    .map(String::as_str)
+                    .chain(variant_args)
+                    .map(|arg| substitute(&interpolate(arg, env), &placeholders, false))
+                    .chain(input)
+                    .collect()
+            }
+        }
+    }
+
+    // If the input file is piped to the command (see --input-mode)
+    fn reads_stdin(&self) -> bool {
+        matches!(
+            self.suite.db.options.input_mode,
+            Some(InputMode::Stdin) | None
+        )
+    }
+
+    // Build a bash command run from the suite's directory with the given environment
+    fn command(&self, command: &str, env: &BTreeMap<String, String>) -> Command {
+        let parts = vec![
+            "bash".to_string(),
+            "-c".to_string(),
+            interpolate(command, env),
+        ];
+        self.argv_command(parts, env)
+    }
+
+    // The same, but running a program and its arguments directly (possibly a wrapper, such as a sandbox, first)
+    fn argv_command(&self, parts: Vec<String>, env: &BTreeMap<String, String>) -> Command {
+        let db = &self.suite.db;
+
+        let mut command_builder = Command::new(&parts[0]);
+        command_builder
+            .args(&parts[1..])
+            .current_dir(db.metadata.directory.as_deref().unwrap_or("."));
+
+        // Add environment variables
+        if !self.preserve_env() {
+            command_builder.env_clear();
+        }
+        for (key, value) in env.iter() {
+            command_builder.env(key, value);
+        }
+
+        command_builder
+    }
+
+    // The wrapper to run a test's command in the sandbox (if any), with only the given directories writable
+    // bwrap gets a read-only view of everything, with a private /tmp and no network
+    fn sandbox(&self, writable: &[&Path]) -> Vec<String> {
+        match self.suite.db.options.sandbox {
+            Some(Sandbox::Bwrap) => {
+                let mut wrapper = [
+                    "bwrap",
+                    "--ro-bind",
+                    "/",
+                    "/",
+                    "--dev",
+                    "/dev",
+                    "--proc",
+                    "/proc",
+                    "--tmpfs",
+                    "/tmp",
+                    "--unshare-net",
+                    "--die-with-parent",
+                ]
+                .map(String::from)
+                .to_vec();
+                for writable in writable {
+                    let writable = writable.to_str().unwrap().to_string();
+                    wrapper.extend(["--bind".to_string(), writable.clone(), writable]);
+                }
+                wrapper
+            }
+            Some(Sandbox::None) | None => Vec::new(),
+        }
+    }
+
+    // Run a setup/teardown style hook, returning its output as an error if it fails
+    // Per test hooks get the input file (relative to the directory) as $1, and the test's env
+    fn run_hook(&self, name: &str, hook: &str, file: Option<&Path>) -> Result<(), String> {
+        log::debug!("Running {} hook: {}", name, hook);
+
+        let env = match file {
+            Some(file) => self.test_env(file),
+            None => self.env.clone(),
+        };
+        let mut command_builder = self.command(hook, &env);
+        if let Some(file) = file {
+            command_builder.arg(name).arg(file);
+        }
+
+        let output = command_builder
+            .stdin(std::process::Stdio::null())
+            .output()
+            .map_err(|err| format!("Unable to run {} hook: {}", name, err))?;
+
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(format!(
+                "{} hook failed ({})\n{}{}",
+                name,
+                output.status,
+                String::from_utf8_lossy(&output.stdout),
+                String::from_utf8_lossy(&output.stderr)
+            ))
+        }
+    }
+
+    /// Run the version command (if any) with the suite's env, returning its trimmed output (see --version-command)
+    pub fn tool_version(&self) -> Option<Result<String, String>> {
+        let command = self.suite.db.options.version_command.as_ref()?;
+        let output = self
+            .command(command, &self.env)
+            .stdin(std::process::Stdio::null())
+            .output();
+
+        Some(match output {
+            Ok(output) if output.status.success() => {
+                Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+            }
+            Ok(output) => Err(format!(
+                "version command failed ({})\n{}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr)
+            )),
+            Err(err) => Err(format!("unable to run version command: {}", err)),
+        })
+    }
+
+    /// A command that can be pasted into a shell to run a single test the same way we do
+    /// Since it's logged, anything --redact-env or --redact-pattern would redact from output is replaced with *** here too
+    pub fn reproduction_command(&self, file: &Path) -> String {
+        let relative = self.suite.relative_path(file);
+        let command = self.build_reproduction_command(file);
+        match self.suite.db.options.redaction(&self.test_env(relative)) {
+            Some(redaction) => redaction.replace_all(&command, REDACTED).into_owned(),
+            None => command,
+        }
+    }
+
+    // The reproduction command, with only the values of redact_env variables hidden (see reproduction_command)
+    fn build_reproduction_command(&self, file: &Path) -> String {
+        let db = &self.suite.db;
+        let mut parts = Vec::new();
+
+        if let Some(directory) = &db.metadata.directory {
+            parts.push(format!("cd {} &&", shell_quote(directory)));
+        }
+
+        let relative = self.suite.relative_path(file);
+        let env = self.test_env(relative);
+        parts.push("env".to_string());
+        if !self.preserve_env() {
+            parts.push("-i".to_string());
+        }
+        for (key, value) in env.iter() {
+            let value = if db.options.redact_env.contains(key) {
+                REDACTED
+            } else {
+                value
+            };
+            parts.push(shell_quote(&format!("{}={}", key, value)));
+        }
+
+        parts.extend(self.sandbox(&[]).iter().map(|part| shell_quote(part)));
+        if let (TestCommand::Argv(_), Some(limit)) = (&db.metadata.command, db.options.cpu_limit) {
+            parts.push("bash -c".to_string());
+            parts.push(shell_quote(&format!("ulimit -S -t {}; exec \"$@\"", limit)));
+            parts.push("testit".to_string());
+        }
+        parts.extend(
+            self.test_argv(relative, &env)
+                .iter()
+                .map(|part| shell_quote(part)),
+        );
+
+        let stdin = if self.reads_stdin() {
+            file.to_str().unwrap()
+        } else {
+            "/dev/null"
+        };
+        match &db.options.preprocess {
+            Some(preprocess) => {
+                let mut preprocess = vec![
+                    "bash -c".to_string(),
+                    shell_quote(preprocess),
+                    "preprocess".to_string(),
+                    shell_quote(relative.to_str().unwrap()),
+                ];
+                if let Some(directory) = &db.metadata.directory {
+                    preprocess.insert(0, format!("cd {} &&", shell_quote(directory)));
+                }
+                format!(
+                    "({}) < {} | ({})",
+                    preprocess.join(" "),
+                    shell_quote(stdin),
+                    parts.join(" ")
+                )
+            }
+            None => format!("({}) < {}", parts.join(" "), shell_quote(stdin)),
+        }
+    }
+}
+
+// Read a pipe to the end on its own thread, sending the result once it's closed (and echoing it as it goes, if set)
+fn read_pipe(pipe: impl Read + Send + 'static, echo: Option<Echo>) -> mpsc::Receiver<Vec<u8>> {
+    let (sender, receiver) = mpsc::channel();
+
+    std::thread::spawn(move || {
+        let mut bytes = Vec::new();
+        match echo {
+            Some(echo) => {
+                // Read a line at a time, so each can be echoed (whole, so parallel tests don't interleave) as it arrives
+                let mut pipe = BufReader::new(pipe);
+                loop {
+                    let start = bytes.len();
+                    match pipe.read_until(b'\n', &mut bytes) {
+                        Ok(0) | Err(_) => break,
+                        Ok(_) => echo.print(&bytes[start..]),
+                    }
+                }
+            }
+            None => {
+                let mut pipe = pipe;
+                let _ = pipe.read_to_end(&mut bytes);
+            }
+        }
+        let _ = sender.send(bytes);
+    });
+
+    receiver
+}
+
+// Convert a test's output to text, along with if it was valid UTF-8
+// With bytes, backslashes are escaped in every output (not just invalid ones), so `\xNN` always means one byte
+fn decode(bytes: Vec<u8>, encoding: OutputEncoding) -> (String, bool) {
+    let valid = std::str::from_utf8(&bytes).is_ok();
+    let text = match encoding {
+        OutputEncoding::Lossy => String::from_utf8_lossy(&bytes).into_owned(),
+        OutputEncoding::Bytes => {
+            let mut text = String::new();
+            for chunk in bytes.utf8_chunks() {
+                text.push_str(&chunk.valid().replace('\\', "\\\\"));
+                for byte in chunk.invalid() {
+                    text.push_str(&format!("\\x{:02x}", byte));
+                }
+            }
+            text
+        }
+    };
+    (text, valid)
+}
+
+// Set NO_COLOR_ENV in an env, unless it already sets them
+fn no_color(env: &mut BTreeMap<String, String>) {
+    for (key, value) in NO_COLOR_ENV {
+        env.entry(key.to_string())
+            .or_insert_with(|| value.to_string());
+    }
+}
+
+// A test's path as a structured log field (see --log-format)
+fn path_str(file: &Path) -> &str {
+    file.to_str().unwrap_or_default()
+}
+
+// Log a test's outcome with its timing and (truncated) output, for --log-file
+fn trace_outcome(file: &Path, outcome: &TestOutcome) {
+    if !log::log_enabled!(log::Level::Trace) {
+        return;
+    }
+
+    match outcome {
+        TestOutcome::Success(output, error, elapsed, usage, _) => {
+            log::trace!(
+                "{}: success after {}ms (user {}ms, system {}ms, max rss {}kb)",
+                file.display(),
+                elapsed,
+                usage.user_ms,
+                usage.system_ms,
+                usage.max_rss_kb
+            );
+            log::trace!("{}: stdout: {}", file.display(), log_file::excerpt(output));
+            log::trace!("{}: stderr: {}", file.display(), log_file::excerpt(error));
+        }
+        TestOutcome::Failure(output, error) => {
+            log::trace!("{}: failure", file.display());
+            log::trace!("{}: stdout: {}", file.display(), log_file::excerpt(output));
+            log::trace!("{}: stderr: {}", file.display(), log_file::excerpt(error));
+        }
+        outcome => log::trace!("{}: {}", file.display(), outcome.name()),
+    }
+}
+
+// Replace anything matching the redaction (if any) in a test's output with REDACTED
+fn redact(outcome: TestOutcome, redaction: Option<&Regex>) -> TestOutcome {
+    let Some(redaction) = redaction else {
+        return outcome;
+    };
+    let redact = |text: String| redaction.replace_all(&text, REDACTED).into_owned();
+
+    match outcome {
+        TestOutcome::Success(output, error, elapsed, usage, input_hash) => {
+            TestOutcome::Success(redact(output), redact(error), elapsed, usage, input_hash)
+        }
+        TestOutcome::Failure(output, error) => TestOutcome::Failure(redact(output), redact(error)),
+        outcome => outcome,
+    }
+}
+
+// Where to echo output from a test as it's read (see Runner::stream)
+struct Echo {
+    prefix: String,
+    stderr: bool,
+    redaction: Option<Regex>,
+}
+
+impl Echo {
+    fn print(&self, line: &[u8]) {
+        let line = String::from_utf8_lossy(line);
+        let line = match &self.redaction {
+            Some(redaction) => redaction.replace_all(&line, REDACTED),
+            None => line,
+        };
+        let line = format!("[{}] {}\n", self.prefix, line.trim_end_matches('\n'));
+        if self.stderr {
+            let _ = std::io::stderr().lock().write_all(line.as_bytes());
+        } else {
+            let _ = std::io::stdout().lock().write_all(line.as_bytes());
+        }
+    }
+}
+
+// Wait for a child to exit on its own thread, sending its exit status and resource usage once it does
+// This uses wait4 directly (rather than Child::wait) since that's the only way to get a single child's usage
+fn wait(child: &Child) -> mpsc::Receiver<(ExitStatus, Usage)> {
+    let (sender, receiver) = mpsc::channel();
+    let pid = child.id() as libc::pid_t;
+
+    std::thread::spawn(move || loop {
+        let mut status = 0;
+        // SAFETY: rusage is plain data, so all zeros is valid
+        let mut rusage = unsafe { std::mem::zeroed::<libc::rusage>() };
+        // SAFETY: both pointers are to valid locals for the duration of the call
+        let result = unsafe { libc::wait4(pid, &mut status, 0, &mut rusage) };
+
+        if result == pid {
+            let _ = sender.send((ExitStatus::from_raw(status), usage(&rusage)));
+            return;
+        } else if std::io::Error::last_os_error().kind() != std::io::ErrorKind::Interrupted {
+            // Dropping the sender tells the receiver something went wrong
+            return;
+        }
+    });
+
+    receiver
+}
+
+// Convert rusage to what we store; times are in ms, and max RSS in KiB (Linux reports it in KiB, macOS in bytes)
+fn usage(rusage: &libc::rusage) -> Usage {
+    let ms = |time: libc::timeval| time.tv_sec as u64 * 1000 + time.tv_usec as u64 / 1000;
+    let max_rss = rusage.ru_maxrss as u64;
+
+    Usage {
+        max_rss_kb: if cfg!(target_os = "macos") {
+            max_rss / 1024
+        } else {
+            max_rss
+        },
+        user_ms: ms(rusage.ru_utime),
+        system_ms: ms(rusage.ru_stime),
+        major_faults: rusage.ru_majflt as u64,
+    }
+}
+
+// Kill a child along with anything else in its process group, then wait for it to be reaped (see wait)
+// They're sent SIGTERM first, so they can clean up, and SIGKILL if the child is still running after a grace period
+fn kill(child: &Child, exited: &mpsc::Receiver<(ExitStatus, Usage)>) {
+    signal_group(child.id(), libc::SIGTERM);
+    let reaped = exited.recv_timeout(KILL_GRACE).is_ok();
+    signal_group(child.id(), libc::SIGKILL);
+    if !reaped {
+        let _ = exited.recv();
+    }
+}
+
+// Each test runs in its own process group (with the child's pid as the id), so this gets anything it started too
+// A group that's already gone (ESRCH) is expected once everything in it has exited
+fn signal_group(pid: u32, signal: libc::c_int) {
+    // SAFETY: kill only sends a signal; a negative pid targets the process group and touches no memory of ours
+    if unsafe { libc::kill(-(pid as libc::pid_t), signal) } != 0 {
+        let err = std::io::Error::last_os_error();
+        if err.raw_os_error() == Some(libc::ESRCH) {
+            log::debug!("Process group {} already exited", pid);
+        } else {
+            log::warn!("Unable to signal process group {}: {}", pid, err);
+        }
+    }
+}
+
+// Limit a command run directly (rather than with bash's ulimit) to a soft limit of CPU seconds once it's started
+fn cpu_limit(command_builder: &mut Command, limit: u64) {
+    let limit = limit as libc::rlim_t;
+    // Only async signal safe calls are made between fork and exec
+    unsafe {
+        command_builder.pre_exec(move || {
+            let mut rlimit = std::mem::zeroed::<libc::rlimit>();
+            if libc::getrlimit(libc::RLIMIT_CPU, &mut rlimit) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            rlimit.rlim_cur = limit.min(rlimit.rlim_max);
+            if libc::setrlimit(libc::RLIMIT_CPU, &rlimit) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+// A test's temporary directory (see Runner::tmpdir), deleted when dropped unless it's kept (see --keep-failed-tmp)
+struct Scratch {
+    path: PathBuf,
+    keep: bool,
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        if !self.keep {
+            let _ = std::fs::remove_dir_all(&self.path);
+        }
+    }
+}
+
+// A new, empty directory for a single test to write output to
+fn scratch_dir() -> error::Result<PathBuf> {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+    let directory = std::env::temp_dir().join(format!(
+        "testit-{}-{}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::create_dir_all(&directory)
+        .map_err(|err| Error::io("create temporary directory", directory.display(), err))?;
+    Ok(directory)
+}
+
+/// Quote a value so bash will treat it as a single word, even with spaces, quotes, or newlines
+pub fn shell_quote(value: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "-_./=:@%+,".contains(c);
+
+    if !value.is_empty() && value.chars().all(safe) {
+        value.to_string()
+    } else {
+        format!("'{}'", value.replace('\'', "'\\''"))
+    }
+}
+
+// Replace each placeholder like {stem} with its value (shell quoted if set)
+// ${stem} is left alone, since that's bash (or interpolation)
+fn substitute(template: &str, placeholders: &[(&str, String)], quote: bool) -> String {
+    let mut result = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let placeholder = placeholders.iter().find(|(name, _)| {
+            rest[1..]
+                .strip_prefix(name)
+                .is_some_and(|after| after.starts_with('}'))
+        });
+        match placeholder {
+            Some((name, value)) if !result.ends_with('$') => {
+                if quote {
+                    result.push_str(&shell_quote(value));
+                } else {
+                    result.push_str(value);
+                }
+                rest = &rest[name.len() + 2..];
+            }
+            _ => {
+                result.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    result.push_str(rest);
+    result
+}
+
+/// Replace each ${VAR} with its value from vars, else the parent environment
+/// Unknown variables are left as is for bash to handle
+pub fn interpolate(value: &str, vars: &BTreeMap<String, String>) -> String {
+    let mut result = String::new();
+    let mut rest = value;
+
+    while let Some(start) = rest.find("${") {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let Some(end) = rest.find('}') else {
+            break;
+        };
+        let name = &rest[2..end];
+
+        match vars.get(name).cloned().or_else(|| std::env::var(name).ok()) {
+            Some(value) => result.push_str(&value),
+            None => result.push_str(&rest[..=end]),
+        }
+        rest = &rest[end + 1..];
+    }
+
+    result.push_str(rest);
+    result
+}
@@ -0,0 +1,88 @@
+// Splitting a suite's files across several jobs (see --shard), so CI can run them in parallel
+use std::path::PathBuf;
+
+use crate::suite::TestSuite;
+
+/// One of several jobs splitting the files between them, from 1 to count
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Shard {
+    pub index: usize,
+    pub count: usize,
+}
+
+impl std::fmt::Display for Shard {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.index, self.count)
+    }
+}
+
+/// Parse a shard like 2/4 (the second of four)
+pub fn parse(value: &str) -> Result<Shard, String> {
+    let invalid = || format!("invalid shard: {} (expected K/N with 1 <= K <= N)", value);
+
+    let (index, count) = value.trim().split_once('/').ok_or_else(invalid)?;
+    let index = index.trim().parse::<usize>().map_err(|_| invalid())?;
+    let count = count.trim().parse::<usize>().map_err(|_| invalid())?;
+    if index == 0 || index > count {
+        return Err(invalid());
+    }
+
+    Ok(Shard { index, count })
+}
+
+impl Shard {
+    /// The files this shard should run, in the order they were given
+    /// Every shard computes the same split from the same db: the slowest tests (by most recent time) are
+    /// assigned first, each to the shard with the least total time so far; tests without a recorded time
+    /// are assumed to take the average; with a matrix, a file's time is the total over its variants
+    pub fn select(&self, suite: &TestSuite, files: &[PathBuf]) -> Vec<PathBuf> {
+        let variants = suite.db.options.variants();
+        let timing = |file: &PathBuf| {
+            variants
+                .iter()
+                .filter_map(|variant| {
+                    suite
+                        .db
+                        .timing
+                        .get(&suite.variant_key(file, variant.as_ref()))
+                        .map(|timing| timing.most_recent)
+                })
+                .reduce(|a, b| a + b)
+        };
+
+        let known = files.iter().filter_map(timing).collect::<Vec<_>>();
+        let average = if known.is_empty() {
+            1
+        } else {
+            (known.iter().sum::<u128>() / known.len() as u128).max(1)
+        };
+
+        // Sort by name as well so ties (and the order files were found in) can't change the split
+        let mut weighted = files
+            .iter()
+            .enumerate()
+            .map(|(i, file)| (timing(file).unwrap_or(average), file, i))
+            .collect::<Vec<_>>();
+        weighted.sort_by(|(a_time, a_file, _), (b_time, b_file, _)| {
+            b_time.cmp(a_time).then_with(|| a_file.cmp(b_file))
+        });
+
+        let mut totals = vec![0u128; self.count];
+        let mut selected = Vec::new();
+        for (time, _, i) in weighted {
+            let (shard, _) = totals
+                .iter()
+                .enumerate()
+                .min_by_key(|(shard, total)| (**total, *shard))
+                .unwrap();
+            totals[shard] += time;
+
+            if shard + 1 == self.index {
+                selected.push(i);
+            }
+        }
+
+        selected.sort();
+        selected.into_iter().map(|i| files[i].clone()).collect()
+    }
+}
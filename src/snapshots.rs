@@ -0,0 +1,115 @@
+// Accepted outputs stored as a tree of files next to the db (see --snapshots), so changes to them are reviewable
+// Each input's first accepted output is `<input>.out`; any others are `<input>.out.2`, `<input>.out.3`, ...
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::db::TreeChanges;
+use crate::error::{Error, Result};
+use crate::fixtures;
+
+/// The snapshot directory for a db, relative to the db file
+pub fn directory(db_path: &str, snapshots: &str) -> PathBuf {
+    Path::new(db_path)
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join(snapshots)
+}
+
+/// Read every snapshot under a directory as results, by input file
+pub fn load(directory: &Path) -> Result<BTreeMap<String, Vec<String>>> {
+    let mut files = Vec::new();
+    for (path, relative) in list(directory)? {
+        let output = std::fs::read_to_string(&path)
+            .map_err(|err| Error::io("read snapshot", path.display(), err))?;
+        files.push((relative, output));
+    }
+    Ok(from_files(files))
+}
+
+/// Results from the contents of snapshot files, by their paths relative to the snapshot directory
+pub fn from_files(
+    files: impl IntoIterator<Item = (String, String)>,
+) -> BTreeMap<String, Vec<String>> {
+    let mut variants: BTreeMap<String, Vec<(usize, String)>> = BTreeMap::new();
+
+    for (relative, output) in files {
+        let Some((file, index)) = parse(&relative) else {
+            log::warn!("Ignoring unexpected snapshot file {}", relative);
+            continue;
+        };
+        variants
+            .entry(file.to_string())
+            .or_default()
+            .push((index, output));
+    }
+
+    variants
+        .into_iter()
+        .map(|(file, mut outputs)| {
+            outputs.sort_by_key(|(index, _)| *index);
+            (
+                file,
+                outputs.into_iter().map(|(_, output)| output).collect(),
+            )
+        })
+        .collect()
+}
+
+/// The changes that write results as snapshots, removing any that are no longer accepted (see Db::save)
+pub fn changes(directory: &Path, results: &BTreeMap<String, Vec<String>>) -> Result<TreeChanges> {
+    let mut changes = TreeChanges::default();
+    let mut stale = list(directory)?
+        .into_iter()
+        .filter(|(_, relative)| parse(relative).is_some())
+        .map(|(path, _)| path)
+        .collect::<Vec<_>>();
+
+    for (file, outputs) in results {
+        for (i, output) in outputs.iter().enumerate() {
+            let path = if i == 0 {
+                directory.join(format!("{}.out", file))
+            } else {
+                directory.join(format!("{}.out.{}", file, i + 1))
+            };
+            stale.retain(|existing| *existing != path);
+
+            // Skip unchanged snapshots, so their modification times only change when they do
+            if std::fs::read_to_string(&path).is_ok_and(|existing| existing == *output) {
+                continue;
+            }
+            changes.writes.push((path, output.clone()));
+        }
+    }
+
+    changes.removals = stale;
+    Ok(changes)
+}
+
+// The input file and (1 based) index of the output a snapshot file holds, by its path relative to the directory
+fn parse(relative: &str) -> Option<(&str, usize)> {
+    match relative.strip_suffix(".out") {
+        Some(file) => Some((file, 1)),
+        None => {
+            let (file, index) = relative.rsplit_once(".out.")?;
+            Some((file, index.parse().ok()?))
+        }
+    }
+}
+
+// All files under a directory (which may not exist yet) that could be snapshots, with their paths relative to it
+fn list(directory: &Path) -> Result<Vec<(PathBuf, String)>> {
+    fixtures::list_files(directory)
+        .into_iter()
+        .filter(|relative| relative.to_string_lossy().contains(".out"))
+        .map(|relative| {
+            let path = directory.join(&relative);
+            match relative.to_str() {
+                Some(relative) => Ok((path.clone(), relative.to_string())),
+                None => Err(Error::Config(format!(
+                    "Snapshot file names must be valid UTF-8: {}",
+                    path.display()
+                ))),
+            }
+        })
+        .collect()
+}
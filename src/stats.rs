@@ -0,0 +1,150 @@
+// Summary statistics about a db: what it holds, how long its tests take, and where its size goes
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::Db;
+
+// How many of the slowest tests to list
+const SLOWEST: usize = 10;
+
+/// Print statistics about a db (loaded from path)
+pub fn print(db: &Db, path: &str) {
+    println!("Tests: {} recorded", db.results.len());
+
+    // When (and where) the baseline was last refreshed
+    if let Some(run) = db.runs.last() {
+        let at = humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(run.at));
+        println!(
+            "Last run: {} on {} ({}, testit {}), {}ms: {} success ({} new), {} failure, {} timeout, {} error, {} skipped",
+            at,
+            run.host,
+            run.os,
+            run.version,
+            run.duration_ms,
+            run.successes,
+            run.new_successes,
+            run.failures,
+            run.timeouts,
+            run.errors,
+            run.skipped
+        );
+        println!(
+            "\t{} run(s) recorded since {}",
+            db.runs.len(),
+            humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(db.runs[0].at))
+        );
+    }
+
+    // How many tests accept each number of variants
+    let mut variants: BTreeMap<usize, usize> = BTreeMap::new();
+    for outputs in db.results.values() {
+        *variants.entry(outputs.len()).or_default() += 1;
+    }
+    println!(
+        "Accepted variants: {} total",
+        db.results.values().map(Vec::len).sum::<usize>()
+    );
+    for (count, tests) in variants.iter() {
+        println!("\t{} variant(s): {} tests", count, tests);
+    }
+
+    // Timing distributions, using each test's most recent time
+    let mut by_directory: BTreeMap<String, Vec<u128>> = BTreeMap::new();
+    for (file, timing) in db.timing.iter() {
+        let directory = Path::new(file)
+            .parent()
+            .map(|parent| parent.display().to_string())
+            .filter(|parent| !parent.is_empty())
+            .unwrap_or_else(|| ".".to_string());
+        by_directory
+            .entry(directory)
+            .or_default()
+            .push(timing.most_recent);
+    }
+    let all = by_directory.values().flatten().copied().collect::<Vec<_>>();
+
+    println!();
+    println!("Timing (most recent, ms):");
+    println!(
+        "\t{:<24} {:>6} {:>8} {:>8} {:>8} {:>8} {:>8} {:>10}",
+        "directory", "tests", "min", "p50", "p90", "p99", "max", "total"
+    );
+    println!("\t{}", distribution("(all)", &all));
+    if by_directory.len() > 1 {
+        for (directory, times) in by_directory.iter() {
+            println!("\t{}", distribution(directory, times));
+        }
+    }
+
+    let mut slowest = db.timing.iter().collect::<Vec<_>>();
+    slowest.sort_by(|(a_file, a), (b_file, b)| {
+        b.most_recent
+            .cmp(&a.most_recent)
+            .then_with(|| a_file.cmp(b_file))
+    });
+    if !slowest.is_empty() {
+        println!();
+        println!("Slowest tests:");
+        for (file, timing) in slowest.iter().take(SLOWEST) {
+            println!(
+                "\t{:>8}ms  {} (fastest {}ms)",
+                timing.most_recent, file, timing.fastest
+            );
+        }
+    }
+
+    // Size on disk (possibly compressed), then how the uncompressed JSON splits between sections
+    println!();
+    match std::fs::metadata(path) {
+        Ok(metadata) => println!("Size: {} bytes on disk", metadata.len()),
+        Err(err) => println!("Size: unknown ({})", err),
+    }
+    let value = serde_json::to_value(db).unwrap();
+    let mut sections = value
+        .as_object()
+        .unwrap()
+        .iter()
+        .map(|(name, value)| (name.as_str(), serde_json::to_vec(value).unwrap().len()))
+        .collect::<Vec<_>>();
+    sections.sort_by(|(a_name, a), (b_name, b)| b.cmp(a).then_with(|| a_name.cmp(b_name)));
+    let total = sections.iter().map(|(_, size)| size).sum::<usize>().max(1);
+
+    let location = if db.options.snapshots.is_some() {
+        " (results are stored as snapshots)"
+    } else {
+        ""
+    };
+    println!("Uncompressed JSON by section{}:", location);
+    for (name, size) in sections {
+        println!(
+            "\t{:<20} {:>12} bytes {:>5.1}%",
+            name,
+            size,
+            size as f64 * 100.0 / total as f64
+        );
+    }
+}
+
+// One row of the timing table: the count, nearest rank percentiles, and total of some times
+fn distribution(name: &str, times: &[u128]) -> String {
+    if times.is_empty() {
+        return format!("{:<24} {:>6}", name, 0);
+    }
+
+    let mut sorted = times.to_vec();
+    sorted.sort_unstable();
+    let percentile = |p: usize| sorted[((sorted.len() * p).div_ceil(100)).max(1) - 1];
+
+    format!(
+        "{:<24} {:>6} {:>8} {:>8} {:>8} {:>8} {:>8} {:>10}",
+        name,
+        sorted.len(),
+        sorted[0],
+        percentile(50),
+        percentile(90),
+        percentile(99),
+        sorted[sorted.len() - 1],
+        sorted.iter().sum::<u128>()
+    )
+}
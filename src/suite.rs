@@ -0,0 +1,552 @@
+// A suite of tests backed by a db, and comparing test outcomes against what it has accepted
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::db::{Db, HistoryEntry, Status, TimingData, Usage};
+use crate::error::{Error, Result};
+use crate::hash::{hash_bytes, hash_dependencies, hash_file};
+use crate::options::{Metadata, Options, Variant};
+use crate::runner::TestOutcome;
+
+/// A set of input files run against a command, along with everything remembered about them
+#[derive(Debug)]
+pub struct TestSuite {
+    pub db: Db,
+
+    /// The variant (see --matrix) tests are currently run with, if any
+    pub variant: Option<Variant>,
+
+    /// The hash of the db file the suite was loaded from (or is replacing), to tell if it changes before saving
+    pub loaded_hash: Option<String>,
+}
+
+/// How a test's outcome compares to what the suite has already seen
+#[derive(Debug)]
+pub struct Comparison {
+    /// The output to show for this test, based on the stream modes
+    pub printed: String,
+
+    /// If this is a success with output that hasn't been accepted before
+    pub new: bool,
+
+    /// Resources this success used far more of than the previous success, such as `max RSS 2048KiB -> 8192KiB`
+    pub usage_regressions: Vec<String>,
+}
+
+// How many runs of each test to keep if --history-length isn't set
+const DEFAULT_HISTORY_LENGTH: usize = 20;
+
+// How much more of a resource a test has to use than last time to count as a regression, and the least it
+// has to use (so tiny tests going from 1ms to 3ms aren't reported)
+const USAGE_REGRESSION_FACTOR: u64 = 2;
+const USAGE_REGRESSION_MIN_RSS_KB: u64 = 10 * 1024;
+const USAGE_REGRESSION_MIN_CPU_MS: u64 = 100;
+
+impl TestSuite {
+    /// Create a new suite with nothing recorded yet
+    pub fn new(metadata: Metadata, options: Options) -> TestSuite {
+        TestSuite {
+            db: Db::new(metadata, options),
+            variant: None,
+            loaded_hash: None,
+        }
+    }
+
+    /// Load a suite from a db file
+    pub fn load(path: &str) -> Result<TestSuite> {
+        let (db, hash) = Db::read_hashed(path)?;
+        Ok(TestSuite {
+            db,
+            variant: None,
+            loaded_hash: Some(hash),
+        })
+    }
+
+    /// Check the metadata and options for problems before running anything
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = self.db.metadata.validate();
+        errors.extend(self.db.options.validate());
+        errors
+    }
+
+    /// Glob the list of all files that we want to test
+    /// This is based on the working directory (or cwd) + each files pattern, in order without duplicates
+    /// Files matching an exclude pattern (relative to the directory) are left out
+    /// Since files are stored by their paths, a file name that isn't valid UTF-8 is an error
+    pub fn files(&self) -> Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+
+        for pattern in self.db.metadata.files.iter() {
+            let pattern = format!(
+                "{}/{}",
+                self.db.metadata.directory.as_deref().unwrap_or("."),
+                pattern
+            );
+
+            let paths = glob::glob(&pattern).map_err(|err| {
+                Error::Config(format!("Invalid files pattern {}: {}", pattern, err))
+            })?;
+            for path in paths {
+                let path = path.map_err(|err| {
+                    Error::io("read", err.path().display().to_string(), err.into())
+                })?;
+                files.push(path);
+            }
+        }
+
+        let files = self.filter_files(files);
+        if let Some(file) = files.iter().find(|file| file.to_str().is_none()) {
+            return Err(Error::Config(format!(
+                "File names must be valid UTF-8: {}",
+                file.display()
+            )));
+        }
+        Ok(files)
+    }
+
+    /// Use an explicit list of files (relative to the directory) instead of the files patterns
+    /// Excludes still apply; files that don't exist are skipped
+    pub fn files_from(&self, list: &[String]) -> Vec<PathBuf> {
+        let files = list
+            .iter()
+            .map(|entry| {
+                let entry = entry.strip_prefix("./").unwrap_or(entry);
+                match &self.db.metadata.directory {
+                    Some(directory) => Path::new(directory).join(entry),
+                    None => PathBuf::from(entry),
+                }
+            })
+            .filter(|file| {
+                if !file.is_file() {
+                    log::warn!("Skipping {}, file does not exist", file.display());
+                }
+                file.is_file()
+            })
+            .collect();
+
+        self.filter_files(files)
+    }
+
+    // Remove excluded files and duplicates (keeping the first of each)
+    fn filter_files(&self, files: Vec<PathBuf>) -> Vec<PathBuf> {
+        let exclude = self
+            .db
+            .metadata
+            .exclude
+            .iter()
+            .map(|pattern| glob::Pattern::new(pattern).unwrap())
+            .collect::<Vec<_>>();
+
+        let mut seen = HashSet::new();
+        files
+            .into_iter()
+            .filter(|file| {
+                let relative = self.relative_path(file);
+                if exclude.iter().any(|pattern| pattern.matches_path(relative)) {
+                    log::debug!("Excluding {}", file.display());
+                    return false;
+                }
+                seen.insert(file.clone())
+            })
+            .collect()
+    }
+
+    /// Remove the directory prefix (if set) so files are stored relative to it
+    pub fn relative_path<'a>(&self, file: &'a Path) -> &'a Path {
+        if let Some(prefix) = &self.db.metadata.directory {
+            file.strip_prefix(prefix).unwrap()
+        } else {
+            file
+        }
+    }
+
+    /// The key a file's results are stored under with the current variant (see variant_key)
+    pub fn key(&self, file: &Path) -> String {
+        self.variant_key(file, self.variant.as_ref())
+    }
+
+    /// The key a file's results are stored under with a variant: its path relative to the directory,
+    /// followed by @ and the variant's name (if any), like inputs/a.txt@O2
+    pub fn variant_key(&self, file: &Path, variant: Option<&Variant>) -> String {
+        let relative = self.relative_path(file).to_string_lossy();
+        match variant {
+            Some(variant) => format!("{}@{}", relative, variant.name),
+            None => relative.to_string(),
+        }
+    }
+
+    /// Tests with accepted outputs in the db (by key) that aren't any of these files (with any variant),
+    /// such as for inputs that were deleted or renamed
+    pub fn orphaned(&self, files: &[PathBuf]) -> Vec<String> {
+        let variants = self.db.options.variants();
+        let keys = files
+            .iter()
+            .flat_map(|file| {
+                variants
+                    .iter()
+                    .map(|variant| self.variant_key(file, variant.as_ref()))
+            })
+            .collect::<HashSet<_>>();
+
+        self.db
+            .results
+            .keys()
+            .filter(|key| !keys.contains(*key))
+            .cloned()
+            .collect()
+    }
+
+    /// Files missing accepted output in the db (for any variant), such as tests that have never succeeded
+    pub fn unrecorded<'a>(&self, files: &'a [PathBuf]) -> Vec<&'a PathBuf> {
+        let variants = self.db.options.variants();
+        files
+            .iter()
+            .filter(|file| {
+                variants.iter().any(|variant| {
+                    !self
+                        .db
+                        .results
+                        .contains_key(&self.variant_key(file, variant.as_ref()))
+                })
+            })
+            .collect()
+    }
+
+    /// The owner of a test, from a <file>.owner sidecar or else the last matching owners pattern
+    pub fn owner(&self, file: &Path) -> Option<String> {
+        let mut sidecar = file.as_os_str().to_owned();
+        sidecar.push(".owner");
+        if let Ok(contents) = std::fs::read_to_string(sidecar) {
+            if let Some(owner) = contents
+                .lines()
+                .map(str::trim)
+                .find(|line| !line.is_empty())
+            {
+                return Some(owner.to_string());
+            }
+        }
+
+        let relative = self.relative_path(file);
+        self.db
+            .options
+            .owners
+            .iter()
+            .rev()
+            .filter_map(|owner| owner.rsplit_once('='))
+            .find(|(pattern, _)| glob::Pattern::new(pattern).unwrap().matches_path(relative))
+            .map(|(_, owner)| owner.to_string())
+    }
+
+    /// Check if a test's output lines can come in any order (see --unordered)
+    pub fn is_unordered(&self, file: &Path) -> bool {
+        let relative = self.relative_path(file);
+        self.db
+            .options
+            .unordered
+            .iter()
+            .any(|pattern| glob::Pattern::new(pattern).unwrap().matches_path(relative))
+    }
+
+    /// Check if a test is expected to hang until the timeout (see --expect-timeout)
+    pub fn expects_timeout(&self, file: &Path) -> bool {
+        let relative = self.relative_path(file);
+        self.db
+            .options
+            .expect_timeout
+            .iter()
+            .any(|pattern| glob::Pattern::new(pattern).unwrap().matches_path(relative))
+    }
+
+    /// Check if a test is expected to exit with a non-zero status (see --expect-failure)
+    pub fn expects_failure(&self, file: &Path) -> bool {
+        let relative = self.relative_path(file);
+        self.db
+            .options
+            .expect_failure
+            .iter()
+            .any(|pattern| glob::Pattern::new(pattern).unwrap().matches_path(relative))
+    }
+
+    /// Check if an output matches an accepted one, with the comparator command if there is one
+    pub fn matches(&self, expected: &str, actual: &str) -> Result<bool> {
+        let Some(comparator) = &self.db.options.comparator else {
+            return Ok(expected == actual);
+        };
+        if expected == actual {
+            return Ok(true);
+        }
+
+        // The comparator gets files (as $1 and $2, so they don't need quoting) rather than the outputs themselves
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let directory = std::env::temp_dir().join(format!(
+            "testit-compare-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let expected_path = directory.join("expected");
+        let actual_path = directory.join("actual");
+        std::fs::create_dir_all(&directory)
+            .and_then(|_| std::fs::write(&expected_path, expected))
+            .and_then(|_| std::fs::write(&actual_path, actual))
+            .map_err(|err| Error::io("write outputs to compare to", directory.display(), err))?;
+
+        let command = comparator
+            .replace("{expected}", "\"$1\"")
+            .replace("{actual}", "\"$2\"");
+        let status = Command::new("bash")
+            .arg("-c")
+            .arg(&command)
+            .arg("comparator")
+            .arg(&expected_path)
+            .arg(&actual_path)
+            .current_dir(self.db.metadata.directory.as_deref().unwrap_or("."))
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status();
+        let _ = std::fs::remove_dir_all(&directory);
+
+        match status {
+            Ok(status) => Ok(status.success()),
+            Err(err) => {
+                log::warn!("Unable to run comparator {}: {}", comparator, err);
+                Ok(false)
+            }
+        }
+    }
+
+    /// Check if neither an input nor its dependencies have changed since its last success
+    /// A preprocessed input (see --preprocess) is compared by the hash of what the preprocess command output, otherwise
+    /// the file is hashed; a file (or dependency) that can't be read counts as changed
+    pub fn is_unchanged(&self, file: &Path, input_hash: Option<&str>) -> bool {
+        let key = self.key(file);
+
+        let matches = |hash: &str| match input_hash {
+            Some(input_hash) => hash == input_hash,
+            None => hash_file(file).is_ok_and(|current| current == hash),
+        };
+        match self.db.hashes.get(&key) {
+            Some(hash) if matches(hash) => hash_dependencies(file).is_ok_and(|current| {
+                self.db
+                    .dependency_hashes
+                    .get(&key)
+                    .cloned()
+                    .unwrap_or_default()
+                    == current
+            }),
+            _ => false,
+        }
+    }
+
+    /// Compare the outcome of running a file against the db, recording it
+    /// New successful output is accepted, along with timing and input hashes
+    pub fn record(&mut self, input: &Path, outcome: &TestOutcome) -> Result<Comparison> {
+        let key = self.key(input);
+        let file = Path::new(&key);
+
+        let comparison = match outcome {
+            TestOutcome::Success(output, error, elapsed_ms, usage, input_hash) => {
+                let mut printed = self.db.options.printed(output, error);
+                let mut saved = self.db.options.saved(output, error);
+
+                // Sort unordered output, so lines in a different order aren't a different output
+                if self.is_unordered(input) {
+                    printed = sort_lines(&printed);
+                    saved = sort_lines(&saved);
+                }
+
+                // Cap huge outputs; the marker's hash means they're still compared on the full output
+                if let Some(max_saved_bytes) = self.db.options.max_saved_bytes {
+                    saved = truncate_saved(&saved, max_saved_bytes);
+                }
+
+                // Update timing data, even if we have a previous success
+                let timing_data = self.db.timing.entry(key.clone()).or_insert(TimingData {
+                    fastest: *elapsed_ms,
+                    most_recent: *elapsed_ms,
+                });
+
+                if timing_data.most_recent > *elapsed_ms * 2 {
+                    log::warn!(
+                        "{}: Slow test (2x previous): {}ms",
+                        file.display(),
+                        elapsed_ms
+                    );
+                }
+                timing_data.most_recent = *elapsed_ms;
+
+                if *elapsed_ms < timing_data.fastest {
+                    timing_data.fastest = *elapsed_ms;
+                    log::info!("New fastest time for {}: {}ms", file.display(), elapsed_ms);
+                }
+
+                // Compare resource usage against the previous success
+                let usage_regressions = self
+                    .db
+                    .usage
+                    .get(&key)
+                    .map(|previous| usage_regressions(previous, usage))
+                    .unwrap_or_default();
+                for regression in usage_regressions.iter() {
+                    log::warn!("{}: Resource regression: {}", file.display(), regression);
+                }
+                self.db.usage.insert(key.clone(), usage.clone());
+
+                // Remember what the input looked like when it last succeeded
+                // If it can't be read anymore, forget the old hashes instead, so it counts as changed next time
+                let input_hash = input_hash.clone().map_or_else(|| hash_file(input), Ok);
+                match input_hash.and_then(|hash| Ok((hash, hash_dependencies(input)?))) {
+                    Ok((hash, dependency_hashes)) => {
+                        self.db.hashes.insert(key.clone(), hash);
+                        self.db
+                            .dependency_hashes
+                            .insert(key.clone(), dependency_hashes);
+                    }
+                    Err(err) => {
+                        log::warn!("{}: {}", file.display(), err);
+                        self.db.hashes.remove(&key);
+                        self.db.dependency_hashes.remove(&key);
+                    }
+                }
+
+                // Don't update results if we've already seen it
+                let mut new = true;
+                for result in self.db.results.get(&key).into_iter().flatten() {
+                    if self.matches(result, &saved)? {
+                        new = false;
+                        break;
+                    }
+                }
+                self.db.latest.insert(key.clone(), saved.clone());
+                if new {
+                    self.db.results.entry(key.clone()).or_default().push(saved);
+                }
+
+                Comparison {
+                    printed,
+                    new,
+                    usage_regressions,
+                }
+            }
+            TestOutcome::Failure(output, error) => Comparison {
+                printed: self.db.options.printed(output, error),
+                new: false,
+                usage_regressions: Vec::new(),
+            },
+            TestOutcome::Error(reason) => Comparison {
+                printed: reason.clone(),
+                new: false,
+                usage_regressions: Vec::new(),
+            },
+            _ => Comparison {
+                printed: String::new(),
+                new: false,
+                usage_regressions: Vec::new(),
+            },
+        };
+
+        // Successes with output other than what was accepted before are remembered as such (see --failed-only)
+        // A test with no accepted outputs before this one doesn't count, such as when first recording it
+        if let Some(status) = outcome.status() {
+            let changed = comparison.new
+                && self
+                    .db
+                    .results
+                    .get(&key)
+                    .is_some_and(|results| results.len() > 1);
+            let status = if changed { Status::New } else { status };
+            self.db.status.insert(key.clone(), status);
+
+            let length = self
+                .db
+                .options
+                .history_length
+                .unwrap_or(DEFAULT_HISTORY_LENGTH);
+            if length > 0 {
+                let history = self.db.history.entry(key.clone()).or_default();
+                history.push(HistoryEntry {
+                    at: SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap()
+                        .as_secs(),
+                    status,
+                    elapsed_ms: match outcome {
+                        TestOutcome::Success(_, _, elapsed_ms, ..) => Some(*elapsed_ms),
+                        _ => None,
+                    },
+                });
+                if history.len() > length {
+                    history.drain(..history.len() - length);
+                }
+            }
+        }
+
+        Ok(comparison)
+    }
+}
+
+// Describe each resource a test used at least USAGE_REGRESSION_FACTOR times more of than before
+fn usage_regressions(previous: &Usage, current: &Usage) -> Vec<String> {
+    let grew = |previous: u64, current: u64, min: u64| {
+        current >= min && current >= previous.max(1) * USAGE_REGRESSION_FACTOR
+    };
+
+    let mut regressions = Vec::new();
+    if grew(
+        previous.max_rss_kb,
+        current.max_rss_kb,
+        USAGE_REGRESSION_MIN_RSS_KB,
+    ) {
+        regressions.push(format!(
+            "max RSS {}KiB -> {}KiB",
+            previous.max_rss_kb, current.max_rss_kb
+        ));
+    }
+
+    let previous_cpu = previous.user_ms + previous.system_ms;
+    let current_cpu = current.user_ms + current.system_ms;
+    if grew(previous_cpu, current_cpu, USAGE_REGRESSION_MIN_CPU_MS) {
+        regressions.push(format!("CPU time {}ms -> {}ms", previous_cpu, current_cpu));
+    }
+
+    regressions
+}
+
+// Keep only the first max_bytes of an output (at a character boundary), followed by a line with its full
+// length and sha256
+fn truncate_saved(output: &str, max_bytes: usize) -> String {
+    if output.len() <= max_bytes {
+        return output.to_string();
+    }
+
+    let mut end = max_bytes;
+    while !output.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    let mut truncated = output[..end].to_string();
+    if !truncated.ends_with('\n') {
+        truncated.push('\n');
+    }
+    truncated.push_str(&format!(
+        "[testit:truncated bytes={} sha256={}]\n",
+        output.len(),
+        hash_bytes(output.as_bytes())
+    ));
+    truncated
+}
+
+// Sort the lines of an output, keeping a trailing newline if it had one
+fn sort_lines(output: &str) -> String {
+    let mut lines = output.lines().collect::<Vec<_>>();
+    lines.sort_unstable();
+
+    let mut sorted = lines.join("\n");
+    if output.ends_with('\n') {
+        sorted.push('\n');
+    }
+    sorted
+}